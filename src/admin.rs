@@ -0,0 +1,508 @@
+use log::{log, Level};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::SystemTime;
+
+use crate::types::{EnqueueError, GlobalQueue, Song, SongOrigin};
+
+/**
+ * A user's role, in increasing order of privilege
+ * */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Role {
+    /**
+     * Can listen, vote and react, but not submit songs
+     * */
+    Listener,
+
+    /**
+     * Can submit songs
+     * */
+    Dj,
+
+    /**
+     * Can submit songs to a locked queue and perform admin actions
+     * */
+    Admin,
+}
+
+/**
+ * Users, sources and recordings that are banned from the queue
+ * */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BanList {
+    /**
+     * Users who may not submit songs
+     * */
+    pub users: HashSet<String>,
+
+    /**
+     * Song sources that may not be queued
+     * */
+    pub sources: HashSet<SongOrigin>,
+
+    /**
+     * MusicBrainz recording IDs that may not be queued
+     * */
+    pub recordings: HashSet<String>,
+}
+
+impl BanList {
+    /**
+     * Checks whether a song is banned by its source or, if metadata has been fetched, its
+     * recording. Sources are compared in their canonical form, so a banned video can't come
+     * back through a different link to it.
+     * */
+    pub fn is_banned(&self, song: &Song) -> bool {
+        let origin = song.origin.canonicalize().unwrap_or_else(|_| song.origin.clone());
+        self.sources.contains(&origin)
+            || song
+                .metadata
+                .as_ref()
+                .and_then(|m| m.recording_mbid.as_ref())
+                .is_some_and(|mbid| self.recordings.contains(mbid))
+    }
+}
+
+/**
+ * An action taken by an admin
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AdminAction {
+    SetRole { user_id: String, role: Role },
+    ForceSkip { song_id: Option<u64> },
+    RemoveSong { song_id: u64, submitter: String },
+    BanUser(String),
+    UnbanUser(String),
+    BanSource(SongOrigin),
+    BanRecording(String),
+    PinSong { song_id: u64 },
+    Lock,
+    Unlock,
+    ResetSession,
+}
+
+/**
+ * A single entry in the admin audit log
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /**
+     * When the action was taken
+     * */
+    pub time: SystemTime,
+
+    /**
+     * The admin who took the action
+     * */
+    pub admin: String,
+
+    /**
+     * The action taken
+     * */
+    pub action: AdminAction,
+}
+
+/**
+ * Reason an admin action failed
+ * */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminError {
+    /**
+     * The user performing the action is not an admin
+     * */
+    NotPermitted,
+
+    /**
+     * No queued song has the given id
+     * */
+    SongNotFound(u64),
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminError::NotPermitted => write!(f, "Only admins can do that"),
+            AdminError::SongNotFound(id) => write!(f, "No queued song with id {}", id),
+        }
+    }
+}
+
+impl std::error::Error for AdminError {}
+
+impl GlobalQueue {
+    /**
+     * Gets a user's role
+     * */
+    pub fn role_of(&self, user_id: &str) -> Role {
+        self.roles.get(user_id).copied().unwrap_or(self.default_role)
+    }
+
+    /**
+     * Checks whether a user's role and the ban list allow them to submit {song}
+     * */
+    pub fn check_permissions(&self, user_id: &str, song: &Song) -> Result<(), EnqueueError> {
+        let role = self.role_of(user_id);
+        if self.bans.users.contains(user_id) {
+            Err(EnqueueError::UserBanned)
+        } else if role < Role::Dj {
+            Err(EnqueueError::NotPermitted)
+        } else if self.locked && role < Role::Admin {
+            Err(EnqueueError::QueueLocked)
+        } else if self.bans.is_banned(song) {
+            Err(EnqueueError::SongBanned)
+        } else {
+            Ok(())
+        }
+    }
+
+    /**
+     * Sets a user's role
+     * */
+    pub fn set_role(&mut self, admin: &str, user_id: &str, role: Role) -> Result<(), AdminError> {
+        self.require_admin(admin)?;
+        self.roles.insert(user_id.to_string(), role);
+        self.audit(
+            admin,
+            AdminAction::SetRole {
+                user_id: user_id.to_string(),
+                role,
+            },
+        );
+        Ok(())
+    }
+
+    /**
+     * Skips the current song without counting it against the submitter
+     * */
+    pub fn force_skip(&mut self, admin: &str) -> Result<(), AdminError> {
+        self.require_admin(admin)?;
+        let song_id = self.now_playing().map(|song| song.id);
+        self.skip_current(false);
+        self.audit(admin, AdminAction::ForceSkip { song_id });
+        Ok(())
+    }
+
+    /**
+     * Removes a song from the global queue or any user's queue
+     * */
+    pub fn remove_song(&mut self, admin: &str, song_id: u64) -> Result<Song, AdminError> {
+        self.require_admin(admin)?;
+        let song = self
+            .take_song(song_id)
+            .ok_or(AdminError::SongNotFound(song_id))?;
+        self.audit(
+            admin,
+            AdminAction::RemoveSong {
+                song_id,
+                submitter: song.submitter.clone(),
+            },
+        );
+        Ok(song)
+    }
+
+    /**
+     * Bans a user from submitting songs, removing all songs they have queued
+     * */
+    pub fn ban_user(&mut self, admin: &str, user_id: &str) -> Result<(), AdminError> {
+        self.require_admin(admin)?;
+        self.bans.users.insert(user_id.to_string());
+        self.q.retain(|song| song.submitter != user_id);
+        self.users.retain(|user| user.user_id != user_id);
+        self.audit(admin, AdminAction::BanUser(user_id.to_string()));
+        Ok(())
+    }
+
+    /**
+     * Allows a banned user to submit songs again
+     * */
+    pub fn unban_user(&mut self, admin: &str, user_id: &str) -> Result<(), AdminError> {
+        self.require_admin(admin)?;
+        self.bans.users.remove(user_id);
+        self.audit(admin, AdminAction::UnbanUser(user_id.to_string()));
+        Ok(())
+    }
+
+    /**
+     * Bans a song source, removing all queued songs from it
+     * */
+    pub fn ban_source(&mut self, admin: &str, origin: SongOrigin) -> Result<(), AdminError> {
+        self.require_admin(admin)?;
+        let origin = origin.canonicalize().unwrap_or(origin);
+        self.bans.sources.insert(origin.clone());
+        self.remove_banned();
+        self.audit(admin, AdminAction::BanSource(origin));
+        Ok(())
+    }
+
+    /**
+     * Bans a MusicBrainz recording, removing all queued songs identified as it
+     * */
+    pub fn ban_recording(&mut self, admin: &str, mbid: &str) -> Result<(), AdminError> {
+        self.require_admin(admin)?;
+        self.bans.recordings.insert(mbid.to_string());
+        self.remove_banned();
+        self.audit(admin, AdminAction::BanRecording(mbid.to_string()));
+        Ok(())
+    }
+
+    /**
+     * Moves a song from anywhere in the queues to play next
+     * */
+    pub fn pin_next(&mut self, admin: &str, song_id: u64) -> Result<(), AdminError> {
+        self.require_admin(admin)?;
+        let song = self
+            .take_song(song_id)
+            .ok_or(AdminError::SongNotFound(song_id))?;
+        self.q.push_front(song);
+        self.audit(admin, AdminAction::PinSong { song_id });
+        Ok(())
+    }
+
+    /**
+     * Locks the queue so only admins can submit songs
+     * */
+    pub fn lock(&mut self, admin: &str) -> Result<(), AdminError> {
+        self.require_admin(admin)?;
+        self.locked = true;
+        self.audit(admin, AdminAction::Lock);
+        Ok(())
+    }
+
+    /**
+     * Unlocks the queue
+     * */
+    pub fn unlock(&mut self, admin: &str) -> Result<(), AdminError> {
+        self.require_admin(admin)?;
+        self.locked = false;
+        self.audit(admin, AdminAction::Unlock);
+        Ok(())
+    }
+
+    /**
+     * Clears all queues, history, votes and rate limits, listeners and pending notifications, and
+     * unlocks the queue. Listeners have to join again for their skip votes to count. Roles, bans
+     * and the audit log are kept.
+     * */
+    pub fn reset_session(&mut self, admin: &str) -> Result<(), AdminError> {
+        self.require_admin(admin)?;
+        self.q.clear();
+        self.users.clear();
+        self.history.clear();
+        self.user_weights = HashMap::new();
+        self.submissions = HashMap::new();
+        self.listeners.clear();
+        self.notifications.clear();
+        self.locked = false;
+        self.audit(admin, AdminAction::ResetSession);
+        Ok(())
+    }
+
+    /**
+     * Removes and returns a queued song by id from the global queue or any user's queue
+     * */
+    pub(crate) fn take_song(&mut self, song_id: u64) -> Option<Song> {
+        if let Some(i) = self.q.iter().position(|song| song.id == song_id) {
+            return self.q.remove(i);
+        }
+        self.users.iter_mut().find_map(|user| {
+            user.q
+                .iter()
+                .position(|song| song.id == song_id)
+                .and_then(|i| user.q.remove(i))
+        })
+    }
+
+    /**
+     * Removes all queued songs matching the ban list
+     * */
+    fn remove_banned(&mut self) {
+        let bans = &self.bans;
+        self.q.retain(|song| !bans.is_banned(song));
+        self.users
+            .iter_mut()
+            .for_each(|user| user.q.retain(|song| !bans.is_banned(song)));
+    }
+
+    fn require_admin(&self, user_id: &str) -> Result<(), AdminError> {
+        if self.role_of(user_id) >= Role::Admin {
+            Ok(())
+        } else {
+            log!(Level::Info, "{} attempted an admin action without permission", user_id);
+            Err(AdminError::NotPermitted)
+        }
+    }
+
+    fn audit(&mut self, admin: &str, action: AdminAction) {
+        log!(Level::Info, "admin {} performed {:?}", admin, action);
+        self.audit_log.push(AuditEntry {
+            time: SystemTime::now(),
+            admin: admin.to_string(),
+            action,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprint::SongMetadata;
+    use crate::types::SourceInfo;
+
+    fn queue_with_admin() -> GlobalQueue {
+        let mut queue = GlobalQueue::new();
+        queue.roles.insert("admin".to_string(), Role::Admin);
+        queue
+    }
+
+    fn youtube_song(link: &str) -> Song {
+        let mut song = Song::new(SongOrigin::Youtube(link.to_string()), String::new());
+        // Stands in for the yt-dlp lookup done when a song is enqueued
+        song.source_info = Some(SourceInfo::default());
+        song
+    }
+
+    #[test]
+    fn banned_video_is_rejected_with_any_timestamp() {
+        let mut queue = queue_with_admin();
+        let banned = SongOrigin::Youtube("https://youtu.be/dQw4w9WgXcQ?t=30".to_string());
+        queue.ban_source("admin", banned).unwrap();
+
+        for link in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=1",
+            "https://youtu.be/dQw4w9WgXcQ?t=1m30s",
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ#t=75",
+        ] {
+            let result = queue.enqueue("dj", youtube_song(link));
+            assert!(matches!(result, Err(EnqueueError::SongBanned)), "{} was not banned", link);
+            assert!(queue.bans.is_banned(&youtube_song(link)), "{} was not banned", link);
+        }
+        assert!(queue
+            .enqueue("dj", youtube_song("https://youtu.be/9bZkp7q19f0?t=5"))
+            .is_ok());
+    }
+
+    fn file(path: &str) -> Song {
+        Song::new(SongOrigin::FileUpload(path.to_string()), String::new())
+    }
+
+    fn queued(queue: &GlobalQueue) -> Vec<SongOrigin> {
+        let users = queue.users.iter().flat_map(|user| user.q.iter());
+        queue.q.iter().chain(users).map(|song| song.origin.clone()).collect()
+    }
+
+    #[test]
+    fn banned_sources_are_removed_and_blocked() {
+        let mut queue = queue_with_admin();
+        queue.enqueue("dj", file("/music/a.flac")).unwrap();
+        queue.enqueue("dj", file("/music/b.flac")).unwrap();
+        queue.q.push_back(queue.users[0].q.pop_front().unwrap());
+
+        let banned = SongOrigin::FileUpload("/music/a.flac".to_string());
+        assert_eq!(
+            queue.ban_source("dj", banned.clone()),
+            Err(AdminError::NotPermitted)
+        );
+        queue.ban_source("admin", banned.clone()).unwrap();
+        assert_eq!(queued(&queue), [file("/music/b.flac").origin]);
+
+        // Admins can't queue banned songs either
+        for user in ["dj", "admin"] {
+            let result = queue.enqueue(user, file("/music/a.flac"));
+            assert!(matches!(result, Err(EnqueueError::SongBanned)), "{} queued it", user);
+        }
+        assert!(matches!(
+            queue.audit_log.last().map(|entry| &entry.action),
+            Some(AdminAction::BanSource(origin)) if *origin == banned
+        ));
+    }
+
+    #[test]
+    fn banned_recordings_are_blocked() {
+        let mut queue = queue_with_admin();
+        let mut song = file("/music/a.flac");
+        song.metadata = Some(SongMetadata {
+            recording_mbid: Some("mbid".to_string()),
+            ..SongMetadata::default()
+        });
+        queue.enqueue("dj", song.clone()).unwrap();
+        queue.ban_recording("admin", "mbid").unwrap();
+        assert!(queued(&queue).is_empty());
+        assert!(matches!(
+            queue.enqueue("dj", song),
+            Err(EnqueueError::SongBanned)
+        ));
+    }
+
+    #[test]
+    fn banned_users_cant_queue() {
+        let mut queue = queue_with_admin();
+        queue.enqueue("dj", file("/music/a.flac")).unwrap();
+        queue.enqueue("other", file("/music/b.flac")).unwrap();
+        queue.ban_user("admin", "dj").unwrap();
+
+        assert_eq!(queued(&queue), [file("/music/b.flac").origin]);
+        assert!(matches!(
+            queue.enqueue("dj", file("/music/c.flac")),
+            Err(EnqueueError::UserBanned)
+        ));
+        queue.unban_user("admin", "dj").unwrap();
+        assert!(queue.enqueue("dj", file("/music/c.flac")).is_ok());
+    }
+
+    #[test]
+    fn roles_and_locking_limit_who_can_queue() {
+        let mut queue = queue_with_admin();
+        queue.set_role("admin", "listener", Role::Listener).unwrap();
+        assert!(matches!(
+            queue.enqueue("listener", file("/music/a.flac")),
+            Err(EnqueueError::NotPermitted)
+        ));
+        assert_eq!(
+            queue.set_role("dj", "dj", Role::Admin),
+            Err(AdminError::NotPermitted)
+        );
+
+        queue.lock("admin").unwrap();
+        assert!(matches!(
+            queue.enqueue("dj", file("/music/a.flac")),
+            Err(EnqueueError::QueueLocked)
+        ));
+        assert!(queue.enqueue("admin", file("/music/a.flac")).is_ok());
+        queue.unlock("admin").unwrap();
+        assert!(queue.enqueue("dj", file("/music/b.flac")).is_ok());
+    }
+
+    #[test]
+    fn reset_sessions_start_fresh() {
+        let mut queue = queue_with_admin();
+        queue.enqueue("dj", file("/music/a.flac")).unwrap();
+        queue.enqueue("dj", file("/music/b.flac")).unwrap();
+        queue.next(0).unwrap();
+        queue.join_listener("listener");
+        queue.vote_skip("listener");
+        queue.notify("dj", "Your song was removed".to_string());
+        queue.ban_user("admin", "banned").unwrap();
+        queue.lock("admin").unwrap();
+
+        assert_eq!(queue.reset_session("dj"), Err(AdminError::NotPermitted));
+        queue.reset_session("admin").unwrap();
+
+        assert!(queued(&queue).is_empty());
+        assert!(queue.history.is_empty() && queue.now_playing().is_none());
+        assert!(queue.user_weights.is_empty() && queue.submissions.is_empty());
+        assert!(queue.listeners.is_empty());
+        assert_eq!(queue.skip_votes_needed(), queue.votes.min_skip_votes);
+        assert!(queue.take_notifications("dj").is_empty());
+        assert!(!queue.locked);
+        // What an admin set up stays
+        assert!(queue.bans.users.contains("banned"));
+        assert_eq!(queue.role_of("admin"), Role::Admin);
+        assert!(matches!(
+            queue.audit_log.last().map(|entry| &entry.action),
+            Some(AdminAction::ResetSession)
+        ));
+    }
+}
@@ -0,0 +1,323 @@
+use log::{log, Level};
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::types::{GlobalQueue, HistoryEntry, Song, SongOrigin};
+
+/**
+ * Submitter shown for songs chosen by autoplay. Songs are marked as autoplay by Song::autoplay,
+ * not by their submitter, so a user with this ID can't pass their songs off as autoplay.
+ * */
+pub const AUTOPLAY_SUBMITTER: &str = "autoplay";
+
+/**
+ * Where autoplay picks songs from when every queue is empty
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AutoplayMode {
+    /**
+     * Autoplay is disabled and the room goes silent
+     * */
+    Off,
+
+    /**
+     * Play through a house playlist in order, looping at the end
+     * */
+    Playlist(Vec<SongOrigin>),
+
+    /**
+     * Shuffle through recently played songs, favouring well received ones
+     * */
+    History,
+
+    /**
+     * Pick songs from history sharing artists or genres with the last song played, falling back
+     * to shuffling history if nothing is similar
+     * */
+    MoreLikeThis,
+
+    /**
+     * Shuffle through the local music library
+     * */
+    Library,
+}
+
+/**
+ * Autoplay configuration and state
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Autoplay {
+    /**
+     * Where songs are picked from
+     * */
+    pub mode: AutoplayMode,
+
+    /**
+     * Number of most recently played songs that won't be picked again
+     * */
+    pub avoid_recent: usize,
+
+    /**
+     * Position of the next song in the house playlist
+     * */
+    pub position: usize,
+}
+
+impl Default for Autoplay {
+    fn default() -> Self {
+        Autoplay {
+            mode: AutoplayMode::Off,
+            avoid_recent: 10,
+            position: 0,
+        }
+    }
+}
+
+impl Song {
+    /**
+     * Whether this song was picked by autoplay rather than submitted by a user
+     * */
+    pub fn is_autoplay(&self) -> bool {
+        self.autoplay
+    }
+
+    /**
+     * Marks the song as picked by autoplay
+     * */
+    fn into_autoplay(mut self) -> Song {
+        self.submitter = AUTOPLAY_SUBMITTER.to_string();
+        self.autoplay = true;
+        self
+    }
+}
+
+impl GlobalQueue {
+    /**
+     * Whether the current song is from autoplay and a user has since queued a song, meaning the
+     * player should stop the current song and move on
+     * */
+    pub fn should_yield_autoplay(&self) -> bool {
+        self.now_playing().is_some_and(|song| song.is_autoplay()) && self.has_queued()
+    }
+
+    /**
+     * Picks a song to play while every queue is empty, according to the autoplay mode. Songs are
+     * checked against the moderation rules and ban list the same way submissions are, and ones
+     * that fail are passed over.
+     * */
+    pub(crate) fn autoplay_song(&mut self) -> Option<Song> {
+        let song = match self.autoplay.mode.clone() {
+            AutoplayMode::Off => None,
+            AutoplayMode::Playlist(origins) => self.pick_from_playlist(&origins),
+            AutoplayMode::History => self.pick_from_history(|_| 1.0),
+            AutoplayMode::MoreLikeThis => {
+                let last = self
+                    .history
+                    .back()
+                    .and_then(|entry| entry.song.metadata.clone());
+                match last {
+                    Some(last) => {
+                        let artists = last.artist_mbids().into_iter().collect::<HashSet<_>>();
+                        let similar = self.pick_from_history(|song| {
+                            song.metadata.as_ref().map_or(0.0, |meta| {
+                                let shared_artists = meta
+                                    .artist_mbids()
+                                    .iter()
+                                    .filter(|id| artists.contains(*id))
+                                    .count();
+                                let shared_genres = meta
+                                    .genres
+                                    .iter()
+                                    .filter(|g| last.genres.contains(g))
+                                    .count();
+                                (2 * shared_artists + shared_genres) as f64
+                            })
+                        });
+                        similar.or_else(|| self.pick_from_history(|_| 1.0))
+                    }
+                    None => self.pick_from_history(|_| 1.0),
+                }
+            }
+            AutoplayMode::Library => self.pick_from_library(),
+        };
+
+        if let Some(song) = &song {
+            log!(Level::Debug, "autoplay picked {:?}", song.origin);
+        }
+        song
+    }
+
+    /**
+     * Takes the next allowed song from the house playlist, moving the position on past it
+     * */
+    fn pick_from_playlist(&mut self, origins: &[SongOrigin]) -> Option<Song> {
+        for _ in 0..origins.len() {
+            let origin = origins[self.autoplay.position % origins.len()].clone();
+            self.autoplay.position = (self.autoplay.position + 1) % origins.len();
+            let song = Song::new(origin, String::new()).into_autoplay();
+            match self.moderate(&song) {
+                Ok(()) => return Some(song),
+                Err(reason) => {
+                    log!(Level::Debug, "autoplay passed over {:?}: {}", song.origin, reason)
+                }
+            }
+        }
+        None
+    }
+
+    /**
+     * Picks a random track from the local library that hasn't been played recently
+     * */
+    fn pick_from_library(&self) -> Option<Song> {
+        let library = self.library.as_ref()?;
+        let recent = self
+            .history
+            .iter()
+            .rev()
+            .take(self.autoplay.avoid_recent)
+            .filter_map(|entry| match &entry.song.origin {
+                SongOrigin::FileUpload(path) => Some(path.as_str()),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        // Draw a few extra so recently played tracks can be passed over
+        let tracks = match library.random(recent.len() + 1) {
+            Ok(tracks) => tracks,
+            Err(e) => {
+                log!(Level::Warn, "could not pick a track from the library: {}", e);
+                return None;
+            }
+        };
+        tracks
+            .iter()
+            .filter(|track| !recent.contains(track.path.as_str()))
+            .map(|track| track.to_song("").into_autoplay())
+            .find(|song| self.moderate(song).is_ok())
+    }
+
+    /**
+     * Picks a random song from history, weighted by its reactions, whether it was skipped, and
+     * {affinity}. Songs with an affinity of 0 are never picked. Recently played songs are avoided
+     * unless there is nothing else, in which case anything but the last song played will do.
+     * */
+    fn pick_from_history<F: Fn(&Song) -> f64>(&self, affinity: F) -> Option<Song> {
+        let recent = self
+            .history
+            .iter()
+            .rev()
+            .take(self.autoplay.avoid_recent)
+            .map(|entry| &entry.song.origin)
+            .collect::<HashSet<_>>();
+
+        let mut candidates = self.history_candidates(&recent, &affinity);
+        if candidates.is_empty() {
+            // Early in a session every song is recent, and playing one again beats silence
+            let last = self.history.back().map(|entry| &entry.song.origin);
+            candidates = self.history_candidates(&last.into_iter().collect(), &affinity);
+        }
+
+        let dist = WeightedIndex::new(candidates.iter().map(|(_, w)| *w)).ok()?;
+        let (entry, _) = candidates[dist.sample(&mut thread_rng())];
+
+        let mut song = Song::new(entry.song.origin.clone(), String::new()).into_autoplay();
+        song.metadata = entry.song.metadata.clone();
+        song.fingerprint = entry.song.fingerprint.clone();
+        song.source_info = entry.song.source_info.clone();
+        Some(song)
+    }
+
+    /**
+     * Songs in history autoplay can pick, other than those from {exclude}, with their weights
+     * */
+    fn history_candidates<F: Fn(&Song) -> f64>(
+        &self,
+        exclude: &HashSet<&SongOrigin>,
+        affinity: &F,
+    ) -> Vec<(&HistoryEntry, f64)> {
+        let mut seen = HashSet::new();
+        self.history
+            .iter()
+            .rev()
+            .filter(|entry| !exclude.contains(&entry.song.origin))
+            .filter(|entry| seen.insert(&entry.song.origin))
+            // Songs can be banned or blocked after they were played
+            .filter(|entry| self.moderate(&entry.song).is_ok())
+            .filter_map(|entry| {
+                let affinity = affinity(&entry.song);
+                if affinity <= 0.0 {
+                    return None;
+                }
+                let mut weight = (1.0 + entry.song.score() as f64).max(0.1) * affinity;
+                if entry.skipped {
+                    weight *= 0.25;
+                }
+                Some((entry, weight))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn played(queue: &mut GlobalQueue, path: &str) {
+        queue.history.push_back(HistoryEntry {
+            song: Song::new(SongOrigin::FileUpload(path.to_string()), "dj".to_string()),
+            played_at: SystemTime::now(),
+            finished: true,
+            skipped: false,
+        });
+    }
+
+    fn picks(queue: &mut GlobalQueue) -> HashSet<SongOrigin> {
+        (0..50)
+            .map(|_| queue.autoplay_song().expect("autoplay picked nothing"))
+            .inspect(|song| assert!(song.is_autoplay()))
+            .map(|song| song.origin)
+            .collect()
+    }
+
+    fn file(path: &str) -> SongOrigin {
+        SongOrigin::FileUpload(path.to_string())
+    }
+
+    #[test]
+    fn history_avoids_recent_songs() {
+        let mut queue = GlobalQueue::new();
+        queue.autoplay.mode = AutoplayMode::History;
+        queue.autoplay.avoid_recent = 2;
+        for path in ["/music/a.flac", "/music/b.flac", "/music/c.flac"] {
+            played(&mut queue, path);
+        }
+        assert_eq!(picks(&mut queue), HashSet::from([file("/music/a.flac")]));
+    }
+
+    #[test]
+    fn history_replays_recent_songs_rather_than_nothing() {
+        for mode in [AutoplayMode::History, AutoplayMode::MoreLikeThis] {
+            let mut queue = GlobalQueue::new();
+            queue.autoplay.mode = mode;
+            for path in ["/music/a.flac", "/music/b.flac", "/music/c.flac"] {
+                played(&mut queue, path);
+            }
+            // Everything is recent, so anything but the song that just played is picked
+            assert_eq!(
+                picks(&mut queue),
+                HashSet::from([file("/music/a.flac"), file("/music/b.flac")])
+            );
+        }
+    }
+
+    #[test]
+    fn history_with_only_the_last_song_picks_nothing() {
+        let mut queue = GlobalQueue::new();
+        queue.autoplay.mode = AutoplayMode::History;
+        played(&mut queue, "/music/a.flac");
+        assert!(queue.autoplay_song().is_none());
+    }
+}
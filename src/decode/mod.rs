@@ -0,0 +1,187 @@
+use anyhow::Error;
+use rodio::{Decoder, Source};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+/**
+ * Internal module for decoding AAC audio from MP4 containers
+ * */
+pub mod mp4;
+
+/**
+ * Internal module for decoding Opus audio from Ogg containers
+ * */
+pub mod ogg;
+
+/**
+ * Internal module for decoding Opus audio from WebM containers
+ * */
+pub mod webm;
+
+use self::ogg::OggOpusDecoder;
+use mp4::Mp4AacDecoder;
+use webm::WebmOpusDecoder;
+
+/**
+ * Decoded audio, ready to be played or analyzed
+ * */
+pub type AudioSource = Box<dyn Source<Item = f32> + Send>;
+
+/**
+ * Containers that are decoded with the Opus decoder rather than rodio's
+ * */
+const WEBM_EXTENSIONS: [&str; 3] = ["webm", "weba", "mka"];
+
+/**
+ * Extension of Opus audio in an Ogg container. Ogg files with other extensions are usually
+ * Vorbis, which rodio decodes.
+ * */
+const OGG_OPUS_EXTENSION: &str = "opus";
+
+/**
+ * Containers that are decoded with symphonia's MP4 reader rather than rodio's
+ * */
+const MP4_EXTENSIONS: [&str; 2] = ["m4a", "mp4"];
+
+/**
+ * Opens and decodes an audio file, choosing the decoder from its extension. WebM and .opus files
+ * are decoded as Opus, m4a files as AAC, and everything else (mp3, flac, ...) is handed to rodio.
+ * */
+pub fn open_file(path: &str) -> Result<AudioSource, Error> {
+    let file = File::open(path)?;
+    open_reader(file, extension(path).as_deref())
+}
+
+/**
+ * Decodes audio from a reader. {extension} is the extension of the file the audio came from, and
+ * is used to pick the decoder.
+ * */
+pub fn open_reader<R>(reader: R, extension: Option<&str>) -> Result<AudioSource, Error>
+where
+    R: Read + Seek + Send + Sync + 'static,
+{
+    let reader = BufReader::new(reader);
+    match extension {
+        Some(ext) if is_webm(ext) => Ok(Box::new(WebmOpusDecoder::new(reader)?)),
+        Some(OGG_OPUS_EXTENSION) => Ok(Box::new(OggOpusDecoder::new(reader)?)),
+        Some(ext) if MP4_EXTENSIONS.contains(&ext) => Ok(Box::new(Mp4AacDecoder::new(reader)?)),
+        _ => Ok(Box::new(Decoder::new(reader)?.convert_samples())),
+    }
+}
+
+/**
+ * Gets the lowercase extension of a path, ignoring a trailing ".part" left on in-progress
+ * downloads
+ * */
+pub fn extension(path: &str) -> Option<String> {
+    let path = path.strip_suffix(".part").unwrap_or(path);
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+}
+
+/**
+ * Reads all of {source}, passing it to {f} in chunks of whole frames
+ * */
+pub fn for_each_chunk<S, F>(mut source: S, mut f: F) -> Result<(), Error>
+where
+    S: Source<Item = f32>,
+    F: FnMut(&[f32]) -> Result<(), Error>,
+{
+    let channels = source.channels().max(1) as usize;
+    let mut buf: Vec<f32> = Vec::with_capacity(4096 * channels);
+    loop {
+        buf.extend(source.by_ref().take(buf.capacity() - buf.len()));
+        let whole = buf.len() - buf.len() % channels;
+        if whole == 0 {
+            return Ok(());
+        }
+        f(&buf[..whole])?;
+        buf.drain(..whole);
+    }
+}
+
+fn is_webm(extension: &str) -> bool {
+    WEBM_EXTENSIONS.contains(&extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/decode/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /**
+     * Decodes a fixture, returning the source and all of its samples
+     * */
+    fn decode(name: &str) -> (u16, u32, Option<Duration>, Vec<f32>) {
+        let source = open_file(&fixture(name)).unwrap();
+        let (channels, rate, total) = (
+            source.channels(),
+            source.sample_rate(),
+            source.total_duration(),
+        );
+        (channels, rate, total, source.collect())
+    }
+
+    /**
+     * Index of the first frame where either channel is louder than {threshold}
+     * */
+    fn onset(samples: &[f32], channels: u16, threshold: f32) -> usize {
+        samples.iter().position(|s| s.abs() > threshold).unwrap() / channels as usize
+    }
+
+    /**
+     * Length of {samples} when played at {rate}
+     * */
+    fn played_for(samples: &[f32], channels: u16, rate: u32) -> Duration {
+        Duration::from_secs_f64(samples.len() as f64 / channels as f64 / rate as f64)
+    }
+
+    fn assert_near(actual: Duration, expected: Duration, tolerance: Duration) {
+        let diff = actual.max(expected) - actual.min(expected);
+        assert!(diff <= tolerance, "{:?} is not within {:?} of {:?}", actual, tolerance, expected);
+    }
+
+    #[test]
+    fn decodes_webm_opus() {
+        let (channels, rate, total, samples) = decode("tone.webm");
+        assert_eq!(channels, 2);
+        assert_eq!(rate, 48000);
+        assert_eq!(total, Some(Duration::from_secs(1)));
+
+        // 50 packets of 960 samples per channel, less the 312 sample pre-skip
+        assert_eq!(samples.len(), (50 * 960 - 312) * 2);
+        assert_near(
+            played_for(&samples, channels, rate),
+            Duration::from_secs(1),
+            Duration::from_millis(10),
+        );
+
+        // The tone starts a quarter second in. Without the pre-skip it would be 312 samples late.
+        let start = onset(&samples, channels, 0.05);
+        assert!((11950..12050).contains(&start), "tone starts at {}", start);
+    }
+
+    #[test]
+    fn decodes_m4a_aac() {
+        let (channels, rate, total, samples) = decode("tone.m4a");
+        assert_eq!(channels, 2);
+        assert_eq!(rate, 44100);
+
+        // 44 frames of 1024 samples per channel. AAC's encoder delay is left in, as symphonia
+        // doesn't trim it from MP4 files.
+        assert_eq!(samples.len(), 44 * 1024 * 2);
+        let total = total.unwrap();
+        assert_near(total, Duration::from_secs_f64(45056.0 / 44100.0), Duration::from_millis(1));
+        assert_near(played_for(&samples, channels, rate), total, Duration::from_millis(1));
+
+        let start = onset(&samples, channels, 0.05);
+        assert!((11025 + 2048..11025 + 2148).contains(&start), "tone starts at {}", start);
+    }
+}
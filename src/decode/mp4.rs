@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Error};
+use log::{log, Level};
+use rodio::Source;
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Duration;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::default::formats::IsoMp4Reader;
+
+/**
+ * Decoder for AAC audio in an MP4 (m4a) container, as served by YouTube.
+ *
+ * rodio can't be used for these: it hides the length of the file from symphonia, whose MP4 reader
+ * won't open a seekable file without it.
+ * */
+pub struct Mp4AacDecoder {
+    format: IsoMp4Reader,
+    decoder: Box<dyn Decoder>,
+    track: u32,
+    channels: u16,
+    sample_rate: u32,
+    buffer: VecDeque<f32>,
+    total_duration: Option<Duration>,
+}
+
+impl Mp4AacDecoder {
+    /**
+     * Opens the first audio track in an MP4 file. The MP4 index is read from wherever it is in the
+     * file, so the whole file has to be readable: a file that is still downloading is waited for
+     * until it is complete.
+     * */
+    pub fn new<R>(reader: R) -> Result<Self, Error>
+    where
+        R: Read + Seek + Send + Sync + 'static,
+    {
+        let source = SizedSource::new(reader)?;
+        let stream = MediaSourceStream::new(Box::new(source), Default::default());
+        let format = IsoMp4Reader::try_new(stream, &FormatOptions::default())
+            .map_err(|e| anyhow!("Invalid MP4 file: {}", e))?;
+
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| anyhow!("MP4 file contains no audio"))?;
+        let params = track.codec_params.clone();
+        let sample_rate = params
+            .sample_rate
+            .ok_or_else(|| anyhow!("MP4 audio has no sample rate"))?;
+        let total_duration = params
+            .n_frames
+            .map(|frames| Duration::from_secs_f64(frames as f64 / sample_rate as f64));
+
+        let decoder = symphonia::default::get_codecs()
+            .make(&params, &DecoderOptions::default())
+            .map_err(|e| anyhow!("Unsupported MP4 audio: {}", e))?;
+
+        let mut mp4 = Mp4AacDecoder {
+            track: track.id,
+            format,
+            decoder,
+            channels: 0,
+            sample_rate,
+            buffer: VecDeque::new(),
+            total_duration,
+        };
+
+        // The channel count isn't always in the container, but is known once a packet is decoded
+        while mp4.buffer.is_empty() {
+            if !mp4.decode_next() {
+                return Err(anyhow!("MP4 file contains no audio"));
+            }
+        }
+        Ok(mp4)
+    }
+
+    /**
+     * Decodes the next packet of the audio track into the buffer. Returns false at the end of the
+     * stream.
+     * */
+    fn decode_next(&mut self) -> bool {
+        let packet = loop {
+            match self.format.next_packet() {
+                Ok(packet) if packet.track_id() == self.track => break packet,
+                Ok(_) => continue,
+                Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return false
+                }
+                Err(e) => {
+                    log!(Level::Warn, "error reading MP4 packet: {}", e);
+                    return false;
+                }
+            }
+        };
+
+        match self.decoder.decode(&packet) {
+            Ok(decoded) => {
+                let spec = *decoded.spec();
+                self.channels = spec.channels.count() as u16;
+                let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+                samples.copy_interleaved_ref(decoded);
+                self.buffer.extend(samples.samples());
+                true
+            }
+            Err(e) => {
+                // A single corrupt packet shouldn't end the song
+                log!(Level::Warn, "error decoding AAC packet: {}", e);
+                true
+            }
+        }
+    }
+}
+
+impl Iterator for Mp4AacDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        while self.buffer.is_empty() {
+            if !self.decode_next() {
+                return None;
+            }
+        }
+        self.buffer.pop_front()
+    }
+}
+
+impl Source for Mp4AacDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+}
+
+/**
+ * A reader that tells symphonia how long it is
+ * */
+struct SizedSource<R> {
+    reader: R,
+    len: u64,
+}
+
+impl<R: Read + Seek> SizedSource<R> {
+    fn new(mut reader: R) -> io::Result<Self> {
+        let start = reader.stream_position()?;
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(start))?;
+        Ok(SizedSource { reader, len })
+    }
+}
+
+impl<R: Read> Read for SizedSource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for SizedSource<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
+
+impl<R: Read + Seek + Send + Sync> MediaSource for SizedSource<R> {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+}
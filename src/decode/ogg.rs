@@ -0,0 +1,138 @@
+use anyhow::{anyhow, Error};
+use log::{log, Level};
+use ogg::PacketReader;
+use opus::Channels;
+use rodio::Source;
+use std::collections::VecDeque;
+use std::io::{Read, Seek};
+use std::time::Duration;
+
+use super::webm::{MAX_FRAME_SAMPLES, OPUS_SAMPLE_RATE};
+
+/**
+ * Decoder for Opus audio in an Ogg container, as left by yt-dlp when it extracts Opus audio from
+ * sites other than YouTube
+ * */
+pub struct OggOpusDecoder<R: Read + Seek> {
+    packets: PacketReader<R>,
+    decoder: opus::Decoder,
+    serial: u32,
+    channels: u16,
+    buffer: VecDeque<f32>,
+    decoded: Vec<f32>,
+    /**
+     * Samples still to be dropped from the start of the stream, as required by the pre-skip in
+     * the Opus header
+     * */
+    pre_skip: usize,
+}
+
+impl<R: Read + Seek> OggOpusDecoder<R> {
+    /**
+     * Opens the first logical stream of an Ogg file, which must be Opus
+     * */
+    pub fn new(reader: R) -> Result<Self, Error> {
+        let mut packets = PacketReader::new(reader);
+        let head = packets
+            .read_packet()?
+            .ok_or_else(|| anyhow!("Ogg file is empty"))?;
+
+        // OpusHead: magic (8), version (1), channels (1), pre-skip (2, little endian), ...
+        if head.data.len() < 19 || !head.data.starts_with(b"OpusHead") {
+            return Err(anyhow!("Ogg file does not contain Opus audio"));
+        }
+        let channels = head.data[9];
+        let opus_channels = match channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            n => return Err(anyhow!("Unsupported Opus channel count {}", n)),
+        };
+        let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as usize;
+
+        let serial = head.stream_serial();
+        // The comment header always comes next, and has nothing needed for playback
+        let tags = packets
+            .read_packet()?
+            .ok_or_else(|| anyhow!("Ogg Opus file has no comment header"))?;
+        if !tags.data.starts_with(b"OpusTags") {
+            return Err(anyhow!("Ogg Opus file has an invalid comment header"));
+        }
+
+        Ok(OggOpusDecoder {
+            packets,
+            decoder: opus::Decoder::new(OPUS_SAMPLE_RATE, opus_channels)?,
+            serial,
+            channels: channels as u16,
+            buffer: VecDeque::new(),
+            decoded: vec![0.0; MAX_FRAME_SAMPLES * channels as usize],
+            pre_skip: pre_skip * channels as usize,
+        })
+    }
+
+    /**
+     * Decodes the next packet of the Opus stream into the buffer. Returns false at the end of the
+     * stream.
+     * */
+    fn decode_next(&mut self) -> bool {
+        let packet = loop {
+            match self.packets.read_packet() {
+                Ok(Some(packet)) if packet.stream_serial() == self.serial => break packet,
+                Ok(Some(_)) => continue,
+                Ok(None) => return false,
+                Err(e) => {
+                    log!(Level::Warn, "error reading Ogg packet: {}", e);
+                    return false;
+                }
+            }
+        };
+
+        match self
+            .decoder
+            .decode_float(&packet.data, &mut self.decoded, false)
+        {
+            Ok(per_channel) => {
+                let samples = per_channel * self.channels as usize;
+                let skip = self.pre_skip.min(samples);
+                self.pre_skip -= skip;
+                self.buffer.extend(&self.decoded[skip..samples]);
+                true
+            }
+            Err(e) => {
+                // A single corrupt packet shouldn't end the song
+                log!(Level::Warn, "error decoding Opus packet: {}", e);
+                true
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Iterator for OggOpusDecoder<R> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        while self.buffer.is_empty() {
+            if !self.decode_next() {
+                return None;
+            }
+        }
+        self.buffer.pop_front()
+    }
+}
+
+impl<R: Read + Seek> Source for OggOpusDecoder<R> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        OPUS_SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Error};
+use log::{log, Level};
+use matroska_demuxer::{Frame, MatroskaFile, TrackType};
+use opus::Channels;
+use rodio::Source;
+use std::collections::VecDeque;
+use std::io::{Read, Seek};
+use std::time::Duration;
+
+/**
+ * Opus always decodes at 48kHz regardless of the input's original sample rate
+ * */
+pub(super) const OPUS_SAMPLE_RATE: u32 = 48000;
+
+/**
+ * Largest Opus packet is 120ms, which at 48kHz is 5760 samples per channel
+ * */
+pub(super) const MAX_FRAME_SAMPLES: usize = 5760;
+
+/**
+ * Decoder for Opus audio in a WebM (Matroska) container, as served by YouTube
+ * */
+pub struct WebmOpusDecoder<R: Read + Seek> {
+    mkv: MatroskaFile<R>,
+    decoder: opus::Decoder,
+    track: u64,
+    channels: u16,
+    frame: Frame,
+    buffer: VecDeque<f32>,
+    decoded: Vec<f32>,
+    /**
+     * Samples still to be dropped from the start of the stream, as required by the pre-skip in
+     * the Opus header
+     * */
+    pre_skip: usize,
+    total_duration: Option<Duration>,
+}
+
+impl<R: Read + Seek> WebmOpusDecoder<R> {
+    /**
+     * Opens the first Opus track in a WebM file
+     * */
+    pub fn new(reader: R) -> Result<Self, Error> {
+        let mkv = MatroskaFile::open(reader).map_err(|e| anyhow!("Invalid WebM file: {:?}", e))?;
+
+        let track = mkv
+            .tracks()
+            .iter()
+            .find(|t| t.track_type() == TrackType::Audio && t.codec_id() == "A_OPUS")
+            .ok_or_else(|| anyhow!("WebM file contains no Opus audio"))?;
+
+        let channels = track
+            .audio()
+            .map(|a| a.channels().get())
+            .unwrap_or(2);
+        let opus_channels = match channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            n => return Err(anyhow!("Unsupported Opus channel count {}", n)),
+        };
+
+        // OpusHead: magic (8), version (1), channels (1), pre-skip (2, little endian), ...
+        let pre_skip = track
+            .codec_private()
+            .filter(|head| head.len() >= 12 && head.starts_with(b"OpusHead"))
+            .map_or(0, |head| u16::from_le_bytes([head[10], head[11]]) as usize);
+
+        // Duration is in units of the timestamp scale, which is in nanoseconds
+        let total_duration = mkv.info().duration().map(|d| {
+            Duration::from_nanos((d * mkv.info().timestamp_scale().get() as f64) as u64)
+        });
+
+        Ok(WebmOpusDecoder {
+            decoder: opus::Decoder::new(OPUS_SAMPLE_RATE, opus_channels)?,
+            track: track.track_number().get(),
+            channels: channels as u16,
+            mkv,
+            frame: Frame::default(),
+            buffer: VecDeque::new(),
+            decoded: vec![0.0; MAX_FRAME_SAMPLES * channels as usize],
+            pre_skip: pre_skip * channels as usize,
+            total_duration,
+        })
+    }
+
+    /**
+     * Decodes the next packet of the Opus track into the buffer. Returns false at the end of the
+     * stream.
+     * */
+    fn decode_next(&mut self) -> bool {
+        loop {
+            match self.mkv.next_frame(&mut self.frame) {
+                Ok(true) if self.frame.track == self.track => break,
+                Ok(true) => continue,
+                Ok(false) => return false,
+                Err(e) => {
+                    log!(Level::Warn, "error reading WebM frame: {:?}", e);
+                    return false;
+                }
+            }
+        }
+
+        match self
+            .decoder
+            .decode_float(&self.frame.data, &mut self.decoded, false)
+        {
+            Ok(per_channel) => {
+                let samples = per_channel * self.channels as usize;
+                let skip = self.pre_skip.min(samples);
+                self.pre_skip -= skip;
+                self.buffer.extend(&self.decoded[skip..samples]);
+                true
+            }
+            Err(e) => {
+                // A single corrupt packet shouldn't end the song
+                log!(Level::Warn, "error decoding Opus packet: {}", e);
+                true
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Iterator for WebmOpusDecoder<R> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        while self.buffer.is_empty() {
+            if !self.decode_next() {
+                return None;
+            }
+        }
+        self.buffer.pop_front()
+    }
+}
+
+impl<R: Read + Seek> Source for WebmOpusDecoder<R> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        OPUS_SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+}
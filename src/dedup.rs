@@ -0,0 +1,302 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::types::Song;
+
+/**
+ * What to do when a submitted song is a duplicate of one already queued or recently played
+ * */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DuplicatePolicy {
+    /**
+     * Refuse to queue the song
+     * */
+    Reject,
+
+    /**
+     * Queue the song anyway, but let the submitter know it is a duplicate
+     * */
+    Warn,
+
+    /**
+     * Don't queue the song, and count the submission as an upvote of the existing song instead.
+     * Duplicates of songs in history are rejected, since there is nothing left to upvote.
+     * */
+    Merge,
+}
+
+/**
+ * Configuration for duplicate detection
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateConfig {
+    /**
+     * What to do with duplicate submissions
+     * */
+    pub policy: DuplicatePolicy,
+
+    /**
+     * How long after a song was played it is still considered a duplicate
+     * */
+    pub window: Duration,
+
+    /**
+     * Minimum fingerprint similarity from 0.0 - 1.0 for two songs to be considered the same
+     * */
+    pub similarity_threshold: f64,
+}
+
+impl Default for DuplicateConfig {
+    fn default() -> Self {
+        DuplicateConfig {
+            policy: DuplicatePolicy::Warn,
+            window: Duration::from_secs(60 * 60),
+            similarity_threshold: 0.85,
+        }
+    }
+}
+
+/**
+ * Where the existing copy of a duplicate song was found
+ * */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DuplicateLocation {
+    /**
+     * In the global queue, at the contained index
+     * */
+    GlobalQueue(usize),
+
+    /**
+     * In a user's queue; contains the user id and index in that queue
+     * */
+    UserQueue(String, usize),
+
+    /**
+     * Played within the configured duplicate window
+     * */
+    History,
+}
+
+/**
+ * Why two songs were considered duplicates, from most to least certain
+ * */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DuplicateReason {
+    /**
+     * Both songs have the same origin
+     * */
+    SameOrigin,
+
+    /**
+     * Both songs matched the same MusicBrainz recording
+     * */
+    SameRecording(String),
+
+    /**
+     * Both songs matched the same AcoustID track
+     * */
+    SameAcoustId(String),
+
+    /**
+     * The songs' fingerprints are similar; contains the similarity from 0.0 - 1.0
+     * */
+    SimilarFingerprint(f64),
+}
+
+/**
+ * A song found to duplicate a submitted song
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Duplicate {
+    /**
+     * Where the existing song is
+     * */
+    pub location: DuplicateLocation,
+
+    /**
+     * The user who submitted the existing song
+     * */
+    pub submitter: String,
+
+    /**
+     * Why the songs are considered duplicates
+     * */
+    pub reason: DuplicateReason,
+}
+
+/**
+ * Maximum offset, in sub-fingerprints, that two fingerprints are shifted against each other when
+ * comparing them. Each sub-fingerprint covers roughly 0.124 seconds, so this allows for about 10
+ * seconds of difference in intros.
+ * */
+const MAX_ALIGNMENT_OFFSET: usize = 80;
+
+/**
+ * Minimum number of overlapping sub-fingerprints required to compare two fingerprints
+ * */
+const MIN_OVERLAP: usize = 40;
+
+/**
+ * Checks whether two songs are the same, using whatever identifying information is available on
+ * both of them
+ * */
+pub fn compare_songs(a: &Song, b: &Song, similarity_threshold: f64) -> Option<DuplicateReason> {
+    if a.origin == b.origin {
+        return Some(DuplicateReason::SameOrigin);
+    }
+
+    if let (Some(ma), Some(mb)) = (&a.metadata, &b.metadata) {
+        if let (Some(ra), Some(rb)) = (&ma.recording_mbid, &mb.recording_mbid) {
+            if ra == rb {
+                return Some(DuplicateReason::SameRecording(ra.clone()));
+            }
+        }
+        if let (Some(aa), Some(ab)) = (&ma.acoustid_id, &mb.acoustid_id) {
+            if aa == ab {
+                return Some(DuplicateReason::SameAcoustId(aa.clone()));
+            }
+        }
+    }
+
+    if let (Some(fa), Some(fb)) = (&a.fingerprint, &b.fingerprint) {
+        let similarity = fingerprint_similarity(&fa.raw, &fb.raw);
+        if similarity >= similarity_threshold {
+            return Some(DuplicateReason::SimilarFingerprint(similarity));
+        }
+    }
+
+    None
+}
+
+/**
+ * Compares two raw fingerprints, returning the fraction of matching bits at the best alignment
+ * from 0.0 - 1.0. Unrelated audio scores around 0.5.
+ * */
+pub fn fingerprint_similarity(a: &[u32], b: &[u32]) -> f64 {
+    let mut best = 0.0;
+    for offset in 0..=MAX_ALIGNMENT_OFFSET {
+        for (x, y) in [(a, b), (b, a)] {
+            if offset >= x.len() {
+                continue;
+            }
+            let x = &x[offset..];
+            let overlap = x.len().min(y.len());
+            if overlap < MIN_OVERLAP {
+                continue;
+            }
+            let errors: u32 = x
+                .iter()
+                .zip(y.iter())
+                .map(|(p, q)| (p ^ q).count_ones())
+                .sum();
+            let similarity = 1.0 - errors as f64 / (overlap as f64 * 32.0);
+            if similarity > best {
+                best = similarity;
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprint::chromaprint::FingerprintData;
+    use crate::fingerprint::SongMetadata;
+    use crate::types::SongOrigin;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn fingerprint(seed: u64, len: usize) -> Vec<u32> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    fn song(path: &str) -> Song {
+        Song::new(SongOrigin::FileUpload(path.to_string()), String::new())
+    }
+
+    fn with_metadata(path: &str, recording: Option<&str>, acoustid: Option<&str>) -> Song {
+        let mut song = song(path);
+        song.metadata = Some(SongMetadata {
+            recording_mbid: recording.map(str::to_string),
+            acoustid_id: acoustid.map(str::to_string),
+            ..SongMetadata::default()
+        });
+        song
+    }
+
+    fn with_fingerprint(path: &str, raw: Vec<u32>) -> Song {
+        let mut song = song(path);
+        song.fingerprint = Some(FingerprintData {
+            duration: 0.0,
+            fp: String::new(),
+            raw,
+        });
+        song
+    }
+
+    #[test]
+    fn fingerprints_are_compared_at_the_best_alignment() {
+        let a = fingerprint(1, 200);
+        assert_eq!(fingerprint_similarity(&a, &a), 1.0);
+        // A longer intro on one copy
+        let mut intro = fingerprint(2, 30);
+        intro.extend(&a);
+        assert_eq!(fingerprint_similarity(&a, &intro), 1.0);
+        assert_eq!(fingerprint_similarity(&intro, &a), 1.0);
+
+        // A few differing bits barely matter
+        let noisy = a.iter().map(|x| x ^ 1).collect::<Vec<_>>();
+        assert!((fingerprint_similarity(&a, &noisy) - 31.0 / 32.0).abs() < 1e-9);
+
+        let unrelated = fingerprint_similarity(&a, &fingerprint(3, 200));
+        assert!(unrelated < 0.6, "unrelated fingerprints scored {}", unrelated);
+        // Too little overlap to say anything
+        assert_eq!(fingerprint_similarity(&a[..MIN_OVERLAP - 1], &a), 0.0);
+    }
+
+    #[test]
+    fn songs_are_compared_by_what_is_known_about_them() {
+        let threshold = DuplicateConfig::default().similarity_threshold;
+        let raw = fingerprint(1, 200);
+        let cases = [
+            (song("/a.flac"), song("/a.flac"), Some(DuplicateReason::SameOrigin)),
+            (song("/a.flac"), song("/b.flac"), None),
+            (
+                with_metadata("/a.flac", Some("rec"), None),
+                with_metadata("/b.flac", Some("rec"), None),
+                Some(DuplicateReason::SameRecording("rec".to_string())),
+            ),
+            (
+                with_metadata("/a.flac", Some("rec"), Some("acoustid")),
+                with_metadata("/b.flac", Some("other"), Some("acoustid")),
+                Some(DuplicateReason::SameAcoustId("acoustid".to_string())),
+            ),
+            (
+                with_metadata("/a.flac", Some("rec"), None),
+                with_metadata("/b.flac", None, None),
+                None,
+            ),
+            (
+                with_fingerprint("/a.flac", raw.clone()),
+                with_fingerprint("/b.flac", raw.clone()),
+                Some(DuplicateReason::SimilarFingerprint(1.0)),
+            ),
+            (
+                with_fingerprint("/a.flac", raw),
+                with_fingerprint("/b.flac", fingerprint(2, 200)),
+                None,
+            ),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(
+                compare_songs(&a, &b, threshold),
+                expected,
+                "{:?} and {:?}",
+                a.origin,
+                b.origin
+            );
+        }
+    }
+}
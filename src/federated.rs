@@ -0,0 +1,523 @@
+use log::{log, Level};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task;
+use tokio::time::timeout;
+
+use crate::dedup::compare_songs;
+use crate::library::Library;
+use crate::types::{GlobalQueue, Song};
+
+/**
+ * Where a search result was found
+ * */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SearchSource {
+    Youtube,
+    Library,
+    History,
+}
+
+impl SearchSource {
+    /**
+     * Name of the source to show alongside results
+     * */
+    pub fn label(&self) -> &'static str {
+        match self {
+            SearchSource::Youtube => "YouTube",
+            SearchSource::Library => "Library",
+            SearchSource::History => "Recently played",
+        }
+    }
+}
+
+/**
+ * How searches across every source are run and ranked
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchConfig {
+    /**
+     * Maximum number of results taken from each source
+     * */
+    pub per_source: usize,
+
+    /**
+     * Maximum number of results returned after merging
+     * */
+    pub limit: usize,
+
+    /**
+     * How long to wait for YouTube results before giving up on them
+     * */
+    pub youtube_timeout: Duration,
+
+    /**
+     * How long to wait for library results before giving up on them
+     * */
+    pub library_timeout: Duration,
+
+    /**
+     * How much results from each source count towards the ranking. Local results are favoured
+     * since they play straight away.
+     * */
+    pub youtube_weight: f64,
+    pub library_weight: f64,
+    pub history_weight: f64,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        SearchConfig {
+            per_source: 10,
+            limit: 20,
+            youtube_timeout: Duration::from_secs(10),
+            library_timeout: Duration::from_secs(2),
+            youtube_weight: 1.0,
+            library_weight: 1.3,
+            history_weight: 1.1,
+        }
+    }
+}
+
+impl SearchConfig {
+    fn weight(&self, source: SearchSource) -> f64 {
+        match source {
+            SearchSource::Youtube => self.youtube_weight,
+            SearchSource::Library => self.library_weight,
+            SearchSource::History => self.history_weight,
+        }
+    }
+}
+
+/**
+ * A song found by a search
+ * */
+#[derive(Clone)]
+pub struct SearchResult {
+    /**
+     * The song, with no submitter set. When the same song was found in several places, this is
+     * the copy from the most preferred source: the library, then history, then YouTube.
+     * */
+    pub song: Song,
+
+    /**
+     * Every source the song was found in, most preferred first
+     * */
+    pub sources: Vec<SearchSource>,
+
+    /**
+     * Ranking score; higher is better
+     * */
+    pub score: f64,
+}
+
+/**
+ * Merged results of a search across every source
+ * */
+#[derive(Clone, Default)]
+pub struct SearchResults {
+    /**
+     * Results, best first
+     * */
+    pub results: Vec<SearchResult>,
+
+    /**
+     * Sources that failed or didn't answer in time, so are missing from the results
+     * */
+    pub incomplete: Vec<SearchSource>,
+}
+
+/**
+ * Offset added to each result's rank before scoring, which stops the first few results of a
+ * source from completely outweighing the rest
+ * */
+const RANK_OFFSET: f64 = 10.0;
+
+impl GlobalQueue {
+    /**
+     * Searches YouTube, the local library and play history for {query} at the same time, merging
+     * the results into one ranked list. Songs found in more than one place, judged by origin,
+     * recording MBID or fingerprint, are listed once. YouTube and the library each have their own
+     * timeout, so a slow source only leaves its results out. History is searched in memory as
+     * soon as this is called, so it is always complete.
+     *
+     * The returned future doesn't borrow the queue, so any lock held on it can be released
+     * before awaiting the results.
+     * */
+    pub fn search(&self, query: &str) -> impl Future<Output = SearchResults> + Send + 'static {
+        let query = query.trim().to_string();
+        let config = self.search.clone();
+        let threshold = self.duplicates.similarity_threshold;
+        let library = self.library.clone();
+        let history = self.history_matches(&query, config.per_source);
+
+        async move {
+            if query.is_empty() {
+                return SearchResults::default();
+            }
+
+            let results = search_sources(
+                youtube_search(&query, &config),
+                library_search(&query, library, &config),
+                history,
+                &config,
+                threshold,
+            )
+            .await;
+            log!(
+                Level::Debug,
+                "search for {} found {} results (incomplete: {:?})",
+                query,
+                results.results.len(),
+                results.incomplete
+            );
+            results
+        }
+    }
+
+    /**
+     * Songs from history matching every word of {query} in their title, artist or album, most
+     * played first. Each song is only listed once.
+     * */
+    fn history_matches(&self, query: &str, limit: usize) -> Vec<Song> {
+        let words = query
+            .to_lowercase()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if words.is_empty() {
+            return vec![];
+        }
+
+        let mut matches: Vec<(Song, usize)> = vec![];
+        for entry in self.history.iter().rev() {
+            if let Some((_, plays)) = matches
+                .iter_mut()
+                .find(|(song, _)| song.origin == entry.song.origin)
+            {
+                *plays += 1;
+                continue;
+            }
+            let text = searchable_text(&entry.song);
+            if words.iter().all(|word| text.contains(word.as_str())) {
+                let mut song = Song::new(entry.song.origin.clone(), String::new());
+                song.metadata = entry.song.metadata.clone();
+                song.fingerprint = entry.song.fingerprint.clone();
+                song.source_info = entry.song.source_info.clone();
+                song.resolved = entry.song.resolved.clone();
+                matches.push((song, 1));
+            }
+        }
+
+        // Stable, so songs played equally often stay most recent first
+        matches.sort_by_key(|(_, plays)| std::cmp::Reverse(*plays));
+        matches
+            .into_iter()
+            .take(limit)
+            .map(|(song, _)| song)
+            .collect()
+    }
+}
+
+/**
+ * Waits for the YouTube and library searches, each within its own timeout, and merges their
+ * results with those from {history}
+ * */
+async fn search_sources<Y, L>(
+    youtube: Y,
+    library: L,
+    history: Vec<Song>,
+    config: &SearchConfig,
+    threshold: f64,
+) -> SearchResults
+where
+    Y: Future<Output = Option<Vec<Song>>>,
+    L: Future<Output = Option<Vec<Song>>>,
+{
+    let (youtube, library) = tokio::join!(
+        run_source(SearchSource::Youtube, config.youtube_timeout, youtube),
+        run_source(SearchSource::Library, config.library_timeout, library),
+    );
+
+    let mut results = SearchResults::default();
+    // Merged in order of preference, so the copy of a song that is kept is the best one
+    for (source, found) in [
+        (SearchSource::Library, library),
+        (SearchSource::History, Some(history)),
+        (SearchSource::Youtube, youtube),
+    ] {
+        match found {
+            Some(songs) => merge(&mut results.results, source, songs, config, threshold),
+            None => results.incomplete.push(source),
+        }
+    }
+
+    results
+        .results
+        .sort_by(|a, b| b.score.total_cmp(&a.score));
+    results.results.truncate(config.limit);
+    results
+}
+
+/**
+ * Runs one source's search within its timeout. None if it failed or took too long.
+ * */
+async fn run_source<F: Future<Output = Option<Vec<Song>>>>(
+    source: SearchSource,
+    limit: Duration,
+    search: F,
+) -> Option<Vec<Song>> {
+    match timeout(limit, search).await {
+        Ok(found) => found,
+        Err(_) => {
+            log!(Level::Warn, "{} search timed out", source.label());
+            None
+        }
+    }
+}
+
+/**
+ * Searches YouTube. yt-dlp is killed if the search times out.
+ * */
+async fn youtube_search(query: &str, config: &SearchConfig) -> Option<Vec<Song>> {
+    match crate::search_async(query, config.per_source).await {
+        Ok(songs) => Some(songs),
+        Err(e) => {
+            log!(Level::Warn, "YouTube search failed: {}", e);
+            None
+        }
+    }
+}
+
+async fn library_search(
+    query: &str,
+    library: Option<Arc<Library>>,
+    config: &SearchConfig,
+) -> Option<Vec<Song>> {
+    // No library configured, so there is nothing missing from the results
+    let library = match library {
+        Some(library) => library,
+        None => return Some(vec![]),
+    };
+    let (query, count) = (query.to_string(), config.per_source);
+    match task::spawn_blocking(move || library.search(&query, count)).await {
+        Ok(Ok(tracks)) => Some(tracks.iter().map(|track| track.to_song("")).collect()),
+        Ok(Err(e)) => {
+            log!(Level::Warn, "library search failed: {}", e);
+            None
+        }
+        Err(e) => {
+            log!(Level::Warn, "library search panicked: {}", e);
+            None
+        }
+    }
+}
+
+/**
+ * Adds one source's results, in its own order of relevance, to the merged results. Songs already
+ * present gain the source and its score instead of being listed again.
+ * */
+fn merge(
+    results: &mut Vec<SearchResult>,
+    source: SearchSource,
+    songs: Vec<Song>,
+    config: &SearchConfig,
+    threshold: f64,
+) {
+    let mut merged = HashSet::new();
+    for (rank, song) in songs.into_iter().enumerate() {
+        let score = config.weight(source) / (RANK_OFFSET + rank as f64);
+        let existing = results
+            .iter()
+            .position(|result| compare_songs(&result.song, &song, threshold).is_some());
+        match existing {
+            // A source can list the same song twice, which shouldn't count twice
+            Some(i) if merged.contains(&i) => {}
+            Some(i) => {
+                let result = &mut results[i];
+                result.score += score;
+                if !result.sources.contains(&source) {
+                    result.sources.push(source);
+                }
+                if result.song.metadata.is_none() {
+                    result.song.metadata = song.metadata;
+                }
+                if result.song.fingerprint.is_none() {
+                    result.song.fingerprint = song.fingerprint;
+                }
+                merged.insert(i);
+            }
+            None => {
+                merged.insert(results.len());
+                results.push(SearchResult {
+                    song,
+                    sources: vec![source],
+                    score,
+                });
+            }
+        }
+    }
+}
+
+/**
+ * Lowercase text a song can be found by in history
+ * */
+fn searchable_text(song: &Song) -> String {
+    let mut text = vec![];
+    if let Some(meta) = &song.metadata {
+        text.extend([meta.title.as_str(), meta.artist.as_str(), meta.album.as_str()]);
+    }
+    if let Some(info) = &song.source_info {
+        text.extend(info.title.as_deref());
+        text.extend(info.channel.as_deref());
+    }
+    text.join(" ").to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprint::SongMetadata;
+    use crate::types::{HistoryEntry, SongOrigin};
+    use std::future::{pending, ready};
+    use std::time::{Instant, SystemTime};
+
+    fn song(origin: SongOrigin, title: &str, artist: &str) -> Song {
+        let mut song = Song::new(origin, String::new());
+        song.metadata = Some(SongMetadata {
+            title: title.to_string(),
+            artist: artist.to_string(),
+            ..SongMetadata::default()
+        });
+        song
+    }
+
+    fn file(path: &str) -> SongOrigin {
+        SongOrigin::FileUpload(path.to_string())
+    }
+
+    fn played(queue: &mut GlobalQueue, song: Song) {
+        queue.history.push_back(HistoryEntry {
+            song,
+            played_at: SystemTime::now(),
+            finished: true,
+            skipped: false,
+        });
+    }
+
+    fn origins(results: &SearchResults) -> Vec<(SongOrigin, Vec<SearchSource>)> {
+        results
+            .results
+            .iter()
+            .map(|result| (result.song.origin.clone(), result.sources.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn history_matches_every_word_once_per_song() {
+        let mut queue = GlobalQueue::new();
+        played(&mut queue, song(file("/music/a.flac"), "One More Time", "Daft Punk"));
+        played(&mut queue, song(file("/music/b.flac"), "Around the World", "Daft Punk"));
+        played(&mut queue, song(file("/music/b.flac"), "Around the World", "Daft Punk"));
+        played(&mut queue, song(file("/music/c.flac"), "One", "Metallica"));
+
+        let matches = |query| {
+            queue
+                .history_matches(query, 10)
+                .into_iter()
+                .map(|song| song.origin)
+                .collect::<Vec<_>>()
+        };
+        // Most played first, then most recent
+        assert_eq!(matches("daft"), [file("/music/b.flac"), file("/music/a.flac")]);
+        assert_eq!(matches("ONE"), [file("/music/c.flac"), file("/music/a.flac")]);
+        assert_eq!(matches("one punk"), [file("/music/a.flac")]);
+        assert!(matches("one nobody").is_empty());
+        assert!(matches(" ").is_empty());
+    }
+
+    #[test]
+    fn songs_in_several_sources_are_merged() {
+        let config = SearchConfig::default();
+        let mut results = vec![];
+        let library = vec![
+            song(file("/music/a.flac"), "One More Time", "Daft Punk"),
+            song(file("/music/b.flac"), "Aerodynamic", "Daft Punk"),
+        ];
+        let mut history = vec![song(file("/music/a.flac"), "One More Time", "Daft Punk")];
+        // A history entry without metadata doesn't replace the library's
+        history[0].metadata = None;
+
+        merge(&mut results, SearchSource::Library, library, &config, 0.9);
+        merge(&mut results, SearchSource::History, history, &config, 0.9);
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].song.origin, file("/music/a.flac"));
+        assert_eq!(
+            results[0].sources,
+            [SearchSource::Library, SearchSource::History]
+        );
+        assert!(results[0].song.metadata.is_some());
+        assert!(results[0].score > results[1].score);
+        assert_eq!(results[1].sources, [SearchSource::Library]);
+
+        // A source listing a song twice only counts it once
+        let score = results[1].score;
+        let twice = vec![
+            song(file("/music/b.flac"), "Aerodynamic", "Daft Punk"),
+            song(file("/music/b.flac"), "Aerodynamic", "Daft Punk"),
+        ];
+        merge(&mut results, SearchSource::Youtube, twice, &config, 0.9);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].score, score + config.youtube_weight / RANK_OFFSET);
+    }
+
+    #[tokio::test]
+    async fn slow_sources_are_left_out() {
+        let mut queue = GlobalQueue::new();
+        played(&mut queue, song(file("/music/a.flac"), "One More Time", "Daft Punk"));
+        played(&mut queue, song(file("/music/c.flac"), "One", "Metallica"));
+        let history = queue.history_matches("one", 10);
+        let library = vec![
+            song(file("/music/a.flac"), "One More Time", "Daft Punk"),
+            song(file("/music/d.flac"), "One Vision", "Queen"),
+        ];
+        let config = SearchConfig {
+            youtube_timeout: Duration::from_millis(50),
+            ..SearchConfig::default()
+        };
+
+        let started = Instant::now();
+        let results = search_sources(pending(), ready(Some(library)), history, &config, 0.9).await;
+        assert!(started.elapsed() < Duration::from_secs(5), "waited for the slow source");
+
+        assert_eq!(results.incomplete, [SearchSource::Youtube]);
+        // The song found twice ranks first, and library results are favoured over history
+        assert_eq!(
+            origins(&results),
+            [
+                (
+                    file("/music/a.flac"),
+                    vec![SearchSource::Library, SearchSource::History]
+                ),
+                (file("/music/d.flac"), vec![SearchSource::Library]),
+                (file("/music/c.flac"), vec![SearchSource::History]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_sources_are_incomplete() {
+        let config = SearchConfig::default();
+        let youtube = ready(Some(vec![song(file("/music/a.flac"), "One", "Metallica")]));
+        let results = search_sources(youtube, ready(None), vec![], &config, 0.9).await;
+        assert_eq!(results.incomplete, [SearchSource::Library]);
+        assert_eq!(
+            origins(&results),
+            [(file("/music/a.flac"), vec![SearchSource::Youtube])]
+        );
+    }
+}
@@ -108,13 +108,17 @@ pub struct Artist {
      * Artist name
      * */
     pub name: String,
+    /**
+     * Phrase joining this artist to the next one on the recording, if any
+     * */
+    pub joinphrase: Option<String>,
 }
 
 pub async fn lookup_by_fingerprint(fp: &FingerprintData) -> Result<AcoustIDResponse, Error> {
     let res = CLIENT
         .get(format!(
             "{}?meta=recordings+releasegroups+compress",
-            ACOUSTID_API_STRING.to_string()
+            *ACOUSTID_API_STRING
         ))
         .query(&[
             ("format", "json"),
@@ -1,33 +1,265 @@
-use anyhow::Error;
+use anyhow::{anyhow, Error};
+use base64::Engine;
+use rodio::Source;
+use rusty_chromaprint::{Configuration, Fingerprinter};
 use serde::{Deserialize, Serialize};
-use std::process::Command;
+use std::time::Duration;
+
+use crate::decode::open_file;
+
+/**
+ * Length of audio used to calculate a fingerprint. Matches the default of the fpcalc utility so
+ * fingerprints are interchangeable with ones calculated by fpcalc.
+ * */
+pub const FINGERPRINT_LENGTH: Duration = Duration::from_secs(120);
+
+/**
+ * Chromaprint algorithm identifier of the TEST2 preset, written into the compressed fingerprint
+ * header
+ * */
+const ALGORITHM_TEST2: u8 = 1;
 
 /**
- * Data output by the fpcalc utility including file length and fingerprint string
+ * Fingerprint of a piece of audio including its length and the compressed fingerprint string,
+ * in the same format output by the fpcalc utility
  * */
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct FingerprintData {
     pub duration: f64,
     #[serde(rename = "fingerprint")]
     pub fp: String,
+    /**
+     * Uncompressed sub-fingerprints, used to compare fingerprints locally. Empty for
+     * fingerprints deserialized from fpcalc output.
+     * */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub raw: Vec<u32>,
 }
 
 /**
- * Calculates the fingerprint of an audio file using fpcalc and the underlying chromaprint
- * library. This fingerprint can be used to lookup song information using the acoustID API
- * and musicbrainz API.
+ * Calculates the fingerprint of an audio file using the underlying chromaprint algorithm. This
+ * fingerprint can be used to lookup song information using the acoustID API and musicbrainz
+ * API.
  * */
 pub fn calculate_fingerprint(filepath: &str) -> Result<FingerprintData, Error> {
-    Ok(serde_json::from_str(
-        String::from_utf8_lossy(
-            Command::new("fpcalc")
-                .arg("-json") // not --json
-                .arg(filepath)
-                .output()?
-                .stdout
-                .as_slice(),
-        )
-        .to_string()
-        .as_str(),
-    )?)
+    fingerprint_source(open_file(filepath)?)
+}
+
+/**
+ * Calculates the fingerprint of the first {length} of an audio file. The file does not need to
+ * be complete, so this can be used on a file that is still being downloaded. If the full length
+ * of the audio is known it should be passed as {total_duration}, since AcoustID lookups match
+ * on the duration of the whole song.
+ * */
+pub fn calculate_fingerprint_partial(
+    filepath: &str,
+    length: Duration,
+    total_duration: Option<Duration>,
+) -> Result<FingerprintData, Error> {
+    fingerprint_partial(open_file(filepath)?, length, total_duration)
+}
+
+/**
+ * Calculates the fingerprint of already decoded audio. Only the first FINGERPRINT_LENGTH of
+ * audio is fingerprinted, but the whole source is consumed to determine its duration.
+ * */
+pub fn fingerprint_source<S: Source<Item = f32>>(source: S) -> Result<FingerprintData, Error> {
+    fingerprint_samples(source, FINGERPRINT_LENGTH, true)
+}
+
+/**
+ * Calculates the fingerprint of the first {length} of decoded audio, without consuming the rest
+ * of the source. Works on endless sources such as streams. The reported duration is taken from
+ * {total_duration}, then the source's own total duration, and finally the length of audio that
+ * was fingerprinted.
+ * */
+pub fn fingerprint_partial<S: Source<Item = f32>>(
+    source: S,
+    length: Duration,
+    total_duration: Option<Duration>,
+) -> Result<FingerprintData, Error> {
+    let total_duration = total_duration.or_else(|| source.total_duration());
+    let mut fp = fingerprint_samples(source, length.min(FINGERPRINT_LENGTH), false)?;
+    if let Some(total) = total_duration {
+        fp.duration = total.as_secs_f64();
+    }
+    Ok(fp)
+}
+
+/**
+ * Feeds up to {length} of audio into the fingerprinter. If {consume_rest} is set the rest of the
+ * source is read (but not fingerprinted) to determine the full duration.
+ * */
+fn fingerprint_samples<S: Source<Item = f32>>(
+    mut source: S,
+    length: Duration,
+    consume_rest: bool,
+) -> Result<FingerprintData, Error> {
+    let sample_rate = source.sample_rate();
+    let channels = source.channels();
+
+    let mut printer = Fingerprinter::new(&Configuration::preset_test2());
+    printer
+        .start(sample_rate, channels as u32)
+        .map_err(|e| anyhow!("Could not start fingerprinter: {:?}", e))?;
+
+    let limit = (length.as_secs_f64() * sample_rate as f64) as usize * channels as usize;
+    let mut total: usize = 0;
+    let mut buf: Vec<i16> = Vec::with_capacity(4096);
+
+    for sample in source.by_ref().take(limit) {
+        // The inverse of how decoders convert 16 bit samples, so decoded audio is fingerprinted
+        // exactly as fpcalc would
+        buf.push((sample * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+        if buf.len() == buf.capacity() {
+            printer.consume(&buf);
+            buf.clear();
+        }
+        total += 1;
+    }
+    printer.consume(&buf);
+    printer.finish();
+
+    if consume_rest {
+        total += source.count();
+    }
+
+    if total == 0 {
+        return Err(anyhow!("Cannot fingerprint empty audio"));
+    }
+
+    let mut raw = printer.fingerprint().to_vec();
+    // rusty_chromaprint pads the audio left over at the end out to one more frame, which
+    // chromaprint itself (and so fpcalc) leaves out. The extra frame only adds the last
+    // sub-fingerprint.
+    raw.pop();
+    Ok(FingerprintData {
+        duration: total as f64 / (sample_rate as f64 * channels as f64),
+        fp: encode_fingerprint(&raw),
+        raw,
+    })
+}
+
+/**
+ * Compresses and base64 encodes a raw fingerprint in the format expected by the AcoustID API.
+ * Port of chromaprint's FingerprintCompressor.
+ * */
+pub fn encode_fingerprint(raw: &[u32]) -> String {
+    // Each sub-fingerprint is xor'd with the previous one and stored as the distances between
+    // its set bits, terminated with a 0.
+    let mut bits: Vec<u8> = vec![];
+    let mut last = 0u32;
+    for &sub in raw {
+        let mut x = sub ^ last;
+        last = sub;
+        let (mut bit, mut last_bit) = (1u8, 0u8);
+        while x != 0 {
+            if x & 1 != 0 {
+                bits.push(bit - last_bit);
+                last_bit = bit;
+            }
+            x >>= 1;
+            bit += 1;
+        }
+        bits.push(0);
+    }
+
+    let size = raw.len();
+    let mut out = vec![
+        ALGORITHM_TEST2,
+        (size >> 16) as u8,
+        (size >> 8) as u8,
+        size as u8,
+    ];
+
+    // Distances of 7 and up are stored as 7 in the normal bits, with the remainder stored in the
+    // exception bits afterwards.
+    out.extend(pack_bits(bits.iter().map(|&b| b.min(7)), 3));
+    out.extend(pack_bits(bits.iter().filter(|&&b| b >= 7).map(|&b| b - 7), 5));
+
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(out)
+}
+
+/**
+ * Packs values of {width} bits each into bytes, least significant bit first
+ * */
+fn pack_bits<I: Iterator<Item = u8>>(values: I, width: u32) -> Vec<u8> {
+    let mut out = vec![];
+    let mut acc: u32 = 0;
+    let mut len: u32 = 0;
+    for v in values {
+        acc |= (v as u32 & ((1 << width) - 1)) << len;
+        len += width;
+        while len >= 8 {
+            out.push(acc as u8);
+            acc >>= 8;
+            len -= 8;
+        }
+    }
+    if len > 0 {
+        out.push(acc as u8);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fingerprint");
+
+    /**
+     * Reads the FINGERPRINT value from fpcalc's output
+     * */
+    fn fpcalc_output(name: &str) -> String {
+        let output = std::fs::read_to_string(format!("{FIXTURES}/{name}")).unwrap();
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix("FINGERPRINT="))
+            .unwrap()
+            .to_string()
+    }
+
+    fn expected_raw() -> Vec<u32> {
+        fpcalc_output("clip.raw.txt")
+            .split(',')
+            .map(|sub| sub.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn matches_reference_fingerprint() {
+        let fp = calculate_fingerprint(&format!("{FIXTURES}/clip.wav")).unwrap();
+        assert_eq!(fp.raw, expected_raw());
+        assert_eq!(fp.fp, fpcalc_output("clip.txt"));
+        assert_eq!(fp.duration, 15.0);
+    }
+
+    #[test]
+    fn compresses_like_reference() {
+        assert_eq!(encode_fingerprint(&expected_raw()), fpcalc_output("clip.txt"));
+    }
+
+    /**
+     * Cases from chromaprint's own compressor tests, which use algorithm 0
+     * */
+    #[test]
+    fn compresses_reference_cases() {
+        let cases: [(&[u32], &[u8]); 6] = [
+            (&[1], &[0, 0, 0, 1, 1]),
+            (&[7], &[0, 0, 0, 1, 73, 0]),
+            (&[1 << 6], &[0, 0, 0, 1, 7, 0]),
+            (&[1 << 8], &[0, 0, 0, 1, 7, 2]),
+            (&[1, 0], &[0, 0, 0, 2, 65, 0]),
+            (&[1, 1], &[0, 0, 0, 2, 1, 0]),
+        ];
+        for (raw, expected) in cases {
+            let mut expected = expected.to_vec();
+            expected[0] = ALGORITHM_TEST2;
+            let encoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(encode_fingerprint(raw))
+                .unwrap();
+            assert_eq!(encoded, expected, "{raw:?}");
+        }
+    }
 }
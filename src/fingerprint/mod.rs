@@ -1,11 +1,14 @@
 use anyhow::{anyhow, Error};
 use lazy_static::lazy_static;
 use log::{log, Level};
-use musicbrainz_rs::entity::{recording::Recording, release_group::{ReleaseGroup, ReleaseGroupPrimaryType}};
+use chrono::Datelike;
+use musicbrainz_rs::entity::{recording::Recording, release::Release, release_group::ReleaseGroupPrimaryType};
 use musicbrainz_rs::prelude::*;
 use reqwest::Client;
 use serde::{Serialize, Deserialize};
 
+use chromaprint::FingerprintData;
+
 /**
  * Internal module for using chromaprint to generate fingerprints from audio files
  * */
@@ -16,12 +19,129 @@ pub mod chromaprint;
  * */
 pub mod acoustid;
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SongMetadata {
     pub title: String,
+    /**
+     * Display string for all credited artists, including join phrases
+     * (e.g. "Artist A feat. Artist B")
+     * */
     pub artist: String,
+    /**
+     * Structured artist credits in credited order. Empty if no artist was found.
+     * */
+    pub artists: Vec<ArtistCredit>,
     pub album: String,
     pub album_art: Option<String>,
     pub duration: f64,
+    /**
+     * MusicBrainz ID of the matched recording
+     * */
+    pub recording_mbid: Option<String>,
+    /**
+     * MusicBrainz ID of the release the album title and track number were taken from
+     * */
+    pub release_mbid: Option<String>,
+    /**
+     * MusicBrainz ID of the release group containing the release
+     * */
+    pub release_group_mbid: Option<String>,
+    /**
+     * AcoustID track ID the fingerprint matched
+     * */
+    pub acoustid_id: Option<String>,
+    /**
+     * Year the release was published
+     * */
+    pub release_year: Option<i32>,
+    /**
+     * Position of the track on its medium
+     * */
+    pub track_number: Option<u32>,
+    /**
+     * Position of the medium (disc) within the release
+     * */
+    pub disc_number: Option<u32>,
+    /**
+     * MusicBrainz genres of the recording, falling back to user submitted tags
+     * if no genres are present
+     * */
+    pub genres: Vec<String>,
+    /**
+     * International Standard Recording Codes associated with the recording
+     * */
+    pub isrcs: Vec<String>,
+    /**
+     * AcoustID score of the fingerprint match from 0.0 - 1.0
+     * */
+    pub confidence: f64,
+}
+
+impl Default for SongMetadata {
+    fn default() -> Self {
+        SongMetadata {
+            title: String::from("Not Found"),
+            artist: String::from("Not Found"),
+            artists: vec![],
+            album: String::from("Not Found"),
+            album_art: None,
+            duration: 0.0,
+            recording_mbid: None,
+            release_mbid: None,
+            release_group_mbid: None,
+            acoustid_id: None,
+            release_year: None,
+            track_number: None,
+            disc_number: None,
+            genres: vec![],
+            isrcs: vec![],
+            confidence: 0.0,
+        }
+    }
+}
+
+impl SongMetadata {
+    /**
+     * MusicBrainz IDs of all credited artists, in credited order
+     * */
+    pub fn artist_mbids(&self) -> Vec<&str> {
+        self.artists
+            .iter()
+            .filter_map(|a| a.mbid.as_deref())
+            .collect()
+    }
+}
+
+/**
+ * A single credited artist on a recording
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ArtistCredit {
+    /**
+     * Name the artist is credited as on this recording, which may differ from
+     * the artist's canonical name
+     * */
+    pub name: String,
+    /**
+     * MusicBrainz ID of the artist, if known
+     * */
+    pub mbid: Option<String>,
+    /**
+     * Phrase joining this credit to the next one (e.g. " feat. ", " & ").
+     * Empty for the last credit.
+     * */
+    pub join_phrase: String,
+}
+
+/**
+ * Formats a list of artist credits into a single display string by
+ * concatenating each credited name with its join phrase
+ * */
+pub fn format_artist_credits(credits: &[ArtistCredit]) -> String {
+    credits
+        .iter()
+        .map(|c| format!("{}{}", c.name, c.join_phrase))
+        .collect::<String>()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -59,30 +179,46 @@ lazy_static!(
     static ref CLIENT: Client = Client::new();
             );
 
+/**
+ * Ranks a release by the primary type of its release group, preferring albums, then EPs, then
+ * singles. Lower is better.
+ * */
+fn release_priority(rel: &Release) -> u8 {
+    match rel.release_group.as_ref().and_then(|rg| rg.primary_type.as_ref()) {
+        Some(ReleaseGroupPrimaryType::Album) => 0,
+        Some(ReleaseGroupPrimaryType::Ep) => 1,
+        Some(ReleaseGroupPrimaryType::Single) => 2,
+        _ => 3,
+    }
+}
+
 /**
  * Spawn a thread for this bitch cause there's a lot of blocking requests in here
  * */
 pub async fn lookup_song(path: &str) -> Result<SongMetadata, Error> {
-    let mut out = SongMetadata {
-        title:String::from("Not Found"),
-        artist:String::from("Not Found"),
-        album:String::from("Not Found"),
-        album_art: None,
-        duration: 0.0,
-    };
-
     let fp = chromaprint::calculate_fingerprint(path)?;
-    out.duration = fp.duration;
 
     log!(Level::Trace, "Audio fingerprint for {}: {}", path, fp.fp);
 
-    let aid_result = acoustid::lookup_by_fingerprint(&fp).await?;
+    lookup_fingerprint(&fp).await
+}
+
+/**
+ * Looks up song metadata from an already calculated fingerprint, which may have been taken from
+ * a partial download or a stream
+ * */
+pub async fn lookup_fingerprint(fp: &FingerprintData) -> Result<SongMetadata, Error> {
+    let mut out = SongMetadata {
+        duration: fp.duration,
+        ..Default::default()
+    };
+
+    let aid_result = acoustid::lookup_by_fingerprint(fp).await?;
 
     log!(
         Level::Debug,
-        "found {} results for path lookup {}",
+        "found {} results for fingerprint lookup",
         aid_result.results.len(),
-        path
     );
 
     log!(Level::Trace, "response from AcoustID API: {:?}", aid_result);
@@ -105,17 +241,23 @@ pub async fn lookup_song(path: &str) -> Result<SongMetadata, Error> {
 
     log!(Level::Debug, "found best result with acoustID {} and score {}", best_result.id, best_result.score);
 
+    out.acoustid_id = Some(best_result.id.clone());
+    out.confidence = best_result.score;
+
     match best_result.recordings {
         Some(recs) => {
             log!(Level::Debug, "found {} recordings from acoustID {}", recs.len(), best_result.id);
             recs.iter().for_each(|e| log!(Level::Trace, "id: {} | aid: {}", e.id, best_result.id));
             
-            let r = recs.get(0).ok_or_else(|| anyhow!("AcoustID Result contained no recordings"))?;
+            let r = recs.first().ok_or_else(|| anyhow!("AcoustID Result contained no recordings"))?;
 
             let rec: Recording = Recording::fetch()
                 .id(r.id.as_str())
                 .with_artists()
                 .with_releases()
+                .with_tags()
+                .with_genres()
+                .with_isrcs()
                 .execute()
                 .await?;
 
@@ -124,69 +266,111 @@ pub async fn lookup_song(path: &str) -> Result<SongMetadata, Error> {
             // Man this finding out metadata shit is easy
             out.title = rec.title;
             
-            let mut album_mbid: Option<String> = None;
+            out.recording_mbid = Some(rec.id.clone());
+            out.isrcs = rec.isrcs.unwrap_or_default();
+            out.genres = match rec.genres {
+                Some(genres) if !genres.is_empty() => genres.into_iter().map(|g| g.name).collect(),
+                _ => rec
+                    .tags
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|t| t.name)
+                    .collect(),
+            };
 
             // and then you get to the album title
-            // there's gotta be a way to make this shit more concise
-            // in short: find all releases of a song, map to release groups, and find the title of
-            // that group, prioritizing albums, then EPs, then single releases.
-            out.album = match rec.releases
-                .and_then(|rels| Some(rels.into_iter()
-                          .filter_map(|rel| rel.release_group)
-                          .filter(|rg| rg.primary_type.is_some())
-                          .collect::<Vec<ReleaseGroup>>())) {
-                    Some(rgs) => {
-                        if rgs.len() == 0 {
-                            String::from("None")
-                        } else {
-                            // I feel like there should be a better way to do this but I don't know
-                            // how to do a 'priority match' over enum variants like this other than
-                            // implementing a sort over ReleaseGroupPrimaryType.
-                            // Maybe min_by/max_by and match rg.primary_type?
-                            if let Some(album) = rgs.iter().find(|rg| rg.primary_type.as_ref().unwrap() == &ReleaseGroupPrimaryType::Album) {
-                                let _  = album_mbid.insert(album.id.clone());
-                                album.title.clone()
-                            } else if let Some(ep) = rgs.iter().find(|rg| rg.primary_type.as_ref().unwrap() == &ReleaseGroupPrimaryType::Ep) {
-                                let _ = album_mbid.insert(ep.id.clone());
-                                ep.title.clone()
-                            } else if let Some(single) = rgs.iter().find(|rg| rg.primary_type.as_ref().unwrap() == &ReleaseGroupPrimaryType::Single) {
-                                let _ = album_mbid.insert(single.id.clone());
-                                String::from("Single")
-                            } else {
-                                String::from("Unrecognized Release Type")
-                            }
-                        }
-                    },
-                    None => String::from("Not Found"),
+            // in short: find all releases of a song with a typed release group, and take the
+            // title of that group, prioritizing albums, then EPs, then single releases. The
+            // release itself is kept around for the year, track number and cover art.
+            let releases = rec.releases;
+            let best_release = releases.as_ref().and_then(|rels| {
+                rels.iter()
+                    .filter(|rel| {
+                        rel.release_group
+                            .as_ref()
+                            .is_some_and(|rg| rg.primary_type.is_some())
+                    })
+                    .min_by_key(|rel| release_priority(rel))
+            });
+
+            out.album = match (&releases, best_release) {
+                (None, _) => String::from("Not Found"),
+                (Some(_), None) => String::from("None"),
+                (Some(_), Some(rel)) => {
+                    // Guarenteed to have a release group by the filter above
+                    let rg = rel.release_group.as_ref().unwrap();
+                    match release_priority(rel) {
+                        0 | 1 => rg.title.clone(),
+                        2 => String::from("Single"),
+                        _ => String::from("Unrecognized Release Type"),
+                    }
+                }
             };
 
+            if let Some(rel) = best_release {
+                out.release_mbid = Some(rel.id.clone());
+                out.release_group_mbid = rel.release_group.as_ref().map(|rg| rg.id.clone());
+                out.release_year = rel.date.map(|d| d.year());
+
+                // Recording lookups only include the medium and track the recording appears on
+                if let Some(medium) = rel
+                    .media
+                    .as_ref()
+                    .and_then(|m| m.iter().find(|m| m.tracks.as_ref().is_some_and(|t| !t.is_empty())))
+                {
+                    out.disc_number = medium.position;
+                    out.track_number = medium
+                        .tracks
+                        .as_ref()
+                        .and_then(|t| t.first())
+                        .map(|t| t.position);
+                }
+            }
+
             // nice break after that one up there
-            out.artist = match rec.artist_credit {
-                Some(artists) => {
-                    // Assumes first credit is primary artist. TODO: Possibly check if returned
-                    // order is by relevance or arbitrary?
-                    artists.get(0).map_or("Not Found".to_string(), |a| a.name.clone())
-                },
-                None => "Not Found".to_string(),
+            // MusicBrainz credits are in credited order, with join phrases describing how each
+            // artist relates to the next one. Fall back to the AcoustID artists if MusicBrainz
+            // didn't give us any.
+            out.artists = match rec.artist_credit {
+                Some(credits) if !credits.is_empty() => credits
+                    .into_iter()
+                    .map(|c| ArtistCredit {
+                        name: c.name,
+                        mbid: Some(c.artist.id),
+                        join_phrase: c.joinphrase.unwrap_or_default(),
+                    })
+                    .collect(),
+                _ => r
+                    .artists
+                    .clone()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|a| ArtistCredit {
+                        name: a.name,
+                        mbid: Some(a.id),
+                        join_phrase: a.joinphrase.unwrap_or_default(),
+                    })
+                    .collect(),
             };
 
+            if !out.artists.is_empty() {
+                out.artist = format_artist_credits(&out.artists);
+            }
+
             // getting album art is non-trivial but is 'critical' apparently (why do I do this to
             // myself)
-            // Fuck now I have to refactor the album thing up there to also get the mbid of that
-            // release.
-            out.album_art = match album_mbid {
+            out.album_art = match &out.release_mbid {
                 None => None,
                 Some(mbid) => {
-                    Some(CLIENT
+                    CLIENT
                         .get(format!("https://coverartarchive.org/release/{}", mbid))
                         .send()
                         .await?
                         .json::<CoverArtArchiveResponse>()
                         .await?
-                        .images[0]
-                        .image
-                        .clone()
-                        )
+                        .images
+                        .first()
+                        .map(|img| img.image.clone())
                 }
             };
 
@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Error};
+use log::{log, Level};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+use crate::sources::{self, spotify, youtube};
+use crate::types::{EnqueueError, EnqueueOutcome, GlobalQueue, Song, SongOrigin};
+
+/**
+ * Which songs of a playlist are imported, and in what order
+ * */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportOptions {
+    /**
+     * Positions in the playlist to import, counting from 0. Positions past the end of the
+     * playlist are ignored.
+     * */
+    pub range: Option<Range<usize>>,
+
+    /**
+     * Whether the selected songs are shuffled before being queued
+     * */
+    pub shuffle: bool,
+
+    /**
+     * Maximum number of songs to import. Applied after shuffling, so a shuffled import picks
+     * random songs from the selected range.
+     * */
+    pub first: Option<usize>,
+}
+
+impl ImportOptions {
+    /**
+     * Picks the entries of a playlist to import
+     * */
+    pub fn select<T>(&self, mut entries: Vec<T>) -> Vec<T> {
+        if let Some(range) = &self.range {
+            let end = range.end.min(entries.len());
+            let start = range.start.min(end);
+            entries = entries.drain(start..end).collect();
+        }
+        if self.shuffle {
+            entries.shuffle(&mut rand::thread_rng());
+        }
+        if let Some(first) = self.first {
+            entries.truncate(first);
+        }
+        entries
+    }
+}
+
+/**
+ * Result of importing a playlist into a user's queue
+ * */
+#[derive(Default)]
+pub struct ImportOutcome {
+    /**
+     * Songs that were queued or merged into an already queued song
+     * */
+    pub queued: Vec<(u64, EnqueueOutcome)>,
+
+    /**
+     * Songs that were rejected, along with why
+     * */
+    pub rejected: Vec<(Song, EnqueueError)>,
+
+    /**
+     * Number of selected songs that weren't attempted because the user's queue filled up
+     * */
+    pub not_attempted: usize,
+}
+
+/**
+ * Expands a YouTube playlist or YouTube Music album link into songs submitted by {submitter},
+ * selected and ordered according to {options}
+ * */
+pub fn fetch_playlist(
+    url: &str,
+    submitter: &str,
+    options: &ImportOptions,
+) -> Result<Vec<Song>, Error> {
+    let playlist = youtube::playlist_url(url)
+        .ok_or_else(|| anyhow!("{} is not a YouTube playlist or album", url))?;
+    let entries = youtube::expand_playlist(&playlist, &sources::config())?;
+
+    Ok(options
+        .select(entries)
+        .into_iter()
+        .map(|info| {
+            let video = youtube::YoutubeUrl {
+                id: info.id.clone(),
+                start: None,
+            };
+            let mut song = Song::new(SongOrigin::Youtube(video.canonical()), submitter.to_string());
+            song.source_info = Some(info);
+            song
+        })
+        .collect())
+}
+
+/**
+ * Expands a Spotify track, album or playlist link into songs submitted by {submitter}, selected
+ * and ordered according to {options}. The songs have the tracks' details as their metadata, so
+ * queue limits can be checked before they are resolved to YouTube videos.
+ * */
+pub async fn fetch_spotify(
+    url: &str,
+    submitter: &str,
+    options: &ImportOptions,
+) -> Result<Vec<Song>, Error> {
+    let tracks = spotify::resolve(url).await?;
+    Ok(options
+        .select(tracks)
+        .into_iter()
+        .map(|track| {
+            let mut song = Song::new(SongOrigin::Spotify(track.url()), submitter.to_string());
+            song.metadata = Some(track.metadata());
+            song
+        })
+        .collect())
+}
+
+impl GlobalQueue {
+    /**
+     * Appends several songs to {user_id}'s queue, such as the tracks of an imported playlist.
+     * The import as a whole counts as a single submission for rate limiting, but every song is
+     * checked against moderation, the other queue limits and duplicates on its own. Importing
+     * stops once the user's queue is full.
+     * */
+    pub fn enqueue_many(
+        &mut self,
+        user_id: &str,
+        songs: Vec<Song>,
+    ) -> Result<ImportOutcome, EnqueueError> {
+        self.check_rate_limit(user_id)?;
+
+        let mut outcome = ImportOutcome::default();
+        let mut songs = songs.into_iter();
+        for song in songs.by_ref() {
+            let id = song.id;
+            match self.enqueue_song(user_id, song.clone(), false) {
+                Ok(queued) => outcome.queued.push((id, queued)),
+                Err(e @ EnqueueError::QueueFull { .. }) => {
+                    outcome.rejected.push((song, e));
+                    break;
+                }
+                // Nothing in the import can be queued if the user isn't allowed to queue at all
+                Err(
+                    e @ (EnqueueError::NotPermitted
+                    | EnqueueError::QueueLocked
+                    | EnqueueError::UserBanned),
+                ) => return Err(e),
+                Err(e) => outcome.rejected.push((song, e)),
+            }
+        }
+        outcome.not_attempted = songs.len();
+
+        if !outcome.queued.is_empty() {
+            self.record_submission(user_id);
+        }
+        log!(
+            Level::Debug,
+            "imported {} songs for {}, {} rejected, {} not attempted",
+            outcome.queued.len(),
+            user_id,
+            outcome.rejected.len(),
+            outcome.not_attempted
+        );
+        Ok(outcome)
+    }
+}
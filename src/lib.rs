@@ -1,32 +1,129 @@
 #![allow(dead_code)]
 
+pub mod admin;
+pub mod autoplay;
+pub mod decode;
+pub mod dedup;
+pub mod federated;
 pub mod fingerprint;
+pub mod import;
+pub mod library;
+pub mod limits;
+pub mod moderation;
+pub mod player;
+pub mod sources;
 pub mod types;
+pub mod votes;
 
 use crate::fingerprint::SongMetadata;
 
-use std::fs;
-use std::io::BufReader;
-use std::{fs::File, path::Path};
+use std::collections::{HashMap, HashSet};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
+use admin::Role;
 use anyhow::{anyhow, Error};
-use fingerprint::lookup_song;
+use decode::AudioSource;
+use dedup::{compare_songs, Duplicate, DuplicateLocation, DuplicatePolicy};
+use fingerprint::chromaprint::{calculate_fingerprint, fingerprint_partial, FingerprintData};
+use fingerprint::lookup_fingerprint;
 use log::{log, Level};
+use player::loudness::{analyze_file, LoudnessInfo};
+use player::silence::{detect_file, SilenceInfo, TrimPoints};
 use rodio::Source;
-use youtube_dl::{SearchOptions, YoutubeDl};
+use sources::growing::Download;
+use sources::spotify::{LinkKind, SpotifyLink};
+use sources::youtube::YoutubeUrl;
+use youtube_dl::{Playlist, SearchOptions, SingleVideo, YoutubeDl};
 
 use types::*;
 
+/**
+ * Number of played songs kept in the history
+ * */
+pub const MAX_HISTORY: usize = 1000;
+
+/**
+ * Searches YouTube for {query}, returning up to {count} songs in order of
+ * relevance. The songs have no submitter set, and have the information
+ * reported by the search as their source info. GlobalQueue::search also
+ * searches the local library and play history.
+ * */
 pub fn search(query: &str, count: usize) -> Result<Vec<Song>, Error> {
     let opts = SearchOptions::youtube(query).with_count(count);
 
     log!(Level::Debug, "Starting search for {}", query);
 
-    let search = YoutubeDl::search_for(&opts).run()?;
+    let search = YoutubeDl::search_for(&opts)
+        .youtube_dl_path(sources::config().ytdlp)
+        .socket_timeout("15")
+        .flat_playlist(true)
+        .run()?;
+
+    let entries = search
+        .into_playlist()
+        .and_then(|playlist| playlist.entries)
+        .unwrap_or_default();
 
-    log!(Level::Debug, "{:?}", search);
+    log!(Level::Debug, "Search for {} found {} results", query, entries.len());
 
-    Err(anyhow!("Not Yet Implemented"))
+    Ok(search_results(&entries))
+}
+
+/**
+ * Same as search, but doesn't block. yt-dlp is killed if the returned future
+ * is dropped before the search finishes, so searches that are given up on,
+ * such as by a timeout, don't keep running.
+ * */
+pub async fn search_async(query: &str, count: usize) -> Result<Vec<Song>, Error> {
+    let opts = SearchOptions::youtube(query).with_count(count);
+
+    log!(Level::Debug, "Starting search for {}", query);
+
+    // The same arguments YoutubeDl::run passes, but its child process isn't
+    // killed when the future running it is dropped
+    let output = tokio::process::Command::new(sources::config().ytdlp)
+        .args(["--flat-playlist", "--socket-timeout", "15", "-J"])
+        .arg(opts.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "yt-dlp search failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let entries = serde_json::from_slice::<Playlist>(&output.stdout)?
+        .entries
+        .unwrap_or_default();
+
+    log!(Level::Debug, "Search for {} found {} results", query, entries.len());
+
+    Ok(search_results(&entries))
+}
+
+/**
+ * Turns the videos found by a search into songs
+ * */
+fn search_results(entries: &[SingleVideo]) -> Vec<Song> {
+    entries
+        .iter()
+        .map(|video| {
+            let url = YoutubeUrl {
+                id: video.id.clone(),
+                start: None,
+            };
+            let mut song = Song::new(SongOrigin::Youtube(url.canonical()), String::new());
+            song.source_info = Some(SourceInfo::from_video(video));
+            song
+        })
+        .collect()
 }
 
 impl Song {
@@ -39,58 +136,140 @@ impl Song {
             _ => None,
         };
         Song {
+            id: next_song_id(),
             origin,
             submitter,
+            autoplay: false,
             metadata: None,
             path,
+            download: None,
+            fingerprint: None,
+            upvotes: HashSet::new(),
+            downvotes: HashSet::new(),
+            skip_votes: HashSet::new(),
+            source_info: None,
+            loudness: None,
+            trim: TrimPoints::default(),
+            silence: None,
+            resolved: None,
+        }
+    }
+
+    /**
+     * Whether the song is a live stream that never ends on its own
+     * */
+    pub fn is_live(&self) -> bool {
+        matches!(self.origin, SongOrigin::Radio(_))
+    }
+
+    /**
+     * Gets the song's current metadata. For radio streams that are playing
+     * this is what the station says it is playing right now.
+     * */
+    pub fn live_metadata(&self) -> Option<SongMetadata> {
+        if self.is_live() {
+            if let Some(meta) = sources::radio::live_metadata(self.id) {
+                return Some(meta);
+            }
         }
+        self.metadata.clone()
     }
 
     /**
-     * Gets the audio stream for a song
+     * Gets the origin the song's audio is fetched from
+     * */
+    pub fn playable_origin(&self) -> &SongOrigin {
+        self.resolved.as_ref().unwrap_or(&self.origin)
+    }
+
+    /**
+     * Resolves songs whose origin can't be played directly. Spotify tracks
+     * are looked up on Spotify and matched to a YouTube video, with the
+     * track's details used as the song's metadata if it has none. Does
+     * nothing for other origins, or songs that are already resolved.
+     * */
+    pub async fn resolve(&mut self) -> Result<(), Error> {
+        if self.resolved.is_some() {
+            return Ok(());
+        }
+        if let SongOrigin::Spotify(url) = &self.origin {
+            let (track, found) = sources::spotify::find_song(url.clone()).await?;
+            self.use_resolved(track, found);
+        }
+        Ok(())
+    }
+
+    /**
+     * Same as Song::resolve, but blocks until the song is resolved. Can be
+     * called from inside an async runtime.
+     * */
+    pub fn resolve_blocking(&mut self) -> Result<(), Error> {
+        if self.resolved.is_some() {
+            return Ok(());
+        }
+        if let SongOrigin::Spotify(url) = &self.origin {
+            let (track, found) = sources::spotify::block_on(sources::spotify::find_song(url.clone()))??;
+            self.use_resolved(track, found);
+        }
+        Ok(())
+    }
+
+    /**
+     * Records the YouTube video a Spotify track was matched to
+     * */
+    fn use_resolved(&mut self, track: sources::spotify::SpotifyTrack, found: Song) {
+        log!(
+            Level::Debug,
+            "resolved Spotify track {} to {:?}",
+            track.url(),
+            found.origin
+        );
+        self.resolved = Some(found.origin);
+        self.source_info = found.source_info;
+        if self.metadata.is_none() {
+            self.metadata = Some(track.metadata());
+        }
+    }
+
+    /**
+     * Records where a fetched song was cached and what its source reported
+     * about it, returning its audio
+     * */
+    fn use_fetched(&mut self, fetched: sources::Fetched) -> AudioSource {
+        self.path = Some(fetched.path);
+        self.download = fetched.download;
+        if let Some(info) = fetched.info {
+            self.source_info = Some(info);
+        }
+        fetched.source
+    }
+
+    /**
+     * Gets the audio stream for a song, resolving it first if it hasn't been
      * */
     pub fn as_stream(&mut self) -> Result<Box<dyn Source<Item = f32> + Send>, Error> {
         let start = std::time::Instant::now();
-        let out: Result<Box<dyn Source<Item = f32> + Send>, Error> = match &self.origin {
+        self.resolve_blocking()?;
+        let origin = self.playable_origin().clone();
+        let out: Result<Box<dyn Source<Item = f32> + Send>, Error> = match &origin {
             SongOrigin::FileUpload(path) => {
-                let file = File::open(path)?;
                 log!(Level::Debug, "Converting file to stream");
-                Ok(Box::new(
-                    rodio::Decoder::new(BufReader::new(file))?.convert_samples(),
-                ))
+                decode::open_file(path)
             }
             SongOrigin::Youtube(url) => {
-                if !Path::new("/tmp/jukebox").exists() {
-                    log!(Level::Debug, "output dir does not exist, creating");
-                    fs::create_dir_all("/tmp/jukebox")?;
-                }
-
-                log!(Level::Debug, "Downloading Youtube video from {url}");
-                let song_dl = YoutubeDl::new(url.as_str())
-                    .socket_timeout("15")
-                    .format("bestaudio")
-                    .output_directory("/tmp/jukebox")
-                    .output_template("%(id)s")
-                    .download(true)
-                    .extra_arg("-x")
-                    .extra_arg("--audio-format")
-                    .extra_arg("mp3")
-                    .run()?;
-
-                log!(Level::Trace, "Getting id from data download");
-                let filename = match song_dl.into_single_video().and_then(|v| Some(v.id)) {
-                    Some(id) => id,
-                    None => url.split("?v=").skip(1).next().unwrap().to_string(),
-                };
-
-                log!(Level::Debug, "Converting output video to stream");
-                let file = File::open(format!("/tmp/jukebox/{filename}.mp3"))?;
-                let _ = self.path.insert(format!("/tmp/jukebox/{filename}.mp3"));
-                Ok(Box::new(
-                    rodio::Decoder::new(BufReader::new(file))?.convert_samples(),
-                ))
-            }
-            _ => todo!(),
+                let fetched = sources::youtube::fetch(url, &sources::config())?;
+                Ok(self.use_fetched(fetched))
+            }
+            SongOrigin::Soundcloud(url) | SongOrigin::Ytdlp(url) => {
+                let fetched = sources::ytdlp::fetch(url, &sources::config())?;
+                Ok(self.use_fetched(fetched))
+            }
+            SongOrigin::Http(url) => {
+                let fetched = sources::http::fetch(url, &sources::config())?;
+                Ok(self.use_fetched(fetched))
+            }
+            SongOrigin::Radio(url) => Ok(sources::radio::open(url, self.id)?.source),
+            SongOrigin::Spotify(url) => Err(anyhow!("Spotify song {} has not been resolved", url)),
         };
 
         log!(
@@ -102,23 +281,347 @@ impl Song {
         out
     }
 
+    /**
+     * Fetches information about the song from its source without downloading
+     * it, such as the uploading channel and duration. Returns None for
+     * origins that don't report any.
+     * */
+    pub fn fetch_source_info(&mut self) -> Result<Option<&SourceInfo>, Error> {
+        if self.source_info.is_none() {
+            let config = sources::config();
+            self.source_info = match self.playable_origin() {
+                SongOrigin::Youtube(url) => YoutubeDl::new(url.as_str())
+                    .youtube_dl_path(&config.ytdlp)
+                    .socket_timeout("15")
+                    .run()?
+                    .into_single_video()
+                    .map(|v| SourceInfo::from_video(&v)),
+                SongOrigin::Soundcloud(url) | SongOrigin::Ytdlp(url) => {
+                    Some(sources::ytdlp::probe(url, &config)?.info)
+                }
+                _ => None,
+            };
+        }
+        Ok(self.source_info.as_ref())
+    }
+
+    /**
+     * Looks up what can be known about the song without downloading it, such
+     * as its duration, so it can be checked before it is queued. Spotify
+     * tracks are resolved to a YouTube video first. Does nothing for songs
+     * that have already been looked up, or origins that report nothing.
+     * */
+    pub fn probe(&mut self) -> Result<(), Error> {
+        self.resolve_blocking()?;
+        self.fetch_source_info()?;
+        Ok(())
+    }
+
+    /**
+     * Copies information learned about the same song elsewhere, such as on
+     * the copy that was downloaded for playback, onto this one
+     * */
+    pub(crate) fn fill_from(&mut self, other: &Song) {
+        if other.metadata.is_some() {
+            self.metadata = other.metadata.clone();
+        }
+        if other.fingerprint.is_some() {
+            self.fingerprint = other.fingerprint.clone();
+        }
+        if other.source_info.is_some() {
+            self.source_info = other.source_info.clone();
+        }
+        if other.path.is_some() {
+            self.path = other.path.clone();
+            self.download = other.download.clone();
+        }
+        self.resolved = self.resolved.take().or_else(|| other.resolved.clone());
+        self.loudness = self.loudness.take().or_else(|| other.loudness.clone());
+        self.silence = self.silence.or(other.silence);
+    }
+
+    /**
+     * The song's download, if it is still in progress
+     * */
+    pub fn downloading(&self) -> Option<&Download> {
+        self.download.as_ref().filter(|download| !download.is_done())
+    }
+
+    /**
+     * Path of the song's audio file, once it has been fetched. Uploaded files
+     * are played from where they are, without being fetched.
+     * */
+    pub fn audio_path(&self) -> Option<&str> {
+        match (&self.path, self.playable_origin()) {
+            (Some(path), _) => Some(path),
+            (None, SongOrigin::FileUpload(path)) => Some(path),
+            (None, _) => None,
+        }
+    }
+
+    /**
+     * Blocks until the song's audio has finished downloading, if it is
+     * still in progress
+     * */
+    fn wait_for_download(&self) -> Result<(), Error> {
+        if let Some(download) = self.downloading() {
+            log!(Level::Debug, "waiting for song {} to finish downloading", self.id);
+            download.wait()?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Gets the audio stream for a song from its already downloaded file,
+     * without fetching it again. Used to restart a song at a different
+     * position. If the download is still in progress the stream follows it
+     * as it downloads.
+     * */
+    pub fn open_cached(&self) -> Result<Box<dyn Source<Item = f32> + Send>, Error> {
+        let path = self
+            .audio_path()
+            .ok_or_else(|| anyhow!("Song has not been fetched yet"))?;
+        match self.downloading() {
+            Some(download) => {
+                decode::open_reader(download.open()?, decode::extension(path).as_deref())
+            }
+            None => decode::open_file(path),
+        }
+    }
+
     /**
      * Gets the song's metadata or, if the field is None, attempts to fetch
-     * the metadata from the song's origin.
+     * the metadata from the song's origin. Waits for the song to finish
+     * downloading if it is still in progress.
      * */
     pub async fn fetch_metadata(&mut self) -> Result<&SongMetadata, Error> {
         if self.metadata.is_some() {
             self.metadata.as_ref().ok_or_else(|| unreachable!())
         } else {
+            if let Some(download) = self.downloading().cloned() {
+                tokio::task::spawn_blocking(move || download.wait()).await??;
+            }
             if self.path.is_some() {
-                let meta = lookup_song(self.path.as_ref().unwrap()).await?;
-                let _ = self.metadata.insert(meta);
-                self.metadata.as_ref().ok_or_else(|| unreachable!())
+                let fp = calculate_fingerprint(self.path.as_ref().unwrap())?;
+                self.identify(fp).await
             } else {
                 Err(anyhow!("Song has not been fetched yet"))
             }
         }
     }
+
+    /**
+     * Fetches the song's metadata from the first {length} of its audio file, which may still be
+     * downloading. Existing metadata is replaced, since a full fingerprint is more reliable than
+     * a partial one.
+     * */
+    pub async fn fetch_metadata_partial(
+        &mut self,
+        length: Duration,
+        total_duration: Option<Duration>,
+    ) -> Result<&SongMetadata, Error> {
+        let fp = fingerprint_partial(self.open_cached()?, length, total_duration)?;
+        self.identify(fp).await
+    }
+
+    /**
+     * Fetches the song's metadata from the first {length} of an already decoded source, such as
+     * the one being played or a live stream
+     * */
+    pub async fn fetch_metadata_from_source<S: Source<Item = f32>>(
+        &mut self,
+        source: S,
+        length: Duration,
+    ) -> Result<&SongMetadata, Error> {
+        let fp = fingerprint_partial(source, length, None)?;
+        self.identify(fp).await
+    }
+
+    /**
+     * Looks up and stores metadata for a fingerprint of this song
+     * */
+    async fn identify(&mut self, fp: FingerprintData) -> Result<&SongMetadata, Error> {
+        let meta = lookup_fingerprint(&fp).await?;
+        let _ = self.fingerprint.insert(fp);
+        Ok(self.metadata.insert(meta))
+    }
+
+    /**
+     * Gets the song's loudness or, if it hasn't been measured yet, reads it
+     * from the song's ReplayGain tags or analyzes the downloaded audio.
+     * Blocks until the song finishes downloading if it is still in progress.
+     * */
+    pub fn analyze_loudness(&mut self) -> Result<&LoudnessInfo, Error> {
+        if self.loudness.is_none() {
+            self.wait_for_download()?;
+            let path = self
+                .audio_path()
+                .ok_or_else(|| anyhow!("Song has not been fetched yet"))?;
+            let _ = self.loudness.insert(analyze_file(path)?);
+        }
+        self.loudness.as_ref().ok_or_else(|| unreachable!())
+    }
+
+    /**
+     * Replaces the song's origin with its canonical form. A start time in
+     * the origin, like a YouTube timestamp, becomes the song's start point
+     * unless one has already been set.
+     * */
+    pub fn canonicalize(&mut self) -> Result<(), Error> {
+        let origin = self.origin.canonicalize()?;
+        if self.trim.start.is_none() {
+            self.trim.start = self.origin.start_time();
+        }
+        self.origin = origin;
+        Ok(())
+    }
+
+    /**
+     * Sets the manual start and end points of the song
+     * */
+    pub fn set_trim(&mut self, start: Option<Duration>, end: Option<Duration>) -> Result<(), Error> {
+        if let (Some(start), Some(end)) = (start, end) {
+            if start >= end {
+                return Err(anyhow!("Song must start before it ends"));
+            }
+        }
+        self.trim = TrimPoints { start, end };
+        Ok(())
+    }
+
+    /**
+     * Gets the song's leading and trailing silence or, if it hasn't been
+     * detected yet, analyzes the downloaded audio. Blocks until the song
+     * finishes downloading if it is still in progress.
+     * */
+    pub fn detect_silence(&mut self, threshold: f64) -> Result<&SilenceInfo, Error> {
+        if self.silence.is_none() {
+            self.wait_for_download()?;
+            let path = self
+                .audio_path()
+                .ok_or_else(|| anyhow!("Song has not been fetched yet"))?;
+            let _ = self.silence.insert(detect_file(path, threshold)?);
+        }
+        self.silence.as_ref().ok_or_else(|| unreachable!())
+    }
+
+    /**
+     * Gets the range of the song to play, using the manual trim points where
+     * set and the detected silence otherwise
+     * */
+    pub fn effective_trim(&self, max_trim: Duration) -> TrimPoints {
+        let detected = self
+            .silence
+            .map(|s| s.trim_points(max_trim))
+            .unwrap_or_default();
+        TrimPoints {
+            start: self.trim.start.or(detected.start),
+            end: self.trim.end.or(detected.end),
+        }
+    }
+
+    /**
+     * Gets the duration of the song, if known
+     * */
+    pub fn duration(&self) -> Option<Duration> {
+        self.metadata
+            .as_ref()
+            .map(|m| m.duration)
+            .filter(|d| *d > 0.0)
+            .or_else(|| self.source_info.as_ref().and_then(|i| i.duration))
+            .map(Duration::from_secs_f64)
+    }
+
+    /**
+     * Gets the display information for this song as shown in the queue UI
+     * */
+    pub fn queue_entry(&self) -> QueueEntry {
+        let metadata = self.live_metadata();
+        QueueEntry {
+            title: metadata.as_ref().map(|m| m.title.clone()),
+            artist: metadata.as_ref().map(|m| m.artist.clone()),
+            artists: metadata.map_or(vec![], |m| m.artists),
+            submitter: self.submitter.clone(),
+        }
+    }
+}
+
+impl SongOrigin {
+    /**
+     * Works out the origin of pasted input. Links to supported sites become
+     * the matching origin, and anything that isn't a link is searched for,
+     * with the top result being used.
+     * */
+    pub fn parse(input: &str) -> Result<SongOrigin, Error> {
+        if let Some(origin) = sources::detect::classify(input)? {
+            return Ok(origin);
+        }
+        log!(Level::Debug, "{} is not a link, searching for it", input.trim());
+        search(input.trim(), 1)?
+            .into_iter()
+            .next()
+            .map(|song| song.origin)
+            .ok_or_else(|| anyhow!("No results found for \"{}\"", input.trim()))
+    }
+
+    /**
+     * Gets the canonical form of the origin, so that different links to the
+     * same song, including YouTube links with different timestamps, compare
+     * equal. Fails if the origin is a link that can't be understood.
+     * */
+    pub fn canonicalize(&self) -> Result<SongOrigin, Error> {
+        match self {
+            SongOrigin::Youtube(url) => {
+                Ok(SongOrigin::Youtube(YoutubeUrl::parse(url)?.canonical()))
+            }
+            SongOrigin::Spotify(url) => match SpotifyLink::parse(url) {
+                Some(link) if link.kind == LinkKind::Track => Ok(SongOrigin::Spotify(link.url())),
+                _ => Err(anyhow!(
+                    "{} is not a Spotify track; albums and playlists have to be imported",
+                    url
+                )),
+            },
+            other => Ok(other.clone()),
+        }
+    }
+
+    /**
+     * Gets the position the origin asks playback to start at, such as the
+     * timestamp of a YouTube link
+     * */
+    pub fn start_time(&self) -> Option<Duration> {
+        match self {
+            SongOrigin::Youtube(url) => YoutubeUrl::parse(url).ok().and_then(|u| u.start),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for SongOrigin {
+    type Err = Error;
+
+    /**
+     * Same as SongOrigin::parse
+     * */
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        SongOrigin::parse(input)
+    }
+}
+
+impl SourceInfo {
+    /**
+     * Collects the source information reported by yt-dlp for a video
+     * */
+    pub fn from_video(video: &SingleVideo) -> Self {
+        SourceInfo {
+            id: video.id.clone(),
+            title: Some(video.title.clone()),
+            channel: video.channel.clone().or_else(|| video.uploader.clone()),
+            channel_id: video.channel_id.clone().or_else(|| video.uploader_id.clone()),
+            duration: video.duration.as_ref().and_then(|d| d.as_f64()),
+            age_limit: video.age_limit,
+        }
+    }
 }
 
 impl UserQueue {
@@ -130,6 +633,7 @@ impl UserQueue {
             user_id,
             q: vec![].into(),
             shuffle: false,
+            credit: 0.0,
         }
     }
 
@@ -141,7 +645,20 @@ impl UserQueue {
     }
 
     fn has_songs(&self) -> bool {
-        self.q.len() > 0
+        !self.q.is_empty()
+    }
+
+    /**
+     * Adds a song to the back of the user's queue
+     * */
+    pub fn enqueue(&mut self, song: Song) {
+        self.q.push_back(song)
+    }
+}
+
+impl Default for GlobalQueue {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -153,31 +670,53 @@ impl GlobalQueue {
         GlobalQueue {
             q: vec![].into(),
             users: vec![].into(),
+            history: vec![].into(),
+            duplicates: Default::default(),
+            listeners: HashSet::new(),
+            votes: Default::default(),
+            user_weights: HashMap::new(),
+            limits: Default::default(),
+            submissions: HashMap::new(),
+            roles: HashMap::new(),
+            default_role: Role::Dj,
+            bans: Default::default(),
+            locked: false,
+            audit_log: vec![],
+            moderation: Default::default(),
+            notifications: vec![].into(),
+            autoplay: Default::default(),
+            library: None,
+            search: Default::default(),
         }
     }
 
     /**
      * Gets the next song and adds a new song into the queue from the next user
      * as long as the current number of songs is less than or equal to the
-     * target_count
+     * target_count. The song that was playing is marked as finished. If
+     * every queue is empty a song is picked by autoplay instead.
      * */
     pub fn next(&mut self, target_count: usize) -> Option<Song> {
+        self.finish_current();
         if self.q.len() <= target_count {
-            if let Some(song) = loop {
-                if let Some(mut user) = self.users.pop_front() {
-                    if let Some(song) = user.get_next() {
-                        self.users.push_back(user);
-                        break Some(song);
-                    }
-                    // Users with no songs in queue are dropped
-                } else {
-                    break None;
-                }
-            } {
+            if let Some(song) = self.pull_song() {
                 self.q.push_back(song);
             }
         }
-        self.q.pop_front()
+        let song = match self.q.pop_front() {
+            Some(song) => song,
+            None => self.autoplay_song()?,
+        };
+        self.history.push_back(HistoryEntry {
+            song: song.clone(),
+            played_at: SystemTime::now(),
+            finished: false,
+            skipped: false,
+        });
+        while self.history.len() > MAX_HISTORY {
+            self.history.pop_front();
+        }
+        Some(song)
     }
 
     /**
@@ -185,14 +724,59 @@ impl GlobalQueue {
      * {count} songs in the global queue
      * */
     pub fn flush_songs(&mut self, count: usize) {
-        while self.q.len() < count && self.users.len() > 0 {
-            let mut user = self.users.pop_front().unwrap(); // Guarenteed safe by loop condition
-            if let Some(song) = user.get_next() {
-                self.q.push_back(song);
+        while self.q.len() < count {
+            match self.pull_song() {
+                Some(song) => self.q.push_back(song),
+                None => break,
+            }
+        }
+    }
+
+    /**
+     * Whether any song is waiting in the global queue or a user queue
+     * */
+    pub fn has_queued(&self) -> bool {
+        !self.q.is_empty() || self.users.iter().any(|user| user.has_songs())
+    }
+
+    /**
+     * Whether the current song is a live stream and a song has since been
+     * queued, meaning the player should stop the stream and move on. Streams
+     * never end on their own, so nothing queued behind one would play
+     * otherwise.
+     * */
+    pub fn should_yield_live(&self) -> bool {
+        self.now_playing().is_some_and(|song| song.is_live()) && self.has_queued()
+    }
+
+    /**
+     * Takes the next song from the user queues in weighted round robin order.
+     * Each user gains credit equal to their scheduling weight when their turn
+     * comes up, and a song is taken once they have a full credit, so users
+     * with skipped songs are picked less often.
+     * */
+    fn pull_song(&mut self) -> Option<Song> {
+        // Users with no songs in queue are dropped
+        self.users.retain(|user| user.has_songs());
+        // Weights are at least MIN_WEIGHT, so someone reaches a full credit within this many
+        // turns. The limit only matters if a weight isn't a number.
+        let turns = self.users.len() * (1.0 / votes::MIN_WEIGHT).ceil() as usize;
+        for _ in 0..turns {
+            let mut user = self.users.pop_front()?;
+            user.credit += self.weight_of(&user.user_id);
+            if user.credit >= 1.0 {
+                user.credit -= 1.0;
+                let song = user.get_next();
                 self.users.push_back(user);
+                return song;
             }
-            // Users with no songs in queue are dropped
+            self.users.push_back(user);
         }
+        log!(Level::Warn, "no user reached a full scheduling credit, taking the next song anyway");
+        let mut user = self.users.pop_front()?;
+        let song = user.get_next();
+        self.users.push_back(user);
+        song
     }
 
     /**
@@ -202,11 +786,387 @@ impl GlobalQueue {
         self.users.push_back(UserQueue::new(user_id))
     }
 
+    /**
+     * Submits a song to a user's queue, registering the user if they have no
+     * queue yet. The submission is checked against the user's role, bans and
+     * the queue limits, and
+     * duplicates are handled according to the duplicate policy.
+     * */
+    pub fn enqueue(&mut self, user_id: &str, song: Song) -> Result<EnqueueOutcome, EnqueueError> {
+        self.enqueue_song(user_id, song, true)
+    }
+
+    /**
+     * Checks and queues a song for {user_id}. If {rate_limited} is false the
+     * submission isn't checked against or counted towards the user's rate
+     * limit, for when the caller has already accounted for it.
+     * */
+    pub(crate) fn enqueue_song(
+        &mut self,
+        user_id: &str,
+        mut song: Song,
+        rate_limited: bool,
+    ) -> Result<EnqueueOutcome, EnqueueError> {
+        song.canonicalize()
+            .map_err(|e| EnqueueError::InvalidOrigin(e.to_string()))?;
+        // Durations and the like are needed by the checks below. Callers that hold the queue
+        // behind a lock can probe first to avoid holding it during the lookup. Links from sites
+        // that aren't allowed are caught here, before anything is downloaded.
+        song.probe().map_err(|e| match e.downcast_ref::<sources::ytdlp::NotAllowed>() {
+            Some(not_allowed) => EnqueueError::Blocked(not_allowed.to_string()),
+            None => EnqueueError::InvalidOrigin(e.to_string()),
+        })?;
+        self.check_permissions(user_id, &song)?;
+        self.moderation
+            .check(&song)
+            .map_err(EnqueueError::Blocked)?;
+        if rate_limited {
+            self.check_rate_limit(user_id)?;
+        }
+        self.check_song_limits(user_id, &song)?;
+
+        let outcome = match self.find_duplicate(&song) {
+            None => EnqueueOutcome::Queued,
+            Some(dup) => match self.duplicates.policy {
+                DuplicatePolicy::Reject => return Err(EnqueueError::Duplicate(dup)),
+                DuplicatePolicy::Warn => EnqueueOutcome::QueuedDuplicate(dup),
+                DuplicatePolicy::Merge => {
+                    let existing = match &dup.location {
+                        DuplicateLocation::GlobalQueue(i) => self.q.get_mut(*i),
+                        DuplicateLocation::UserQueue(user, i) => self
+                            .users
+                            .iter_mut()
+                            .find(|u| &u.user_id == user)
+                            .and_then(|u| u.q.get_mut(*i)),
+                        DuplicateLocation::History => None,
+                    };
+                    match existing {
+                        Some(existing) => {
+                            log!(
+                                Level::Debug,
+                                "merging duplicate from {} into song from {}",
+                                user_id,
+                                dup.submitter
+                            );
+                            existing.upvotes.insert(user_id.to_string());
+                            if rate_limited {
+                                self.record_submission(user_id);
+                            }
+                            return Ok(EnqueueOutcome::Merged(dup));
+                        }
+                        None => return Err(EnqueueError::Duplicate(dup)),
+                    }
+                }
+            },
+        };
+
+        match self.users.iter_mut().find(|u| u.user_id == user_id) {
+            Some(user) => user.enqueue(song),
+            None => {
+                let mut user = UserQueue::new(user_id.to_string());
+                user.enqueue(song);
+                self.users.push_back(user);
+            }
+        }
+        if rate_limited {
+            self.record_submission(user_id);
+        }
+        Ok(outcome)
+    }
+
+    /**
+     * Records information learned about a queued song since it was submitted,
+     * such as its duration or metadata once it has been downloaded and
+     * fingerprinted, then checks the song again. A song that no longer passes
+     * is removed and its submitter notified, as is the later of two songs
+     * that turn out to be duplicates. Should be called whenever a queued
+     * song's information is filled in. Returns the removed song, if any.
+     * */
+    pub fn update_song(&mut self, updated: &Song) -> Option<Song> {
+        self.history
+            .iter_mut()
+            .filter(|entry| entry.song.id == updated.id)
+            .for_each(|entry| entry.song.fill_from(updated));
+
+        let song = self.queued_song_mut(updated.id)?;
+        song.fill_from(updated);
+        let song = song.clone();
+        let reason = match self.recheck(&song) {
+            Ok(()) => return self.remove_duplicate(&song),
+            Err(reason) => reason,
+        };
+
+        let removed = self.take_song(song.id)?;
+        log!(
+            Level::Info,
+            "removed song {} from {}: {}",
+            removed.id,
+            removed.submitter,
+            reason
+        );
+        self.notify(
+            &removed.submitter,
+            format!("Your song was removed from the queue: {}", reason),
+        );
+        Some(removed)
+    }
+
+    /**
+     * Checks a queued song against the checks that depend on information
+     * that may only be learned after it was queued
+     * */
+    fn recheck(&self, song: &Song) -> Result<(), EnqueueError> {
+        // Explicit content and blocked artists are often only known once the
+        // song has been identified
+        self.moderate(song).map_err(EnqueueError::Blocked)?;
+
+        let limits = &self.limits;
+        if let (Some(max), Some(duration)) = (limits.max_song_duration, song.duration()) {
+            if duration > max {
+                return Err(EnqueueError::SongTooLong { duration, max });
+            }
+        }
+        // The song already counts towards its submitter's total
+        let queued = self
+            .users
+            .iter()
+            .find(|user| user.q.iter().any(|s| s.id == song.id));
+        if let (Some(max), Some(user)) = (limits.max_total_duration, queued) {
+            let total = user.q.iter().filter_map(|s| s.duration()).sum();
+            if total > max {
+                return Err(EnqueueError::QueueTooLong { total, max });
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Handles a duplicate of a queued song that could only be found once
+     * more was known about it, such as a matching fingerprint or recording,
+     * according to the duplicate policy. Of the two, the one submitted later
+     * is removed. Returns the removed song, if either was.
+     * */
+    fn remove_duplicate(&mut self, song: &Song) -> Option<Song> {
+        if self.duplicates.policy == DuplicatePolicy::Warn {
+            return None;
+        }
+        let dup = self.find_duplicate(song)?;
+        let other = match &dup.location {
+            DuplicateLocation::GlobalQueue(i) => self.q.get(*i),
+            DuplicateLocation::UserQueue(user, i) => self
+                .users
+                .iter()
+                .find(|u| &u.user_id == user)
+                .and_then(|u| u.q.get(*i)),
+            DuplicateLocation::History => None,
+        };
+        // Song ids are allocated in order, so the higher one was submitted later
+        let (removed, kept) = match other {
+            Some(other) if other.id > song.id => (other.id, Some(song.id)),
+            other => (song.id, other.map(|other| other.id)),
+        };
+
+        let merge = self.duplicates.policy == DuplicatePolicy::Merge;
+        let removed = self.take_song(removed)?;
+        let kept = kept.and_then(|id| self.queued_song_mut(id));
+        let kept_by = kept.as_ref().map_or(dup.submitter.clone(), |k| k.submitter.clone());
+        if let Some(kept) = kept.filter(|_| merge) {
+            kept.upvotes.insert(removed.submitter.clone());
+        }
+        log!(
+            Level::Info,
+            "removed song {} from {} as a duplicate ({:?})",
+            removed.id,
+            removed.submitter,
+            dup.reason
+        );
+        self.notify(
+            &removed.submitter,
+            format!(
+                "Your song was removed from the queue: it duplicates a song submitted by {}",
+                kept_by
+            ),
+        );
+        Some(removed)
+    }
+
+    /**
+     * Finds a queued song by id in the global queue or any user's queue
+     * */
+    fn queued_song_mut(&mut self, song_id: u64) -> Option<&mut Song> {
+        self.q
+            .iter_mut()
+            .chain(self.users.iter_mut().flat_map(|user| user.q.iter_mut()))
+            .find(|song| song.id == song_id)
+    }
+
+    /**
+     * Finds a song in the global queue, any user queue, or the history within
+     * the duplicate window that is the same as {song}
+     * */
+    pub fn find_duplicate(&self, song: &Song) -> Option<Duplicate> {
+        let threshold = self.duplicates.similarity_threshold;
+        let found = |location: DuplicateLocation, other: &Song| {
+            if other.id == song.id {
+                return None;
+            }
+            compare_songs(song, other, threshold).map(|reason| Duplicate {
+                location,
+                submitter: other.submitter.clone(),
+                reason,
+            })
+        };
+
+        let queued = self
+            .q
+            .iter()
+            .enumerate()
+            .find_map(|(i, other)| found(DuplicateLocation::GlobalQueue(i), other));
+        let user_queued = || {
+            self.users.iter().find_map(|user| {
+                user.q.iter().enumerate().find_map(|(i, other)| {
+                    found(DuplicateLocation::UserQueue(user.user_id.clone(), i), other)
+                })
+            })
+        };
+        let played = || {
+            let cutoff = SystemTime::now()
+                .checked_sub(self.duplicates.window)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            self.history
+                .iter()
+                .rev()
+                .take_while(|entry| entry.played_at >= cutoff)
+                .find_map(|entry| found(DuplicateLocation::History, &entry.song))
+        };
+
+        queued.or_else(user_queued).or_else(played)
+    }
+
     pub fn preview(&self, count: usize) -> Vec<&Song> {
-        if self.q.len() >= count {
-            self.q.range(0..count).collect::<Vec<&Song>>()
-        } else {
-            self.q.range(0..self.q.len() - 1).collect::<Vec<&Song>>()
+        self.q.iter().take(count).collect::<Vec<&Song>>()
+    }
+
+    /**
+     * Gets display information for the next {count} songs in the global queue
+     * */
+    pub fn preview_entries(&self, count: usize) -> Vec<QueueEntry> {
+        self.preview(count)
+            .into_iter()
+            .map(|song| song.queue_entry())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str) -> SongOrigin {
+        SongOrigin::FileUpload(path.to_string())
+    }
+
+    /**
+     * A queue with {count} songs queued by each of {users}, named after their user and position
+     * */
+    fn queue_with_songs(users: &[&str], count: usize) -> GlobalQueue {
+        let mut queue = GlobalQueue::new();
+        for user in users {
+            let mut user_queue = UserQueue::new(user.to_string());
+            for i in 0..count {
+                let origin = file(&format!("/music/{user}-{i}.flac"));
+                user_queue.enqueue(Song::new(origin, user.to_string()));
+            }
+            queue.users.push_back(user_queue);
         }
+        queue
+    }
+
+    fn pulls(queue: &mut GlobalQueue, count: usize) -> Vec<String> {
+        (0..count)
+            .map_while(|_| queue.pull_song())
+            .map(|song| song.submitter)
+            .collect()
+    }
+
+    #[test]
+    fn songs_are_pulled_round_robin() {
+        let mut queue = queue_with_songs(&["a", "b", "c"], 2);
+        assert_eq!(pulls(&mut queue, 10), ["a", "b", "c", "a", "b", "c"]);
+        assert!(queue.users.is_empty(), "users with no songs left weren't dropped");
+
+        // Songs come out of each user's queue in the order they were queued
+        let mut queue = queue_with_songs(&["a"], 3);
+        let origins = (0..3).map(|_| queue.pull_song().unwrap().origin).collect::<Vec<_>>();
+        assert_eq!(
+            origins,
+            ["/music/a-0.flac", "/music/a-1.flac", "/music/a-2.flac"].map(file)
+        );
+    }
+
+    #[test]
+    fn users_with_lower_weights_are_pulled_less_often() {
+        let mut queue = queue_with_songs(&["a", "b", "c"], 10);
+        queue.user_weights.insert("b".to_string(), 0.5);
+        let order = pulls(&mut queue, 10);
+        assert_eq!(order, ["a", "c", "a", "b", "c", "a", "c", "a", "b", "c"]);
+
+        // Even the lowest weight is scheduled, here every fourth turn
+        let mut queue = queue_with_songs(&["a", "b"], 10);
+        queue.user_weights.insert("b".to_string(), 0.0);
+        assert_eq!(queue.weight_of("b"), 0.25);
+        let order = pulls(&mut queue, 10);
+        assert_eq!(order, ["a", "a", "a", "a", "b", "a", "a", "a", "a", "b"]);
+    }
+
+    #[test]
+    fn next_moves_songs_into_the_global_queue() {
+        let mut queue = queue_with_songs(&["a", "b"], 1);
+        let first = queue.next(1).unwrap();
+        assert_eq!(first.submitter, "a");
+        assert_eq!(queue.now_playing().map(|song| song.id), Some(first.id));
+        assert_eq!(queue.next(1).unwrap().submitter, "b");
+        assert!(queue.history.iter().rev().skip(1).all(|entry| entry.finished));
+    }
+
+    #[test]
+    fn duplicates_follow_the_policy() {
+        let song = |user: &str| Song::new(file("/music/song.flac"), user.to_string());
+
+        let mut queue = GlobalQueue::new();
+        assert!(matches!(queue.enqueue("a", song("a")), Ok(EnqueueOutcome::Queued)));
+        let warned = queue.enqueue("b", song("b"));
+        match warned {
+            Ok(EnqueueOutcome::QueuedDuplicate(dup)) => {
+                assert_eq!(dup.location, DuplicateLocation::UserQueue("a".to_string(), 0));
+                assert_eq!(dup.submitter, "a");
+            }
+            other => panic!("duplicate wasn't warned about: {:?}", other.map(|_| ())),
+        }
+
+        let mut queue = GlobalQueue::new();
+        queue.duplicates.policy = DuplicatePolicy::Reject;
+        queue.enqueue("a", song("a")).unwrap();
+        assert!(matches!(queue.enqueue("b", song("b")), Err(EnqueueError::Duplicate(_))));
+
+        let mut queue = GlobalQueue::new();
+        queue.duplicates.policy = DuplicatePolicy::Merge;
+        queue.enqueue("a", song("a")).unwrap();
+        assert!(matches!(queue.enqueue("b", song("b")), Ok(EnqueueOutcome::Merged(_))));
+        let queued = &queue.users[0].q;
+        assert_eq!(queued.len(), 1);
+        assert!(queued[0].upvotes.contains("b"));
+        assert!(queue.users.iter().all(|user| user.user_id != "b"));
+
+        // Songs played recently are duplicates too, but there's nothing to merge into
+        queue.next(0).unwrap();
+        queue.next(0);
+        assert!(matches!(
+            queue.enqueue("b", song("b")),
+            Err(EnqueueError::Duplicate(Duplicate {
+                location: DuplicateLocation::History,
+                ..
+            }))
+        ));
     }
 }
@@ -0,0 +1,621 @@
+use anyhow::{anyhow, Error};
+use log::{log, Level};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use crate::fingerprint::{ArtistCredit, SongMetadata};
+use crate::types::{Song, SongOrigin};
+
+/**
+ * Internal module for reading the tags of audio files
+ * */
+pub mod tags;
+
+/**
+ * Internal module for keeping the index up to date as files change
+ * */
+pub mod watch;
+
+/**
+ * Extensions of files that are indexed
+ * */
+const AUDIO_EXTENSIONS: [&str; 7] = ["flac", "mp3", "ogg", "wav", "m4a", "aac", "webm"];
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS tracks (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE,
+        title TEXT NOT NULL,
+        artist TEXT,
+        album TEXT,
+        album_artist TEXT,
+        genre TEXT,
+        year INTEGER,
+        track_number INTEGER,
+        disc_number INTEGER,
+        duration REAL NOT NULL,
+        recording_mbid TEXT,
+        release_mbid TEXT,
+        modified INTEGER NOT NULL
+    );
+    CREATE VIRTUAL TABLE IF NOT EXISTS tracks_fts USING fts5(
+        title, artist, album, album_artist, genre,
+        content='tracks', content_rowid='id'
+    );
+    CREATE TRIGGER IF NOT EXISTS tracks_insert AFTER INSERT ON tracks BEGIN
+        INSERT INTO tracks_fts(rowid, title, artist, album, album_artist, genre)
+        VALUES (new.id, new.title, new.artist, new.album, new.album_artist, new.genre);
+    END;
+    CREATE TRIGGER IF NOT EXISTS tracks_delete AFTER DELETE ON tracks BEGIN
+        INSERT INTO tracks_fts(tracks_fts, rowid, title, artist, album, album_artist, genre)
+        VALUES ('delete', old.id, old.title, old.artist, old.album, old.album_artist, old.genre);
+    END;
+    CREATE TRIGGER IF NOT EXISTS tracks_update AFTER UPDATE ON tracks BEGIN
+        INSERT INTO tracks_fts(tracks_fts, rowid, title, artist, album, album_artist, genre)
+        VALUES ('delete', old.id, old.title, old.artist, old.album, old.album_artist, old.genre);
+        INSERT INTO tracks_fts(rowid, title, artist, album, album_artist, genre)
+        VALUES (new.id, new.title, new.artist, new.album, new.album_artist, new.genre);
+    END;
+";
+
+const TRACK_COLUMNS: &str = "id, path, title, artist, album, album_artist, genre, year, \
+    track_number, disc_number, duration, recording_mbid, release_mbid";
+
+/**
+ * Where the library is and where its index is kept
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryConfig {
+    /**
+     * Directories scanned for audio files, including all their subdirectories
+     * */
+    pub directories: Vec<String>,
+
+    /**
+     * Path of the SQLite database the index is stored in
+     * */
+    pub database: String,
+}
+
+impl Default for LibraryConfig {
+    fn default() -> Self {
+        LibraryConfig {
+            directories: vec![],
+            database: String::from("/tmp/jukebox/library.db"),
+        }
+    }
+}
+
+/**
+ * A track in the local library
+ * */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LibraryTrack {
+    /**
+     * ID of the track in the index. Stays the same while the file is unchanged.
+     * */
+    pub id: i64,
+    pub path: String,
+    /**
+     * Title from the file's tags, or its file name if it has no title tag
+     * */
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub duration: f64,
+    pub recording_mbid: Option<String>,
+    pub release_mbid: Option<String>,
+}
+
+impl LibraryTrack {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(LibraryTrack {
+            id: row.get(0)?,
+            path: row.get(1)?,
+            title: row.get(2)?,
+            artist: row.get(3)?,
+            album: row.get(4)?,
+            album_artist: row.get(5)?,
+            genre: row.get(6)?,
+            year: row.get(7)?,
+            track_number: row.get(8)?,
+            disc_number: row.get(9)?,
+            duration: row.get(10)?,
+            recording_mbid: row.get(11)?,
+            release_mbid: row.get(12)?,
+        })
+    }
+
+    /**
+     * Song metadata built from the track's tags
+     * */
+    pub fn metadata(&self) -> SongMetadata {
+        let artist = self.artist.clone().unwrap_or_else(|| String::from("Unknown Artist"));
+        SongMetadata {
+            title: self.title.clone(),
+            artists: vec![ArtistCredit {
+                name: artist.clone(),
+                mbid: None,
+                join_phrase: String::new(),
+            }],
+            artist,
+            album: self.album.clone().unwrap_or_default(),
+            duration: self.duration,
+            recording_mbid: self.recording_mbid.clone(),
+            release_mbid: self.release_mbid.clone(),
+            release_year: self.year,
+            track_number: self.track_number,
+            disc_number: self.disc_number,
+            genres: self.genre.iter().cloned().collect(),
+            ..SongMetadata::default()
+        }
+    }
+
+    /**
+     * Creates a song that plays the track, with its tags as the song's metadata
+     * */
+    pub fn to_song(&self, submitter: &str) -> Song {
+        let mut song = Song::new(SongOrigin::FileUpload(self.path.clone()), submitter.to_string());
+        song.metadata = Some(self.metadata());
+        song
+    }
+}
+
+/**
+ * Changes made to the index by a scan
+ * */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanReport {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    /**
+     * Files that couldn't be read
+     * */
+    pub failed: usize,
+}
+
+/**
+ * Index of the audio files in the configured directories, searchable by their tags
+ * */
+pub struct Library {
+    pub config: LibraryConfig,
+    conn: Mutex<Connection>,
+}
+
+impl Library {
+    /**
+     * Opens the library's index, creating it if it doesn't exist. The directories aren't scanned
+     * until scan is called.
+     * */
+    pub fn open(config: LibraryConfig) -> Result<Self, Error> {
+        if let Some(dir) = Path::new(&config.database).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let conn = Connection::open(&config.database)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Library {
+            config,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> Result<std::sync::MutexGuard<'_, Connection>, Error> {
+        self.conn
+            .lock()
+            .map_err(|_| anyhow!("Library database lock poisoned"))
+    }
+
+    /**
+     * Scans every configured directory, indexing new and changed files and removing files that
+     * no longer exist. Files under a directory that couldn't be read are kept, since they can't
+     * be told apart from files that were removed.
+     * */
+    pub fn scan(&self) -> Result<ScanReport, Error> {
+        let mut report = ScanReport::default();
+        let mut seen = HashSet::new();
+        let mut unreadable = vec![];
+        for dir in &self.config.directories {
+            log!(Level::Debug, "scanning library directory {}", dir);
+            let dir = Path::new(dir);
+            if let Err(e) = self.scan_into(dir, &mut seen, &mut unreadable, &mut report) {
+                log!(Level::Warn, "could not scan {}: {}", dir.display(), e);
+                unreadable.push(dir.to_path_buf());
+            }
+        }
+        for dir in &unreadable {
+            log!(Level::Warn, "keeping tracks under {} since it couldn't be read", dir.display());
+        }
+
+        // Only tracks that were indexed from one of the directories and weren't found again
+        let conn = self.conn()?;
+        let stale = {
+            let mut stmt = conn.prepare("SELECT path FROM tracks")?;
+            let paths = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            paths
+                .into_iter()
+                .filter(|path| !seen.contains(path))
+                .filter(|path| !unreadable.iter().any(|dir| Path::new(path).starts_with(dir)))
+                .collect::<Vec<_>>()
+        };
+        for path in stale {
+            conn.execute("DELETE FROM tracks WHERE path = ?1", params![path])?;
+            report.removed += 1;
+        }
+
+        log!(Level::Debug, "library scan finished: {:?}", report);
+        Ok(report)
+    }
+
+    /**
+     * Scans a single directory, indexing new and changed files without removing anything
+     */
+    pub fn scan_dir(&self, dir: &Path) -> Result<ScanReport, Error> {
+        let mut report = ScanReport::default();
+        self.scan_into(dir, &mut HashSet::new(), &mut vec![], &mut report)?;
+        Ok(report)
+    }
+
+    /**
+     * Indexes the files in {dir} and its subdirectories, adding their paths to {seen}.
+     * Subdirectories that can't be read are added to {unreadable}, and an error is returned if
+     * {dir} itself can't be.
+     * */
+    fn scan_into(
+        &self,
+        dir: &Path,
+        seen: &mut HashSet<String>,
+        unreadable: &mut Vec<PathBuf>,
+        report: &mut ScanReport,
+    ) -> Result<(), Error> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                if let Err(e) = self.scan_into(&path, seen, unreadable, report) {
+                    log!(Level::Warn, "could not scan {}: {}", path.display(), e);
+                    unreadable.push(path);
+                }
+                continue;
+            }
+            if !is_audio_file(&path) {
+                continue;
+            }
+
+            seen.insert(path.to_string_lossy().to_string());
+            let modified = modified_time(&path);
+            let indexed = self.indexed_time(&path)?;
+            if indexed.is_some() && indexed == modified {
+                continue;
+            }
+            match self.index_file(&path) {
+                Ok(()) if indexed.is_some() => report.updated += 1,
+                Ok(()) => report.added += 1,
+                Err(e) => {
+                    log!(Level::Warn, "could not index {}: {}", path.display(), e);
+                    report.failed += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn indexed_time(&self, path: &Path) -> Result<Option<i64>, Error> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT modified FROM tracks WHERE path = ?1",
+                params![path.to_string_lossy()],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /**
+     * Reads a file's tags and adds it to the index, replacing any previous entry for it
+     * */
+    pub fn index_file(&self, path: &Path) -> Result<(), Error> {
+        let track = tags::read_track(path)?;
+        let modified = modified_time(path).unwrap_or_default();
+        self.conn()?.execute(
+            "INSERT INTO tracks (path, title, artist, album, album_artist, genre, year,
+                track_number, disc_number, duration, recording_mbid, release_mbid, modified)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+            ON CONFLICT(path) DO UPDATE SET
+                title = excluded.title, artist = excluded.artist, album = excluded.album,
+                album_artist = excluded.album_artist, genre = excluded.genre,
+                year = excluded.year, track_number = excluded.track_number,
+                disc_number = excluded.disc_number, duration = excluded.duration,
+                recording_mbid = excluded.recording_mbid, release_mbid = excluded.release_mbid,
+                modified = excluded.modified",
+            params![
+                track.path,
+                track.title,
+                track.artist,
+                track.album,
+                track.album_artist,
+                track.genre,
+                track.year,
+                track.track_number,
+                track.disc_number,
+                track.duration,
+                track.recording_mbid,
+                track.release_mbid,
+                modified,
+            ],
+        )?;
+        log!(Level::Trace, "indexed {}", track.path);
+        Ok(())
+    }
+
+    /**
+     * Removes a file, or every file in a directory, from the index
+     * */
+    pub fn remove_path(&self, path: &Path) -> Result<(), Error> {
+        let path = path.to_string_lossy();
+        let removed = self.conn()?.execute(
+            "DELETE FROM tracks WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2",
+            params![path, format!("{}/", path.trim_end_matches('/'))],
+        )?;
+        if removed > 0 {
+            log!(Level::Debug, "removed {} tracks under {} from the library", removed, path);
+        }
+        Ok(())
+    }
+
+    /**
+     * Searches the title, artist, album and genre of every track, best matches first. Every word
+     * of {query} has to match, and the last word may be incomplete so results can be shown as
+     * the query is typed.
+     * */
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<LibraryTrack>, Error> {
+        let query = fts_query(query);
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM tracks_fts JOIN tracks ON tracks.id = tracks_fts.rowid
+            WHERE tracks_fts MATCH ?1 ORDER BY bm25(tracks_fts) LIMIT ?2",
+            TRACK_COLUMNS
+                .split(", ")
+                .map(|c| format!("tracks.{}", c.trim()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))?;
+        let tracks = stmt
+            .query_map(params![query, limit as i64], LibraryTrack::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(tracks)
+    }
+
+    /**
+     * Gets a track by its ID
+     * */
+    pub fn get(&self, id: i64) -> Result<Option<LibraryTrack>, Error> {
+        Ok(self
+            .conn()?
+            .query_row(
+                &format!("SELECT {TRACK_COLUMNS} FROM tracks WHERE id = ?1"),
+                params![id],
+                LibraryTrack::from_row,
+            )
+            .optional()?)
+    }
+
+    /**
+     * Picks up to {count} random tracks
+     * */
+    pub fn random(&self, count: usize) -> Result<Vec<LibraryTrack>, Error> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {TRACK_COLUMNS} FROM tracks ORDER BY random() LIMIT ?1"
+        ))?;
+        let tracks = stmt
+            .query_map(params![count as i64], LibraryTrack::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(tracks)
+    }
+
+    /**
+     * Number of tracks in the index
+     * */
+    pub fn count(&self) -> Result<usize, Error> {
+        let count: i64 = self
+            .conn()?
+            .query_row("SELECT count(*) FROM tracks", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+}
+
+/**
+ * Whether a file is audio that the library indexes, judging by its extension
+ * */
+pub fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+fn modified_time(path: &Path) -> Option<i64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64)
+}
+
+/**
+ * Turns user input into an FTS5 query. Each word is quoted so characters like '-' and '"' are
+ * matched literally instead of being read as query syntax.
+ * */
+fn fts_query(input: &str) -> String {
+    let words = input
+        .split_whitespace()
+        .map(|word| word.replace('"', ""))
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{word}\""))
+        .collect::<Vec<_>>();
+    match words.split_last() {
+        Some((last, rest)) => {
+            let mut query = rest.to_vec();
+            query.push(format!("{last}*"));
+            query.join(" ")
+        }
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    fn fixture(path: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), path)
+    }
+
+    /**
+     * An empty directory for a test to put its library in
+     * */
+    fn library_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jukebox-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("music")).unwrap();
+        dir
+    }
+
+    fn open(dir: &Path, directories: &[&str]) -> Library {
+        Library::open(LibraryConfig {
+            directories: directories
+                .iter()
+                .map(|d| dir.join(d).to_string_lossy().to_string())
+                .collect(),
+            database: dir.join("library.db").to_string_lossy().to_string(),
+        })
+        .unwrap()
+    }
+
+    fn insert(library: &Library, title: &str, artist: &str, genre: &str) {
+        library
+            .conn()
+            .unwrap()
+            .execute(
+                "INSERT INTO tracks (path, title, artist, genre, duration, modified)
+                VALUES (?1, ?2, ?3, ?4, 0, 0)",
+                params![format!("/music/{title}.flac"), title, artist, genre],
+            )
+            .unwrap();
+    }
+
+    fn titles(tracks: Vec<LibraryTrack>) -> Vec<String> {
+        tracks.into_iter().map(|track| track.title).collect()
+    }
+
+    #[test]
+    fn fts_queries() {
+        let cases = [
+            ("", ""),
+            ("   ", ""),
+            ("daft", "\"daft\"*"),
+            ("daft  punk", "\"daft\" \"punk\"*"),
+            // Query syntax is matched literally
+            ("AC/DC - live", "\"AC/DC\" \"-\" \"live\"*"),
+            ("NOT this OR that", "\"NOT\" \"this\" \"OR\" \"that\"*"),
+            ("title:\"quoted\"", "\"title:quoted\"*"),
+            ("\"\" rock", "\"rock\"*"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(fts_query(input), expected, "query for {:?}", input);
+        }
+    }
+
+    #[test]
+    fn searches_with_the_last_word_incomplete() {
+        let library = Library::open(LibraryConfig {
+            directories: vec![],
+            database: String::from(":memory:"),
+        })
+        .unwrap();
+        insert(&library, "One More Time", "Daft Punk", "House");
+        insert(&library, "Back In Black", "AC/DC", "Rock");
+        insert(&library, "Punk Rock Song", "Bad Religion", "Punk");
+
+        assert_eq!(titles(library.search("daft pu", 10).unwrap()), ["One More Time"]);
+        assert_eq!(titles(library.search("ac/dc", 10).unwrap()), ["Back In Black"]);
+        assert_eq!(titles(library.search("bla", 10).unwrap()), ["Back In Black"]);
+        // Earlier words must be whole
+        assert!(library.search("daf punk", 10).unwrap().is_empty());
+        assert_eq!(library.search("punk", 10).unwrap().len(), 2);
+        assert!(library.search("- OR \"", 10).unwrap().is_empty());
+        assert!(library.search("", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn scans_add_update_and_remove_files() {
+        let dir = library_dir("scan");
+        let music = dir.join("music");
+        fs::copy(fixture("fingerprint/clip.wav"), music.join("clip.wav")).unwrap();
+        fs::create_dir(music.join("album")).unwrap();
+        fs::copy(fixture("decode/tone.m4a"), music.join("album/tone.m4a")).unwrap();
+        fs::write(music.join("album/cover.txt"), "not audio").unwrap();
+        fs::write(music.join("broken.mp3"), "not really audio").unwrap();
+        let library = open(&dir, &["music"]);
+
+        let report = library.scan().unwrap();
+        assert_eq!((report.added, report.updated, report.removed), (2, 0, 0));
+        assert_eq!(report.failed, 1);
+        assert_eq!(titles(library.search("tone", 10).unwrap()), ["tone"]);
+
+        // Nothing changed
+        let report = library.scan().unwrap();
+        assert_eq!((report.added, report.updated, report.removed), (0, 0, 0));
+
+        let modified = SystemTime::now() - Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(music.join("clip.wav"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        fs::remove_file(music.join("album/tone.m4a")).unwrap();
+        let report = library.scan().unwrap();
+        assert_eq!((report.added, report.updated, report.removed), (0, 1, 1));
+        assert_eq!(library.count().unwrap(), 1);
+        assert!(library.search("tone", 10).unwrap().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn scans_keep_tracks_in_directories_that_cant_be_read() {
+        let dir = library_dir("unreadable");
+        fs::create_dir(dir.join("more")).unwrap();
+        fs::copy(fixture("fingerprint/clip.wav"), dir.join("music/clip.wav")).unwrap();
+        fs::copy(fixture("decode/tone.m4a"), dir.join("more/tone.m4a")).unwrap();
+        let library = open(&dir, &["music", "more"]);
+        assert_eq!(library.scan().unwrap().added, 2);
+
+        // As if the disk it was on had been unmounted
+        fs::rename(dir.join("more"), dir.join("elsewhere")).unwrap();
+        let report = library.scan().unwrap();
+        assert_eq!(report.removed, 0);
+        assert_eq!(library.count().unwrap(), 2);
+
+        // Files that are gone from directories that could be read are still removed
+        fs::remove_file(dir.join("music/clip.wav")).unwrap();
+        assert_eq!(library.scan().unwrap().removed, 1);
+        assert_eq!(titles(library.search("tone", 10).unwrap()), ["tone"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::Error;
+use lofty::{read_from_path, Accessor, AudioFile, ItemKey, TaggedFileExt};
+use std::path::Path;
+
+use super::LibraryTrack;
+
+/**
+ * Reads the tags and duration of an audio file. Files without tags are still indexed, titled
+ * after their file name.
+ * */
+pub fn read_track(path: &Path) -> Result<LibraryTrack, Error> {
+    let file = read_from_path(path)?;
+    let duration = file.properties().duration().as_secs_f64();
+    let fallback_title = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut track = LibraryTrack {
+        path: path.to_string_lossy().to_string(),
+        title: fallback_title,
+        duration,
+        ..LibraryTrack::default()
+    };
+
+    if let Some(tag) = file.primary_tag().or_else(|| file.first_tag()) {
+        if let Some(title) = tag.title().filter(|t| !t.trim().is_empty()) {
+            track.title = title.to_string();
+        }
+        track.artist = tag.artist().map(|a| a.to_string());
+        track.album = tag.album().map(|a| a.to_string());
+        track.album_artist = tag.get_string(&ItemKey::AlbumArtist).map(str::to_string);
+        track.genre = tag.genre().map(|g| g.to_string());
+        track.year = tag.year().map(|y| y as i32);
+        track.track_number = tag.track();
+        track.disc_number = tag.disk();
+        track.recording_mbid = tag
+            .get_string(&ItemKey::MusicBrainzRecordingId)
+            .map(str::to_string);
+        track.release_mbid = tag
+            .get_string(&ItemKey::MusicBrainzReleaseId)
+            .map(str::to_string);
+    }
+
+    Ok(track)
+}
@@ -0,0 +1,59 @@
+use anyhow::Error;
+use log::{log, Level};
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{recommended_watcher, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{is_audio_file, Library};
+
+/**
+ * Watches the library's directories, indexing audio files as they are added or changed and
+ * removing them from the index when they are deleted. The library is only watched for as long as
+ * the returned watcher is kept.
+ * */
+pub fn watch(library: Arc<Library>) -> Result<RecommendedWatcher, Error> {
+    let handler = library.clone();
+    let mut watcher = recommended_watcher(move |event: notify::Result<Event>| match event {
+        Ok(event) => handle(&handler, event),
+        Err(e) => log!(Level::Warn, "library watcher error: {}", e),
+    })?;
+
+    for dir in &library.config.directories {
+        log!(Level::Debug, "watching {} for changes", dir);
+        watcher.watch(Path::new(dir), RecursiveMode::Recursive)?;
+    }
+    Ok(watcher)
+}
+
+fn handle(library: &Library, event: Event) {
+    let result = match event.kind {
+        // Renames report the old path first and the new path second
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => match event.paths.as_slice() {
+            [from, to] => library.remove_path(from).and_then(|_| index(library, to)),
+            _ => Ok(()),
+        },
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => event
+            .paths
+            .iter()
+            .try_for_each(|path| library.remove_path(path)),
+        EventKind::Create(_) | EventKind::Modify(_) => {
+            event.paths.iter().try_for_each(|path| index(library, path))
+        }
+        _ => Ok(()),
+    };
+    if let Err(e) = result {
+        log!(Level::Warn, "could not update library index: {}", e);
+    }
+}
+
+fn index(library: &Library, path: &Path) -> Result<(), Error> {
+    if path.is_dir() {
+        // A directory moved into the library, scan everything in it
+        library.scan_dir(path).map(|_| ())
+    } else if is_audio_file(path) {
+        library.index_file(path)
+    } else {
+        Ok(())
+    }
+}
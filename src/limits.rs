@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+use crate::types::{EnqueueError, GlobalQueue, Song};
+
+/**
+ * Limits on how much a single user can queue. Limits set to None are not enforced.
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueLimits {
+    /**
+     * Maximum number of songs in a user's queue
+     * */
+    pub max_songs: Option<usize>,
+
+    /**
+     * Maximum total duration of all songs in a user's queue. Songs without a known duration
+     * don't count towards the total.
+     * */
+    pub max_total_duration: Option<Duration>,
+
+    /**
+     * Maximum duration of a single song. Songs without a known duration are allowed.
+     * */
+    pub max_song_duration: Option<Duration>,
+
+    /**
+     * Maximum number of submissions a user can make within submission_window
+     * */
+    pub max_submissions: Option<usize>,
+
+    /**
+     * Time window used for rate limiting submissions
+     * */
+    pub submission_window: Duration,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            max_songs: Some(25),
+            max_total_duration: Some(Duration::from_secs(2 * 60 * 60)),
+            max_song_duration: Some(Duration::from_secs(20 * 60)),
+            max_submissions: Some(10),
+            submission_window: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl GlobalQueue {
+    /**
+     * Checks whether {user_id} may submit {song} without going over any of the queue limits
+     * */
+    pub fn check_limits(&self, user_id: &str, song: &Song) -> Result<(), EnqueueError> {
+        self.check_rate_limit(user_id)?;
+        self.check_song_limits(user_id, song)
+    }
+
+    /**
+     * Checks whether {user_id} has made too many submissions recently
+     * */
+    pub fn check_rate_limit(&self, user_id: &str) -> Result<(), EnqueueError> {
+        let limits = &self.limits;
+
+        if let (Some(max), Some(window)) = (limits.max_submissions, self.submissions.get(user_id)) {
+            let now = SystemTime::now();
+            let recent = window
+                .iter()
+                .filter(|t| now.duration_since(**t).unwrap_or_default() < limits.submission_window)
+                .collect::<Vec<_>>();
+            if recent.len() >= max {
+                // The oldest submission in the window has to expire before another is allowed
+                let oldest = recent[recent.len() - max];
+                let elapsed = now.duration_since(*oldest).unwrap_or_default();
+                return Err(EnqueueError::RateLimited {
+                    retry_after: limits.submission_window.saturating_sub(elapsed),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Checks whether {song} fits in {user_id}'s queue, ignoring the rate limit
+     * */
+    pub fn check_song_limits(&self, user_id: &str, song: &Song) -> Result<(), EnqueueError> {
+        let limits = &self.limits;
+
+        if let (Some(max), Some(duration)) = (limits.max_song_duration, song.duration()) {
+            if duration > max {
+                return Err(EnqueueError::SongTooLong { duration, max });
+            }
+        }
+
+        let queued = self.users.iter().find(|u| u.user_id == user_id);
+
+        if let Some(max) = limits.max_songs {
+            if queued.map_or(0, |u| u.q.len()) >= max {
+                return Err(EnqueueError::QueueFull { max });
+            }
+        }
+
+        if let Some(max) = limits.max_total_duration {
+            let total = queued
+                .map_or(Duration::ZERO, |u| u.q.iter().filter_map(|s| s.duration()).sum())
+                + song.duration().unwrap_or_default();
+            if total > max {
+                return Err(EnqueueError::QueueTooLong { total, max });
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Records a submission from {user_id} for rate limiting, forgetting submissions that have
+     * fallen out of the window
+     * */
+    pub(crate) fn record_submission(&mut self, user_id: &str) {
+        let now = SystemTime::now();
+        let window = self.limits.submission_window;
+        let times = self.submissions.entry(user_id.to_string()).or_default();
+        while times
+            .front()
+            .is_some_and(|t| now.duration_since(*t).unwrap_or_default() >= window)
+        {
+            times.pop_front();
+        }
+        times.push_back(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{SongOrigin, SourceInfo, UserQueue};
+
+    fn ago(secs: u64) -> SystemTime {
+        SystemTime::now() - Duration::from_secs(secs)
+    }
+
+    fn song(secs: Option<f64>) -> Song {
+        let origin = SongOrigin::FileUpload("/music/song.flac".to_string());
+        let mut song = Song::new(origin, String::new());
+        song.source_info = Some(SourceInfo {
+            duration: secs,
+            ..SourceInfo::default()
+        });
+        song
+    }
+
+    fn rate_limited_queue(submitted: &[u64]) -> GlobalQueue {
+        let mut queue = GlobalQueue::new();
+        queue.limits.max_submissions = Some(2);
+        queue.limits.submission_window = Duration::from_secs(60);
+        queue
+            .submissions
+            .insert("a".to_string(), submitted.iter().map(|secs| ago(*secs)).collect());
+        queue
+    }
+
+    #[test]
+    fn submissions_are_limited_within_the_window() {
+        // Only the submissions in the last minute count
+        assert!(rate_limited_queue(&[]).check_rate_limit("a").is_ok());
+        assert!(rate_limited_queue(&[90, 80, 10]).check_rate_limit("a").is_ok());
+        assert!(rate_limited_queue(&[30, 10]).check_rate_limit("b").is_ok());
+
+        match rate_limited_queue(&[90, 30, 10]).check_rate_limit("a") {
+            // Once the submission 30s ago leaves the window
+            Err(EnqueueError::RateLimited { retry_after }) => {
+                let secs = retry_after.as_secs_f64();
+                assert!((29.0..=30.0).contains(&secs), "retry after {}s", secs);
+            }
+            other => panic!("submission wasn't rate limited: {:?}", other),
+        }
+
+        let mut queue = rate_limited_queue(&[30, 10]);
+        queue.limits.max_submissions = None;
+        assert!(queue.check_rate_limit("a").is_ok());
+    }
+
+    #[test]
+    fn old_submissions_are_forgotten() {
+        let mut queue = rate_limited_queue(&[120, 90, 10]);
+        queue.record_submission("a");
+        assert_eq!(queue.submissions["a"].len(), 2);
+        assert!(queue.check_rate_limit("a").is_err());
+
+        // Submissions count however they are queued
+        let mut queue = rate_limited_queue(&[10]);
+        queue.enqueue("a", song(None)).unwrap();
+        assert!(matches!(
+            queue.enqueue("a", song(None)),
+            Err(EnqueueError::RateLimited { .. })
+        ));
+    }
+
+    #[test]
+    fn songs_must_fit_in_the_queue() {
+        let mut queue = GlobalQueue::new();
+        queue.limits.max_songs = Some(2);
+        queue.limits.max_song_duration = Some(Duration::from_secs(600));
+        queue.limits.max_total_duration = Some(Duration::from_secs(900));
+
+        assert!(matches!(
+            queue.check_song_limits("a", &song(Some(601.0))),
+            Err(EnqueueError::SongTooLong { .. })
+        ));
+        // Songs of unknown length are let through
+        assert!(queue.check_song_limits("a", &song(None)).is_ok());
+
+        let mut user = UserQueue::new("a".to_string());
+        user.enqueue(song(Some(500.0)));
+        queue.users.push_back(user);
+        assert!(matches!(
+            queue.check_song_limits("a", &song(Some(401.0))),
+            Err(EnqueueError::QueueTooLong { .. })
+        ));
+        assert!(queue.check_song_limits("a", &song(Some(400.0))).is_ok());
+
+        queue.users[0].enqueue(song(None));
+        assert!(matches!(
+            queue.check_song_limits("a", &song(None)),
+            Err(EnqueueError::QueueFull { max: 2 })
+        ));
+        // Other users' queues don't count
+        assert!(queue.check_song_limits("b", &song(Some(400.0))).is_ok());
+    }
+}
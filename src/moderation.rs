@@ -0,0 +1,347 @@
+use log::{log, Level};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+
+use crate::sources::youtube::video_id;
+use crate::types::{GlobalQueue, Notification, Song, SongOrigin};
+
+/**
+ * Blocklists and content filters applied to songs when they are submitted and again once their
+ * metadata has been resolved. Artist names, keywords and channel names are matched
+ * case-insensitively.
+ * */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModerationConfig {
+    /**
+     * Blocked YouTube video IDs
+     * */
+    pub video_ids: HashSet<String>,
+
+    /**
+     * Blocked channels, by channel ID or name
+     * */
+    pub channels: HashSet<String>,
+
+    /**
+     * Blocked AcoustID track IDs
+     * */
+    pub acoustids: HashSet<String>,
+
+    /**
+     * Blocked MusicBrainz recording IDs
+     * */
+    pub recordings: HashSet<String>,
+
+    /**
+     * Blocked artists, by MusicBrainz ID or name
+     * */
+    pub artists: HashSet<String>,
+
+    /**
+     * Keywords that may not appear in a song or upload title
+     * */
+    pub title_keywords: Vec<String>,
+
+    /**
+     * Whether explicit songs are filtered
+     * */
+    pub filter_explicit: bool,
+
+    /**
+     * Curated list of explicit songs, by YouTube video ID or MusicBrainz recording ID. Only used
+     * when filter_explicit is set.
+     * */
+    pub explicit: HashSet<String>,
+}
+
+impl ModerationConfig {
+    /**
+     * Checks a song against the blocklists and content filters, using whatever information is
+     * currently known about it. Returns the reason the song was rejected, if any.
+     * */
+    pub fn check(&self, song: &Song) -> Result<(), String> {
+        let video_id = youtube_id(song);
+        let info = song.source_info.as_ref();
+        let meta = song.metadata.as_ref();
+
+        if let Some(id) = video_id.as_ref().filter(|id| self.video_ids.contains(*id)) {
+            return Err(format!("video {} is blocked", id));
+        }
+
+        if let Some(info) = info {
+            let blocked_channel = [&info.channel_id, &info.channel]
+                .into_iter()
+                .flatten()
+                .find(|c| contains_ignore_case(&self.channels, c));
+            if let Some(channel) = blocked_channel {
+                return Err(format!("channel {} is blocked", channel));
+            }
+        }
+
+        if let Some(meta) = meta {
+            if let Some(id) = meta.acoustid_id.as_ref().filter(|id| self.acoustids.contains(*id)) {
+                return Err(format!("AcoustID track {} is blocked", id));
+            }
+            if let Some(id) = meta
+                .recording_mbid
+                .as_ref()
+                .filter(|id| self.recordings.contains(*id))
+            {
+                return Err(format!("recording {} is blocked", id));
+            }
+            let blocked_artist = meta.artists.iter().find(|a| {
+                contains_ignore_case(&self.artists, &a.name)
+                    || a.mbid.as_ref().is_some_and(|id| self.artists.contains(id))
+            });
+            if let Some(artist) = blocked_artist {
+                return Err(format!("artist {} is blocked", artist.name));
+            }
+        }
+
+        let titles = [
+            meta.map(|m| &m.title),
+            info.and_then(|i| i.title.as_ref()),
+        ];
+        for title in titles.into_iter().flatten() {
+            let title = title.to_lowercase();
+            if let Some(keyword) = self
+                .title_keywords
+                .iter()
+                .find(|k| title.contains(&k.to_lowercase()))
+            {
+                return Err(format!("title contains blocked keyword \"{}\"", keyword));
+            }
+        }
+
+        if self.filter_explicit && self.is_explicit(song, video_id.as_deref()) {
+            return Err(String::from("explicit songs are not allowed"));
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Checks whether a song is explicit, from the curated list, the source's age restriction or
+     * an "explicit" tag on its recording
+     * */
+    fn is_explicit(&self, song: &Song, video_id: Option<&str>) -> bool {
+        let curated = video_id.is_some_and(|id| self.explicit.contains(id))
+            || song
+                .metadata
+                .as_ref()
+                .and_then(|m| m.recording_mbid.as_ref())
+                .is_some_and(|id| self.explicit.contains(id));
+        let age_restricted = song
+            .source_info
+            .as_ref()
+            .and_then(|i| i.age_limit)
+            .is_some_and(|age| age >= 18);
+        let tagged = song
+            .metadata
+            .as_ref()
+            .is_some_and(|m| m.genres.iter().any(|g| g.eq_ignore_ascii_case("explicit")));
+        curated || age_restricted || tagged
+    }
+}
+
+/**
+ * Gets the YouTube video ID of a song, if it is from YouTube
+ * */
+fn youtube_id(song: &Song) -> Option<String> {
+    match song.playable_origin() {
+        SongOrigin::Youtube(url) => song
+            .source_info
+            .as_ref()
+            .map(|i| i.id.clone())
+            .or_else(|| video_id(url)),
+        _ => None,
+    }
+}
+
+fn contains_ignore_case(set: &HashSet<String>, value: &str) -> bool {
+    set.iter().any(|v| v.eq_ignore_ascii_case(value))
+}
+
+impl GlobalQueue {
+    /**
+     * Checks a song against the moderation rules and ban list
+     * */
+    pub fn moderate(&self, song: &Song) -> Result<(), String> {
+        self.moderation.check(song)?;
+        if self.bans.is_banned(song) {
+            return Err(String::from("song is banned"));
+        }
+        Ok(())
+    }
+
+    /**
+     * Re-checks every queued song against the moderation rules and ban list, removing rejected
+     * songs and notifying their submitters. Should be called after the rules change; songs whose
+     * information is filled in after they are queued are re-checked by update_song. Returns the
+     * removed songs.
+     * */
+    pub fn moderate_queued(&mut self) -> Vec<Song> {
+        let mut rejected: Vec<(Song, String)> = vec![];
+        let mut queues = std::mem::take(&mut self.users);
+        let mut q = std::mem::take(&mut self.q);
+
+        let mut split = |q: &mut VecDeque<Song>| {
+            let mut kept = VecDeque::with_capacity(q.len());
+            for song in q.drain(..) {
+                match self.moderate(&song) {
+                    Ok(()) => kept.push_back(song),
+                    Err(reason) => rejected.push((song, reason)),
+                }
+            }
+            *q = kept;
+        };
+
+        split(&mut q);
+        queues.iter_mut().for_each(|user| split(&mut user.q));
+        self.q = q;
+        self.users = queues;
+
+        rejected
+            .into_iter()
+            .map(|(song, reason)| {
+                log!(Level::Info, "removed song {} from {}: {}", song.id, song.submitter, reason);
+                self.notify(
+                    &song.submitter,
+                    format!("Your song was removed from the queue: {}", reason),
+                );
+                song
+            })
+            .collect()
+    }
+
+    /**
+     * Queues a message to be delivered to a user
+     * */
+    pub fn notify(&mut self, user_id: &str, message: String) {
+        self.notifications.push_back(Notification {
+            user_id: user_id.to_string(),
+            message,
+        });
+    }
+
+    /**
+     * Takes all pending notifications for a user
+     * */
+    pub fn take_notifications(&mut self, user_id: &str) -> Vec<Notification> {
+        let (mine, others): (Vec<_>, Vec<_>) = self
+            .notifications
+            .drain(..)
+            .partition(|n| n.user_id == user_id);
+        self.notifications = others.into();
+        mine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprint::{ArtistCredit, SongMetadata};
+    use crate::types::{EnqueueError, SourceInfo};
+
+    fn youtube(id: &str, title: &str, channel: &str) -> Song {
+        let link = format!("https://www.youtube.com/watch?v={id}");
+        let mut song = Song::new(SongOrigin::Youtube(link), "dj".to_string());
+        song.source_info = Some(SourceInfo {
+            id: id.to_string(),
+            title: Some(title.to_string()),
+            channel: Some(channel.to_string()),
+            ..SourceInfo::default()
+        });
+        song
+    }
+
+    fn by_artist(artist: &str, title: &str) -> Song {
+        let origin = SongOrigin::FileUpload(format!("/music/{title}.flac"));
+        let mut song = Song::new(origin, "dj".to_string());
+        song.metadata = Some(SongMetadata {
+            title: title.to_string(),
+            artist: artist.to_string(),
+            artists: vec![ArtistCredit {
+                name: artist.to_string(),
+                mbid: None,
+                join_phrase: String::new(),
+            }],
+            ..SongMetadata::default()
+        });
+        song
+    }
+
+    fn config() -> ModerationConfig {
+        ModerationConfig {
+            video_ids: HashSet::from(["dQw4w9WgXcQ".to_string()]),
+            channels: HashSet::from(["Blocked Channel".to_string()]),
+            artists: HashSet::from(["blocked artist".to_string()]),
+            title_keywords: vec!["Nightcore".to_string()],
+            ..ModerationConfig::default()
+        }
+    }
+
+    #[test]
+    fn songs_are_checked_against_the_blocklists() {
+        let config = config();
+        let cases = [
+            (youtube("dQw4w9WgXcQ", "Song", "Channel"), false),
+            (youtube("9bZkp7q19f0", "Song", "blocked channel"), false),
+            (youtube("9bZkp7q19f0", "Song (NIGHTCORE remix)", "Channel"), false),
+            (youtube("9bZkp7q19f0", "Song", "Channel"), true),
+            (by_artist("Blocked Artist", "Song"), false),
+            (by_artist("Artist", "nightcore song"), false),
+            (by_artist("Artist", "Song"), true),
+        ];
+        for (song, allowed) in cases {
+            let result = config.check(&song);
+            assert_eq!(result.is_ok(), allowed, "{:?}: {:?}", song.origin, result);
+        }
+    }
+
+    #[test]
+    fn explicit_songs_are_only_filtered_when_asked() {
+        let mut song = youtube("9bZkp7q19f0", "Song", "Channel");
+        song.source_info.as_mut().unwrap().age_limit = Some(18);
+        let mut config = ModerationConfig::default();
+        assert!(config.check(&song).is_ok());
+        config.filter_explicit = true;
+        assert!(config.check(&song).is_err());
+
+        let curated = youtube("dQw4w9WgXcQ", "Song", "Channel");
+        assert!(config.check(&curated).is_ok());
+        config.explicit.insert("dQw4w9WgXcQ".to_string());
+        assert!(config.check(&curated).is_err());
+    }
+
+    #[test]
+    fn blocked_songs_cant_be_queued() {
+        let mut queue = GlobalQueue::new();
+        queue.moderation = config();
+        let result = queue.enqueue("dj", by_artist("Blocked Artist", "Song"));
+        assert!(matches!(result, Err(EnqueueError::Blocked(_))));
+        assert!(queue.enqueue("dj", by_artist("Artist", "Song")).is_ok());
+    }
+
+    #[test]
+    fn queued_songs_are_removed_when_the_rules_change() {
+        let mut queue = GlobalQueue::new();
+        queue.enqueue("dj", by_artist("Artist", "Song")).unwrap();
+        queue.enqueue("dj", by_artist("Other", "Other Song")).unwrap();
+        queue.q.push_back(queue.users[0].q.pop_front().unwrap());
+
+        queue.moderation.artists.insert("artist".to_string());
+        let removed = queue.moderate_queued();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].origin, by_artist("Artist", "Song").origin);
+        assert!(queue.q.is_empty());
+        assert_eq!(queue.users[0].q.len(), 1);
+
+        // The submitter is told, and only them
+        assert!(queue.take_notifications("someone").is_empty());
+        let notifications = queue.take_notifications("dj");
+        assert_eq!(notifications.len(), 1);
+        assert!(notifications[0].message.contains("artist Artist is blocked"));
+        assert!(queue.take_notifications("dj").is_empty());
+    }
+}
@@ -0,0 +1,539 @@
+use anyhow::{anyhow, Error};
+use ebur128::{EbuR128, Mode};
+use lofty::{read_from_path, ItemKey, TaggedFileExt};
+use log::{log, Level};
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::decode::{for_each_chunk, open_file};
+
+/**
+ * Loudness that ReplayGain 2.0 gains are relative to, in LUFS
+ * */
+const REPLAYGAIN_REFERENCE_LUFS: f64 = -18.0;
+
+/**
+ * Configuration for loudness normalization during playback
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoudnessConfig {
+    /**
+     * Whether songs are normalized at all
+     * */
+    pub enabled: bool,
+
+    /**
+     * Integrated loudness songs are normalized towards, in LUFS
+     * */
+    pub target_lufs: f64,
+
+    /**
+     * Ceiling no sample may exceed after normalization, in dBTP
+     * */
+    pub true_peak_limit: f64,
+
+    /**
+     * Maximum gain applied to quiet songs, in dB
+     * */
+    pub max_gain: f64,
+}
+
+impl Default for LoudnessConfig {
+    fn default() -> Self {
+        LoudnessConfig {
+            enabled: true,
+            target_lufs: -16.0,
+            true_peak_limit: -1.0,
+            max_gain: 12.0,
+        }
+    }
+}
+
+/**
+ * Where a song's loudness information came from
+ * */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoudnessSource {
+    /**
+     * Measured from the decoded audio
+     * */
+    Analyzed,
+
+    /**
+     * Read from ReplayGain tags in the file
+     * */
+    ReplayGain,
+}
+
+/**
+ * Loudness of a song, measured according to EBU R128
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoudnessInfo {
+    /**
+     * Integrated loudness in LUFS
+     * */
+    pub integrated: f64,
+
+    /**
+     * Highest true peak across all channels in dBTP, if known. ReplayGain tags don't always
+     * include it.
+     * */
+    pub true_peak: Option<f64>,
+
+    pub source: LoudnessSource,
+}
+
+impl LoudnessInfo {
+    /**
+     * Linear gain to apply to reach the configured target without exceeding the true peak limit
+     * or maximum gain. Without a known peak the gain is applied in full, leaving any peaks it
+     * pushes over the limit to the limiter.
+     * */
+    pub fn gain(&self, config: &LoudnessConfig) -> f32 {
+        let mut gain_db = (config.target_lufs - self.integrated).min(config.max_gain);
+        if let Some(peak) = self.true_peak {
+            gain_db = gain_db.min(config.true_peak_limit - peak);
+        }
+        db_to_linear(gain_db) as f32
+    }
+}
+
+/**
+ * Gets the loudness of an audio file, from its ReplayGain tags if present or by analyzing it
+ * otherwise
+ * */
+pub fn analyze_file(path: &str) -> Result<LoudnessInfo, Error> {
+    match read_replaygain(path) {
+        Some(info) => {
+            log!(Level::Debug, "using ReplayGain tags for {}", path);
+            Ok(info)
+        }
+        None => analyze_source(open_file(path)?),
+    }
+}
+
+/**
+ * Measures the integrated loudness and true peak of decoded audio
+ * */
+pub fn analyze_source<S: Source<Item = f32>>(source: S) -> Result<LoudnessInfo, Error> {
+    let mut meter = LoudnessMeter::new(source.channels(), source.sample_rate())?;
+    for_each_chunk(source, |frames| meter.add(frames))?;
+    meter.finish()
+}
+
+/**
+ * Measures the loudness of audio given to it a chunk at a time, for measuring it while the same
+ * audio is analyzed in other ways
+ * */
+pub struct LoudnessMeter {
+    meter: EbuR128,
+    channels: u32,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: u16, sample_rate: u32) -> Result<Self, Error> {
+        let channels = channels as u32;
+        let meter = EbuR128::new(channels, sample_rate, Mode::I | Mode::TRUE_PEAK)?;
+        Ok(LoudnessMeter { meter, channels })
+    }
+
+    /**
+     * Adds the next whole frames of audio, interleaved
+     * */
+    pub fn add(&mut self, frames: &[f32]) -> Result<(), Error> {
+        Ok(self.meter.add_frames_f32(frames)?)
+    }
+
+    /**
+     * Gets the loudness of all the audio added
+     * */
+    pub fn finish(&self) -> Result<LoudnessInfo, Error> {
+        let peak = (0..self.channels)
+            .map(|c| self.meter.true_peak(c))
+            .collect::<Result<Vec<f64>, _>>()?
+            .into_iter()
+            .fold(0.0, f64::max);
+
+        let integrated = self.meter.loudness_global()?;
+        if !integrated.is_finite() {
+            return Err(anyhow!("Audio is silent"));
+        }
+
+        Ok(LoudnessInfo {
+            integrated,
+            true_peak: Some(linear_to_db(peak)),
+            source: LoudnessSource::Analyzed,
+        })
+    }
+}
+
+/**
+ * Reads the track loudness from ReplayGain tags, if the file has them
+ * */
+pub fn read_replaygain(path: &str) -> Option<LoudnessInfo> {
+    let file = read_from_path(path).ok()?;
+    let tag = file.primary_tag().or_else(|| file.first_tag())?;
+
+    let gain = tag
+        .get_string(&ItemKey::ReplayGainTrackGain)?
+        .trim()
+        .trim_end_matches("dB")
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+    let peak = tag
+        .get_string(&ItemKey::ReplayGainTrackPeak)
+        .and_then(|p| p.trim().parse::<f64>().ok());
+
+    Some(LoudnessInfo {
+        integrated: REPLAYGAIN_REFERENCE_LUFS - gain,
+        true_peak: peak.map(linear_to_db),
+        source: LoudnessSource::ReplayGain,
+    })
+}
+
+fn db_to_linear(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+fn linear_to_db(linear: f64) -> f64 {
+    20.0 * linear.max(1e-9).log10()
+}
+
+/**
+ * How far ahead the limiter looks for peaks, and so how long it takes to turn the gain down
+ * before one
+ * */
+const LIMITER_LOOKAHEAD: Duration = Duration::from_millis(5);
+
+/**
+ * How long the limiter takes to return to full gain after a peak
+ * */
+const LIMITER_RELEASE: Duration = Duration::from_millis(100);
+
+/**
+ * Source that applies a fixed normalization gain, with a look-ahead limiter keeping any peaks
+ * that would still exceed the ceiling under it. Output is delayed by the limiter's look-ahead.
+ * */
+pub struct Normalize<S: Source<Item = f32>> {
+    source: S,
+    gain: f32,
+
+    /**
+     * Gain to move towards, for sources whose gain is set while they play
+     * */
+    target: Option<Arc<AtomicU32>>,
+    ramp: f32,
+    limiter: Limiter,
+
+    /**
+     * Limited samples of the frame being output
+     * */
+    output: VecDeque<f32>,
+}
+
+/**
+ * Sets the gain of a Normalize source created with Normalize::deferred while it plays
+ * */
+#[derive(Clone)]
+pub struct GainHandle(Arc<AtomicU32>);
+
+impl GainHandle {
+    pub fn set(&self, gain: f32) {
+        self.0.store(gain.to_bits(), Ordering::Relaxed);
+    }
+}
+
+impl<S: Source<Item = f32>> Normalize<S> {
+    /**
+     * Wraps {source}, applying {gain} and limiting output to {ceiling} dBTP
+     * */
+    pub fn new(source: S, gain: f32, ceiling: f64) -> Self {
+        let rate = source.sample_rate() as f32 * source.channels() as f32;
+        let limiter = Limiter::new(
+            source.channels(),
+            source.sample_rate(),
+            db_to_linear(ceiling) as f32,
+        );
+        Normalize {
+            source,
+            gain,
+            target: None,
+            // A change in gain of 1.0 takes a second
+            ramp: 1.0 / rate,
+            limiter,
+            output: VecDeque::new(),
+        }
+    }
+
+    /**
+     * Wraps {source} without changing its gain until one is set through the returned handle,
+     * for songs that can only be measured once they finish downloading. The gain moves to the new
+     * value over about a second rather than jumping to it.
+     * */
+    pub fn deferred(source: S, ceiling: f64) -> (Self, GainHandle) {
+        let target = Arc::new(AtomicU32::new(1.0f32.to_bits()));
+        let mut normalize = Normalize::new(source, 1.0, ceiling);
+        normalize.target = Some(target.clone());
+        (normalize, GainHandle(target))
+    }
+
+    fn follow_target(&mut self) {
+        if let Some(target) = &self.target {
+            let target = f32::from_bits(target.load(Ordering::Relaxed));
+            self.gain += (target - self.gain).clamp(-self.ramp, self.ramp);
+        }
+    }
+
+    /**
+     * Reads the next frame from the source, with the gain applied. Returns None at the end of
+     * the source.
+     * */
+    fn next_frame(&mut self) -> Option<Vec<f32>> {
+        let mut frame = Vec::with_capacity(self.limiter.channels);
+        while frame.len() < self.limiter.channels {
+            self.follow_target();
+            match self.source.next() {
+                Some(sample) => frame.push(sample * self.gain),
+                None => break,
+            }
+        }
+        if frame.is_empty() {
+            return None;
+        }
+        // A partial last frame is padded so the output stays aligned to channels
+        frame.resize(self.limiter.channels, 0.0);
+        Some(frame)
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Normalize<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        while self.output.is_empty() {
+            let frame = match self.limiter.padding {
+                0 => self.next_frame(),
+                _ => None,
+            };
+            let frame = frame.or_else(|| self.limiter.pad())?;
+            self.output.extend(self.limiter.push(frame).into_iter().flatten());
+        }
+        self.output.pop_front()
+    }
+}
+
+/**
+ * Peak limiter that looks ahead for samples over its ceiling. Frames are delayed so the gain can
+ * be lowered smoothly over the look-ahead before a peak arrives, instead of clipping it, and is
+ * then raised again over the release time.
+ *
+ * The gain needed by each frame is the lowest needed by it and the look-ahead frames after it.
+ * Averaging that over the look-ahead gives a gain that ramps down in time for every peak without
+ * ever being higher than the peak needs.
+ * */
+struct Limiter {
+    channels: usize,
+    ceiling: f32,
+    lookahead: usize,
+
+    /**
+     * Samples of the frames waiting to be output
+     * */
+    delay: VecDeque<f32>,
+
+    /**
+     * Candidates for the lowest gain needed in the look-ahead, as the index of the frame needing
+     * it and the gain, in increasing order of both
+     * */
+    needed: VecDeque<(u64, f32)>,
+
+    /**
+     * The lowest gain needed in the look-ahead of each of the last frames, and their sum
+     * */
+    window: VecDeque<f32>,
+    sum: f64,
+
+    envelope: f32,
+    release: f32,
+
+    /**
+     * Number of frames processed, and how many of them were padding added at the end
+     * */
+    frames: u64,
+    padding: usize,
+}
+
+impl Limiter {
+    fn new(channels: u16, sample_rate: u32, ceiling: f32) -> Self {
+        let lookahead =
+            ((LIMITER_LOOKAHEAD.as_secs_f64() * sample_rate as f64).round() as usize).max(1);
+        Limiter {
+            channels: channels.max(1) as usize,
+            ceiling,
+            lookahead,
+            delay: VecDeque::new(),
+            needed: VecDeque::new(),
+            window: vec![1.0; lookahead + 1].into(),
+            sum: (lookahead + 1) as f64,
+            envelope: 1.0,
+            release: 1.0 / (LIMITER_RELEASE.as_secs_f32() * sample_rate as f32),
+            frames: 0,
+            padding: 0,
+        }
+    }
+
+    /**
+     * Silence to feed in once the input has ended, pushing out the frames still in the delay.
+     * Returns None once every frame has been pushed out.
+     * */
+    fn pad(&mut self) -> Option<Vec<f32>> {
+        if self.padding == self.lookahead {
+            return None;
+        }
+        self.padding += 1;
+        Some(vec![0.0; self.channels])
+    }
+
+    /**
+     * Takes the next frame of input and returns the frame leaving the delay, if it's full yet
+     * */
+    fn push(&mut self, input: Vec<f32>) -> Option<Vec<f32>> {
+        let peak = input.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let needed = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+        let index = self.frames;
+        self.frames += 1;
+        self.delay.extend(input);
+
+        while self.needed.back().is_some_and(|(_, gain)| *gain >= needed) {
+            self.needed.pop_back();
+        }
+        self.needed.push_back((index, needed));
+
+        if index < self.lookahead as u64 {
+            return None;
+        }
+
+        // The frame leaving the delay, and the lowest gain needed by it and the frames after it
+        let out = index - self.lookahead as u64;
+        while self.needed.front().is_some_and(|(i, _)| *i < out) {
+            self.needed.pop_front();
+        }
+        let lowest = self.needed.front().map_or(1.0, |(_, gain)| *gain);
+
+        self.window.push_back(lowest);
+        self.sum += lowest as f64;
+        self.sum -= self.window.pop_front().unwrap_or(1.0) as f64;
+        let smoothed = (self.sum / self.window.len() as f64) as f32;
+        self.envelope = smoothed.min(self.envelope + self.release);
+
+        let ceiling = self.ceiling;
+        let envelope = self.envelope;
+        Some(
+            self.delay
+                .drain(..self.channels)
+                // Rounding in the average can leave the odd sample a hair over the ceiling
+                .map(|sample| (sample * envelope).clamp(-ceiling, ceiling))
+                .collect(),
+        )
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Normalize<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        let buffered = self.limiter.delay.len() + self.output.len();
+        self.source.current_frame_len().map(|len| len + buffered)
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn loudness(integrated: f64, true_peak: Option<f64>) -> LoudnessInfo {
+        LoudnessInfo {
+            integrated,
+            true_peak,
+            source: LoudnessSource::ReplayGain,
+        }
+    }
+
+    #[test]
+    fn gains() {
+        let config = LoudnessConfig::default();
+        let cases = [
+            // Quiet songs are boosted towards the target
+            (loudness(-20.0, Some(-10.0)), 4.0),
+            // ... but no further than the peak allows
+            (loudness(-20.0, Some(-3.0)), 2.0),
+            // ... or the maximum gain
+            (loudness(-40.0, Some(-30.0)), 12.0),
+            // Loud songs are turned down
+            (loudness(-10.0, Some(0.0)), -6.0),
+            // Without a peak, the limiter is left to catch the peaks
+            (loudness(-20.0, None), 4.0),
+        ];
+        for (info, expected_db) in cases {
+            let gain_db = linear_to_db(info.gain(&config) as f64);
+            assert!((gain_db - expected_db).abs() < 1e-4, "{:?} got {} dB", info, gain_db);
+        }
+    }
+
+    #[test]
+    fn limits_peaks_without_clipping() {
+        let rate = 48000;
+        let (peak_at, len) = (rate as usize / 2, rate as usize);
+        let mut input = vec![0.5f32; len];
+        input[peak_at] = 2.0;
+        input[peak_at + 1] = -2.0;
+
+        let source = SamplesBuffer::new(1, rate, input.clone());
+        let output = Normalize::new(source, 1.0, 0.0).collect::<Vec<_>>();
+        assert_eq!(output.len(), len, "samples were lost or added");
+
+        let gains = output.iter().zip(&input).map(|(o, i)| o / i).collect::<Vec<_>>();
+        assert!(gains.iter().all(|gain| *gain <= 1.0 + 1e-6));
+        assert!((output[peak_at] - 1.0).abs() < 1e-4, "peak limited to {}", output[peak_at]);
+        assert!((output[peak_at + 1] + 1.0).abs() < 1e-4);
+
+        // The gain is lowered gradually before the peak rather than clipping it
+        let lookahead = (LIMITER_LOOKAHEAD.as_secs_f64() * rate as f64) as usize;
+        let step = 0.5 / lookahead as f32 + 1e-4;
+        for i in peak_at - 2 * lookahead..peak_at {
+            assert!((gains[i] - gains[i + 1]).abs() <= step, "gain jumps at {}", i);
+        }
+        assert_eq!(gains[peak_at - 2 * lookahead], 1.0);
+        // ... and recovers after it
+        assert!(gains[peak_at + 2..].iter().all(|gain| *gain > 0.49));
+        assert_eq!(gains[len - 1], 1.0);
+    }
+
+    #[test]
+    fn keeps_frames_whole() {
+        let source = SamplesBuffer::new(2, 8000, vec![0.1f32; 7]);
+        let output = Normalize::new(source, 2.0, 0.0).collect::<Vec<_>>();
+        assert_eq!(output, vec![0.2; 7].into_iter().chain([0.0]).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,393 @@
+use anyhow::{anyhow, Error};
+use log::{log, Level};
+use rodio::{OutputStreamHandle, Sink, Source};
+use serde::{Deserialize, Serialize};
+use std::thread;
+use std::time::Duration;
+
+use crate::decode::{for_each_chunk, open_file};
+use crate::sources::growing::Download;
+use crate::types::Song;
+
+/**
+ * Internal module for measuring song loudness and normalizing it during playback
+ * */
+pub mod loudness;
+
+/**
+ * Internal module for detecting and trimming silence at the start and end of songs
+ * */
+pub mod silence;
+
+/**
+ * Internal module for crossfading and gapless transitions between songs
+ * */
+pub mod transition;
+
+use loudness::{read_replaygain, GainHandle, LoudnessConfig, LoudnessInfo, LoudnessMeter, Normalize};
+use silence::{DeferredEnd, EndHandle, SilenceConfig, SilenceDetector, SilenceInfo, TrimPoints};
+use transition::{TrackInfo, TransitionConfig, TransitionEngine, TransitionHandle};
+
+/**
+ * Configuration for all audio processing done by the player
+ * */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlayerConfig {
+    pub transitions: TransitionConfig,
+    pub loudness: LoudnessConfig,
+    pub silence: SilenceConfig,
+}
+
+/**
+ * Playback progress of a song
+ * */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    /**
+     * ID of the song playing
+     * */
+    pub song_id: u64,
+
+    /**
+     * How far into the song playback is
+     * */
+    pub elapsed: Duration,
+
+    /**
+     * Total length of the song, if known
+     * */
+    pub total: Option<Duration>,
+}
+
+/**
+ * Plays songs on an audio output, normalizing their loudness and transitioning between them
+ * according to the player config
+ * */
+pub struct Player {
+    sink: Sink,
+    transitions: TransitionHandle,
+    loudness: LoudnessConfig,
+    silence: SilenceConfig,
+}
+
+impl Player {
+    /**
+     * Creates a new player on the given audio output
+     * */
+    pub fn new(output: &OutputStreamHandle, config: PlayerConfig) -> Result<Self, Error> {
+        let sink = Sink::try_new(output)?;
+        let (engine, transitions) = TransitionEngine::new(config.transitions);
+        sink.append(engine);
+        Ok(Player {
+            sink,
+            transitions,
+            loudness: config.loudness,
+            silence: config.silence,
+        })
+    }
+
+    /**
+     * Fetches a song's audio and queues it to play after everything already queued. The song's
+     * silence and loudness are measured in the background if they haven't been already. Live
+     * streams aren't connected to until they start playing.
+     * */
+    pub fn queue(&self, song: &mut Song) -> Result<(), Error> {
+        if song.is_live() {
+            log!(Level::Debug, "queueing stream {} for playback", song.id);
+            let mut stream = song.clone();
+            self.transitions
+                .queue_opener(Box::new(move || stream.as_stream()), TrackInfo::from_song(song));
+            return Ok(());
+        }
+
+        let source = song.as_stream()?;
+        let (source, info) = self.prepare(song, source, None);
+        log!(Level::Debug, "queueing song {} for playback", song.id);
+        self.transitions.queue(source, info);
+        Ok(())
+    }
+
+    /**
+     * Seeks the current song to {position}. The song must be the one currently playing. Positions
+     * outside the part of the song that is played, after trimming, are moved to its start or end.
+     * Seeking past what has been downloaded waits for the download to catch up.
+     * */
+    pub fn seek(&self, song: &mut Song, position: Duration) -> Result<(), Error> {
+        match self.progress() {
+            Some(progress) if progress.song_id == song.id => {}
+            _ => return Err(anyhow!("Song {} is not currently playing", song.id)),
+        }
+        if let Some(total) = song.duration() {
+            if position >= total {
+                return Err(anyhow!("Cannot seek past the end of the song"));
+            }
+        }
+        let position = self.trim(song).clamp(position);
+
+        let source = song.open_cached()?;
+        let (source, info) = self.prepare(song, source, Some(position));
+        log!(Level::Debug, "seeking song {} to {:?}", song.id, position);
+        self.transitions.replace(source, info);
+        Ok(())
+    }
+
+    /**
+     * Gets the progress of the song currently playing
+     * */
+    pub fn progress(&self) -> Option<Progress> {
+        let (info, elapsed) = self.transitions.position()?;
+        Some(Progress {
+            song_id: info.song_id,
+            elapsed,
+            total: info.total,
+        })
+    }
+
+    /**
+     * Applies trimming and loudness normalization to a song's source. If {seek} is set the
+     * source starts there instead of at the song's start point.
+     *
+     * Songs whose silence or loudness hasn't been measured yet start at their manual start point
+     * and unity gain, so queueing them doesn't wait for their audio to be decoded. They are
+     * measured on another thread, once they finish downloading, and their trailing silence and
+     * loudness are applied from then on. The results aren't stored on the song, which is
+     * measured again if it is prepared again.
+     * */
+    fn prepare(
+        &self,
+        song: &mut Song,
+        mut source: Box<dyn Source<Item = f32> + Send>,
+        seek: Option<Duration>,
+    ) -> (Box<dyn Source<Item = f32> + Send>, TrackInfo) {
+        let mut info = TrackInfo::from_song(song);
+        info.total = info.total.or_else(|| source.total_duration());
+
+        // Streams have no file to analyze, and nothing to trim or seek
+        if info.live {
+            return (source, info);
+        }
+
+        let trim = self.trim(song);
+        let start = seek.or(trim.start).unwrap_or_default();
+        if !start.is_zero() {
+            log!(Level::Debug, "starting song {} at {:?}", song.id, start);
+            source = Box::new(source.skip_duration(start));
+        }
+        let mut deferred_end = None;
+        match trim.end {
+            Some(end) => {
+                log!(Level::Debug, "ending song {} at {:?}", song.id, end);
+                source = Box::new(source.take_duration(end.saturating_sub(start)));
+            }
+            None if self.silence.enabled && song.silence.is_none() => {
+                let (trimmed, handle) = DeferredEnd::new(source, start);
+                source = Box::new(trimmed);
+                deferred_end = Some(handle);
+            }
+            None => {}
+        }
+        info.offset = start;
+
+        let mut deferred_gain = None;
+        if let (true, Some(loudness)) = (self.loudness.enabled, &song.loudness) {
+            let gain = loudness.gain(&self.loudness);
+            log!(
+                Level::Debug,
+                "normalizing song {} from {:.1} LUFS with gain {:.2}",
+                song.id,
+                loudness.integrated,
+                gain
+            );
+            source = Box::new(Normalize::new(source, gain, self.loudness.true_peak_limit));
+        } else if self.loudness.enabled {
+            let (normalized, handle) = Normalize::deferred(source, self.loudness.true_peak_limit);
+            source = Box::new(normalized);
+            deferred_gain = Some(handle);
+        }
+
+        if deferred_end.is_some() || deferred_gain.is_some() {
+            match song.audio_path() {
+                Some(path) => {
+                    log!(Level::Debug, "measuring song {} in the background", song.id);
+                    let download = song.downloading().cloned();
+                    self.measure_later(song.id, path, download, deferred_end, deferred_gain);
+                }
+                None => log!(Level::Warn, "song {} has no file to measure", song.id),
+            }
+        }
+
+        (source, info)
+    }
+
+    /**
+     * Part of {song} that is played, with as much silence trimmed as the config allows
+     * */
+    fn trim(&self, song: &Song) -> TrimPoints {
+        let trim = song.effective_trim(if self.silence.enabled {
+            self.silence.max_trim
+        } else {
+            Duration::ZERO
+        });
+        TrimPoints {
+            // A manual start point can be after the detected trailing silence, which is ignored
+            end: trim.end.filter(|end| Some(*end) > trim.start),
+            ..trim
+        }
+    }
+
+    /**
+     * Waits for {download}, if there is one, to finish on another thread, then detects the
+     * trailing silence of the file at {path} and measures its loudness, applying them through the
+     * handles
+     * */
+    fn measure_later(
+        &self,
+        song_id: u64,
+        path: &str,
+        download: Option<Download>,
+        end: Option<EndHandle>,
+        gain: Option<GainHandle>,
+    ) {
+        let (silence, loudness) = (self.silence.clone(), self.loudness.clone());
+        let path = path.to_string();
+        thread::spawn(move || {
+            if let Some(Err(e)) = download.map(|download| download.wait()) {
+                log!(Level::Warn, "could not measure song {}: {}", song_id, e);
+                return;
+            }
+            let threshold = end.as_ref().map(|_| silence.threshold);
+            let measured = match measure_file(&path, threshold, gain.is_some()) {
+                Ok(measured) => measured,
+                Err(e) => {
+                    log!(Level::Warn, "could not measure song {}: {}", song_id, e);
+                    return;
+                }
+            };
+
+            if let (Some(end), Some(detected)) = (end, measured.0) {
+                if let Some(at) = detected.trim_points(silence.max_trim).end {
+                    log!(Level::Debug, "ending song {} at {:?}", song_id, at);
+                    end.set(at);
+                }
+            }
+            if let (Some(handle), Some(measured)) = (gain, measured.1) {
+                let gain = measured.gain(&loudness);
+                log!(
+                    Level::Debug,
+                    "normalizing song {} from {:.1} LUFS with gain {:.2}",
+                    song_id,
+                    measured.integrated,
+                    gain
+                );
+                handle.set(gain);
+            }
+        });
+    }
+
+    /**
+     * Fades out the current song and moves on to the next one
+     * */
+    pub fn skip(&self) {
+        self.transitions.skip()
+    }
+
+    /**
+     * Whether a song is currently playing
+     * */
+    pub fn is_playing(&self) -> bool {
+        self.transitions.is_playing()
+    }
+
+    /**
+     * Number of songs waiting to play after the current one
+     * */
+    pub fn queued(&self) -> usize {
+        self.transitions.queued()
+    }
+
+    /**
+     * Sets the output volume, where 1.0 is unchanged
+     * */
+    pub fn set_volume(&self, volume: f32) {
+        self.sink.set_volume(volume)
+    }
+}
+
+/**
+ * Detects the silence in an audio file if {threshold} is set, and measures its loudness if
+ * {loudness} is set, decoding the file at most once for both. Loudness is read from the file's
+ * ReplayGain tags instead where it has them.
+ * */
+fn measure_file(
+    path: &str,
+    threshold: Option<f64>,
+    loudness: bool,
+) -> Result<(Option<SilenceInfo>, Option<LoudnessInfo>), Error> {
+    let replaygain = if loudness { read_replaygain(path) } else { None };
+    if threshold.is_none() && (replaygain.is_some() || !loudness) {
+        return Ok((None, replaygain));
+    }
+
+    let source = open_file(path)?;
+    let (channels, sample_rate) = (source.channels(), source.sample_rate());
+    let mut detector =
+        threshold.map(|threshold| SilenceDetector::new(channels, sample_rate, threshold));
+    let mut meter = match (loudness, &replaygain) {
+        (true, None) => Some(LoudnessMeter::new(channels, sample_rate)?),
+        _ => None,
+    };
+    for_each_chunk(source, |frames| {
+        if let Some(detector) = &mut detector {
+            detector.add(frames);
+        }
+        match &mut meter {
+            Some(meter) => meter.add(frames),
+            None => Ok(()),
+        }
+    })?;
+
+    let silence = detector.map(|detector| detector.finish()).transpose()?;
+    let loudness = match meter {
+        Some(meter) => Some(meter.finish()?),
+        None => replaygain,
+    };
+    Ok((silence, loudness))
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        self.transitions.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loudness::LoudnessSource;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/decode/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn measures_silence_and_loudness_together() {
+        let path = fixture("tone.webm");
+        let (silence, loudness) = measure_file(&path, Some(-50.0), true).unwrap();
+
+        // The fixture is silent for its first quarter second
+        let silence = silence.expect("silence wasn't detected");
+        let start = silence.audio_start.as_secs_f64();
+        assert!((start - 0.25).abs() < 0.01, "audio starts at {}", start);
+        assert!((silence.duration.as_secs_f64() - 1.0).abs() < 0.01);
+
+        let loudness = loudness.expect("loudness wasn't measured");
+        assert_eq!(loudness.source, LoudnessSource::Analyzed);
+        assert!(loudness.true_peak.is_some());
+
+        // Either can be left out
+        let (silence, loudness) = measure_file(&path, None, true).unwrap();
+        assert!(silence.is_none() && loudness.is_some());
+        let (silence, loudness) = measure_file(&path, Some(-50.0), false).unwrap();
+        assert!(silence.is_some() && loudness.is_none());
+    }
+}
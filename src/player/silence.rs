@@ -0,0 +1,331 @@
+use anyhow::{anyhow, Error};
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::decode::{for_each_chunk, open_file};
+
+/**
+ * Configuration for trimming silence from the start and end of songs
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SilenceConfig {
+    /**
+     * Whether leading and trailing silence is trimmed automatically
+     * */
+    pub enabled: bool,
+
+    /**
+     * Level below which audio is considered silent, in dBFS
+     * */
+    pub threshold: f64,
+
+    /**
+     * Maximum amount trimmed from each end of a song
+     * */
+    pub max_trim: Duration,
+}
+
+impl Default for SilenceConfig {
+    fn default() -> Self {
+        SilenceConfig {
+            enabled: true,
+            threshold: -50.0,
+            max_trim: Duration::from_secs(15),
+        }
+    }
+}
+
+/**
+ * Range of a song to play. Either end may be left unset to play from the start or to the end.
+ * */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrimPoints {
+    /**
+     * Position playback starts at
+     * */
+    pub start: Option<Duration>,
+
+    /**
+     * Position playback ends at
+     * */
+    pub end: Option<Duration>,
+}
+
+impl TrimPoints {
+    /**
+     * Moves {position} into the range, if it is before the start or after the end
+     * */
+    pub fn clamp(&self, position: Duration) -> Duration {
+        position
+            .max(self.start.unwrap_or_default())
+            .min(self.end.unwrap_or(Duration::MAX))
+    }
+}
+
+/**
+ * Where the audible part of a song starts and ends
+ * */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SilenceInfo {
+    /**
+     * Position of the first audible sample
+     * */
+    pub audio_start: Duration,
+
+    /**
+     * Position just after the last audible sample
+     * */
+    pub audio_end: Duration,
+
+    /**
+     * Total length of the song including silence
+     * */
+    pub duration: Duration,
+}
+
+impl SilenceInfo {
+    /**
+     * Trim points that remove the detected silence, limited to {max_trim} from each end
+     * */
+    pub fn trim_points(&self, max_trim: Duration) -> TrimPoints {
+        let leading = self.audio_start.min(max_trim);
+        let trailing = self.duration.saturating_sub(self.audio_end).min(max_trim);
+        TrimPoints {
+            start: Some(leading).filter(|d| !d.is_zero()),
+            end: Some(self.duration - trailing).filter(|_| !trailing.is_zero()),
+        }
+    }
+}
+
+/**
+ * Finds the leading and trailing silence of an audio file
+ * */
+pub fn detect_file(path: &str, threshold: f64) -> Result<SilenceInfo, Error> {
+    detect_source(open_file(path)?, threshold)
+}
+
+/**
+ * Finds the leading and trailing silence of decoded audio. A frame is audible if any of its
+ * channels is louder than {threshold} dBFS.
+ * */
+pub fn detect_source<S: Source<Item = f32>>(source: S, threshold: f64) -> Result<SilenceInfo, Error> {
+    let mut detector = SilenceDetector::new(source.channels(), source.sample_rate(), threshold);
+    for_each_chunk(source, |frames| {
+        detector.add(frames);
+        Ok(())
+    })?;
+    detector.finish()
+}
+
+/**
+ * Finds the leading and trailing silence of audio given to it a chunk at a time, for detecting it
+ * while the same audio is analyzed in other ways
+ * */
+pub struct SilenceDetector {
+    channels: usize,
+    rate: f64,
+    threshold: f32,
+    first: Option<usize>,
+    last: usize,
+    frames: usize,
+}
+
+impl SilenceDetector {
+    /**
+     * Creates a detector for audio in which a frame is audible if any of its channels is louder
+     * than {threshold} dBFS
+     * */
+    pub fn new(channels: u16, sample_rate: u32, threshold: f64) -> Self {
+        SilenceDetector {
+            channels: channels.max(1) as usize,
+            rate: sample_rate as f64,
+            threshold: 10f64.powf(threshold / 20.0) as f32,
+            first: None,
+            last: 0,
+            frames: 0,
+        }
+    }
+
+    /**
+     * Adds the next whole frames of audio, interleaved
+     * */
+    pub fn add(&mut self, frames: &[f32]) {
+        for frame in frames.chunks(self.channels) {
+            if frame.iter().any(|sample| sample.abs() > self.threshold) {
+                self.first.get_or_insert(self.frames);
+                self.last = self.frames;
+            }
+            self.frames += 1;
+        }
+    }
+
+    /**
+     * Gets the silence at either end of all the audio added
+     * */
+    pub fn finish(&self) -> Result<SilenceInfo, Error> {
+        if self.frames == 0 {
+            return Err(anyhow!("Cannot detect silence in empty audio"));
+        }
+        let to_duration = |frame: usize| Duration::from_secs_f64(frame as f64 / self.rate);
+
+        Ok(match self.first {
+            Some(first) => SilenceInfo {
+                audio_start: to_duration(first),
+                audio_end: to_duration(self.last + 1),
+                duration: to_duration(self.frames),
+            },
+            // Entirely silent; leave it alone rather than trimming it to nothing
+            None => SilenceInfo {
+                audio_start: Duration::ZERO,
+                audio_end: to_duration(self.frames),
+                duration: to_duration(self.frames),
+            },
+        })
+    }
+}
+
+/**
+ * Source whose end can be set while it plays, for songs whose trailing silence is only detected
+ * once they finish downloading
+ * */
+pub struct DeferredEnd<S: Source<Item = f32>> {
+    source: S,
+    played: u64,
+    end: Arc<AtomicU64>,
+}
+
+/**
+ * Sets where a DeferredEnd source stops
+ * */
+#[derive(Clone)]
+pub struct EndHandle {
+    end: Arc<AtomicU64>,
+    start: Duration,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl<S: Source<Item = f32>> DeferredEnd<S> {
+    /**
+     * Wraps {source}, which starts {start} into the song
+     * */
+    pub fn new(source: S, start: Duration) -> (Self, EndHandle) {
+        let end = Arc::new(AtomicU64::new(u64::MAX));
+        let handle = EndHandle {
+            end: end.clone(),
+            start,
+            sample_rate: source.sample_rate(),
+            channels: source.channels(),
+        };
+        (
+            DeferredEnd {
+                source,
+                played: 0,
+                end,
+            },
+            handle,
+        )
+    }
+}
+
+impl EndHandle {
+    /**
+     * Ends the source at {end}, a position in the song. Ends it straight away if that has already
+     * been played.
+     * */
+    pub fn set(&self, end: Duration) {
+        let frames = (end.saturating_sub(self.start).as_secs_f64() * self.sample_rate as f64) as u64;
+        self.end
+            .store(frames * self.channels as u64, Ordering::Relaxed);
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for DeferredEnd<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.played >= self.end.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.played += 1;
+        self.source.next()
+    }
+}
+
+impl<S: Source<Item = f32>> Source for DeferredEnd<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn positions_are_clamped_to_the_trimmed_song() {
+        let trim = TrimPoints {
+            start: Some(secs(5)),
+            end: Some(secs(100)),
+        };
+        assert_eq!(trim.clamp(secs(0)), secs(5));
+        assert_eq!(trim.clamp(secs(50)), secs(50));
+        assert_eq!(trim.clamp(secs(150)), secs(100));
+
+        let open = TrimPoints::default();
+        assert_eq!(open.clamp(secs(0)), secs(0));
+        assert_eq!(open.clamp(secs(150)), secs(150));
+    }
+
+    #[test]
+    fn trimming_is_limited() {
+        let silence = SilenceInfo {
+            audio_start: secs(2),
+            audio_end: secs(60),
+            duration: secs(120),
+        };
+        assert_eq!(
+            silence.trim_points(secs(15)),
+            TrimPoints {
+                start: Some(secs(2)),
+                end: Some(secs(105)),
+            }
+        );
+        assert_eq!(silence.trim_points(Duration::ZERO), TrimPoints::default());
+    }
+
+    #[test]
+    fn deferred_ends_stop_the_source() {
+        let source = SamplesBuffer::new(2, 10, vec![0.5f32; 200]);
+        let (trimmed, handle) = DeferredEnd::new(source, secs(2));
+        // Set to a second into the song, after the two seconds it starts at
+        handle.set(secs(3));
+        assert_eq!(trimmed.count(), 20);
+
+        // An end that has already passed stops it straight away
+        let source = SamplesBuffer::new(2, 10, vec![0.5f32; 200]);
+        let (trimmed, handle) = DeferredEnd::new(source, secs(5));
+        handle.set(secs(3));
+        assert_eq!(trimmed.count(), 0);
+    }
+}
@@ -0,0 +1,689 @@
+use anyhow::Error;
+use log::{log, Level};
+use rodio::source::UniformSourceIterator;
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::types::Song;
+
+/**
+ * Number of samples between checks for new commands from the TransitionHandle
+ * */
+const COMMAND_POLL_INTERVAL: usize = 512;
+
+/**
+ * Number of samples decoded at a time by a deck's decoder thread
+ * */
+const CHUNK_SIZE: usize = 4096;
+
+/**
+ * Number of decoded chunks a deck's decoder thread can get ahead of the deck's own buffer
+ * */
+const DECODED_CHUNKS: usize = 4;
+
+/**
+ * Smallest number of samples a deck keeps decoded ahead of playback, so short stalls in decoding
+ * don't interrupt it even with crossfading disabled
+ * */
+const MIN_LOOKAHEAD: usize = CHUNK_SIZE * 4;
+
+/**
+ * Length of the fade used when replacing the current track, such as after seeking, to avoid
+ * clicks
+ * */
+const REPLACE_FADE: Duration = Duration::from_millis(30);
+
+/**
+ * Configuration for transitions between songs
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransitionConfig {
+    /**
+     * How long the end of one song overlaps the start of the next. Zero disables crossfading.
+     * */
+    pub crossfade: Duration,
+
+    /**
+     * How long a skipped song takes to fade out
+     * */
+    pub skip_fade: Duration,
+
+    /**
+     * Whether consecutive tracks from the same album play back to back without crossfading
+     * */
+    pub gapless_albums: bool,
+
+    /**
+     * Number of channels output by the engine
+     * */
+    pub channels: u16,
+
+    /**
+     * Sample rate output by the engine
+     * */
+    pub sample_rate: u32,
+}
+
+impl Default for TransitionConfig {
+    fn default() -> Self {
+        TransitionConfig {
+            crossfade: Duration::from_secs(4),
+            skip_fade: Duration::from_millis(750),
+            gapless_albums: true,
+            channels: 2,
+            sample_rate: 44100,
+        }
+    }
+}
+
+/**
+ * Information about a track needed to decide how to transition into and out of it, and to report
+ * its progress
+ * */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackInfo {
+    pub song_id: u64,
+    pub release_mbid: Option<String>,
+    pub disc_number: Option<u32>,
+    pub track_number: Option<u32>,
+
+    /**
+     * Position in the song the source starts at
+     * */
+    pub offset: Duration,
+
+    /**
+     * Total length of the song, if known
+     * */
+    pub total: Option<Duration>,
+
+    /**
+     * Whether the track is a live stream, which never ends on its own and
+     * is cut off as soon as another track is queued
+     * */
+    pub live: bool,
+}
+
+impl TrackInfo {
+    /**
+     * Gets the transition information of a song from its metadata
+     * */
+    pub fn from_song(song: &Song) -> Self {
+        let meta = song.metadata.as_ref();
+        TrackInfo {
+            song_id: song.id,
+            release_mbid: meta.and_then(|m| m.release_mbid.clone()),
+            disc_number: meta.and_then(|m| m.disc_number),
+            track_number: meta.and_then(|m| m.track_number),
+            offset: Duration::ZERO,
+            total: song.duration(),
+            live: song.is_live(),
+        }
+    }
+
+    /**
+     * Whether {next} directly follows this track on the same release
+     * */
+    pub fn is_followed_by(&self, next: &TrackInfo) -> bool {
+        match (&self.release_mbid, &next.release_mbid, self.track_number, next.track_number) {
+            (Some(a), Some(b), Some(ta), Some(tb)) if a == b => {
+                let disc = self.disc_number.unwrap_or(1);
+                let next_disc = next.disc_number.unwrap_or(1);
+                (disc == next_disc && tb == ta + 1) || (next_disc == disc + 1 && tb == 1)
+            }
+            _ => false,
+        }
+    }
+}
+
+/**
+ * Opens a track's source. Run on the track's decoder thread when the track is started.
+ * */
+pub type Opener = Box<dyn FnOnce() -> Result<Box<dyn Source<Item = f32> + Send>, Error> + Send>;
+
+enum Command {
+    Queue(Deck),
+    Replace(Deck),
+    Skip,
+    ClearQueue,
+    Stop,
+}
+
+/**
+ * Linear gain ramp over a number of samples
+ * */
+struct Fade {
+    pos: usize,
+    len: usize,
+    from: f32,
+    to: f32,
+}
+
+impl Fade {
+    fn fade_in(len: usize) -> Self {
+        Fade {
+            pos: 0,
+            len,
+            from: 0.0,
+            to: 1.0,
+        }
+    }
+
+    /**
+     * Fades out from {from}, so a track that is still fading in doesn't jump to full volume
+     * */
+    fn fade_out(from: f32, len: usize) -> Self {
+        Fade {
+            pos: 0,
+            len,
+            from,
+            to: 0.0,
+        }
+    }
+
+    fn gain(&self) -> f32 {
+        let progress = (self.pos as f32 / self.len.max(1) as f32).min(1.0);
+        self.from + (self.to - self.from) * progress
+    }
+
+    fn is_fade_in(&self) -> bool {
+        self.to > self.from
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.len
+    }
+}
+
+/**
+ * A single track. Its source is decoded on a separate thread, starting as soon as the track is
+ * queued, so slow sources such as partial downloads never block the audio output. Live streams
+ * are only opened once they start playing, since they would otherwise buffer audio, or stall the
+ * station's connection, for as long as they wait in the queue. Keeps up to {lookahead} samples
+ * decoded ahead of playback so the end of the track is known before it is reached.
+ * */
+struct Deck {
+    chunks: Receiver<Vec<f32>>,
+    /**
+     * Starts the decoder thread, if it hasn't been started yet
+     * */
+    pending: Option<Box<dyn FnOnce() + Send>>,
+    buffer: VecDeque<f32>,
+    lookahead: usize,
+    exhausted: bool,
+    info: TrackInfo,
+    fade: Option<Fade>,
+    played: u64,
+}
+
+impl Deck {
+    fn new(open: Opener, info: TrackInfo, lookahead: usize, channels: u16, sample_rate: u32) -> Self {
+        let (tx, rx) = sync_channel(DECODED_CHUNKS);
+        let song_id = info.song_id;
+        let start = move || {
+            thread::spawn(move || match open() {
+                Ok(source) => decode(UniformSourceIterator::new(source, channels, sample_rate), tx),
+                Err(e) => log!(Level::Warn, "could not open song {}: {}", song_id, e),
+            });
+        };
+        let mut deck = Deck {
+            chunks: rx,
+            pending: Some(Box::new(start)),
+            buffer: VecDeque::with_capacity(lookahead + CHUNK_SIZE),
+            lookahead,
+            exhausted: false,
+            info,
+            fade: None,
+            played: 0,
+        };
+        if !deck.info.live {
+            deck.start();
+        }
+        deck
+    }
+
+    /**
+     * Starts opening and decoding the track, if that hasn't already started
+     * */
+    fn start(&mut self) {
+        if let Some(start) = self.pending.take() {
+            start();
+        }
+    }
+
+    /**
+     * Takes whatever the decoder thread has ready, without waiting for more
+     * */
+    fn fill(&mut self) {
+        while !self.exhausted && self.buffer.len() <= self.lookahead {
+            match self.chunks.try_recv() {
+                Ok(chunk) => self.buffer.extend(chunk),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.exhausted = true,
+            }
+        }
+    }
+
+    /**
+     * Current gain of the deck
+     * */
+    fn gain(&self) -> f32 {
+        self.fade.as_ref().map_or(1.0, Fade::gain)
+    }
+
+    /**
+     * Starts fading the deck out over {len} samples from wherever its volume is now
+     * */
+    fn fade_out(&mut self, len: usize) {
+        self.fade = Some(Fade::fade_out(self.gain(), len));
+    }
+
+    /**
+     * Number of samples left in the track, once the end is within the lookahead
+     * */
+    fn remaining(&self) -> Option<usize> {
+        if self.exhausted {
+            Some(self.buffer.len())
+        } else {
+            None
+        }
+    }
+
+    /**
+     * Gets the next sample of the track, or None once it has ended. Outputs silence if the
+     * decoder thread has fallen behind, rather than waiting for it.
+     * */
+    fn next_sample(&mut self) -> Option<f32> {
+        self.fill();
+        let sample = match self.buffer.pop_front() {
+            Some(sample) => sample,
+            None if self.exhausted => return None,
+            None => return Some(0.0),
+        };
+        self.played += 1;
+        match &mut self.fade {
+            Some(fade) => {
+                let gain = fade.gain();
+                fade.pos += 1;
+                if fade.done() && fade.is_fade_in() {
+                    self.fade = None;
+                }
+                Some(sample * gain)
+            }
+            None => Some(sample),
+        }
+    }
+
+    /**
+     * Whether a fade out on this deck has finished
+     * */
+    fn faded_out(&self) -> bool {
+        self.fade
+            .as_ref()
+            .is_some_and(|f| !f.is_fade_in() && f.done())
+    }
+}
+
+/**
+ * Decodes {source} in chunks for a deck until it ends or the deck is dropped
+ * */
+fn decode(
+    mut source: UniformSourceIterator<Box<dyn Source<Item = f32> + Send>, f32>,
+    chunks: SyncSender<Vec<f32>>,
+) {
+    loop {
+        let chunk = source.by_ref().take(CHUNK_SIZE).collect::<Vec<_>>();
+        let ended = chunk.len() < CHUNK_SIZE;
+        if (!chunk.is_empty() && chunks.send(chunk).is_err()) || ended {
+            return;
+        }
+    }
+}
+
+/**
+ * State shared between the engine and its handle
+ * */
+struct Shared {
+    playing: AtomicBool,
+    queued: AtomicUsize,
+    current: Mutex<Option<TrackInfo>>,
+    played: AtomicU64,
+}
+
+/**
+ * Source that plays queued tracks back to back, crossfading between them. Outputs silence while
+ * idle, and only ends once stopped through its handle.
+ * */
+pub struct TransitionEngine {
+    config: TransitionConfig,
+    commands: Receiver<Command>,
+    shared: Arc<Shared>,
+    queue: VecDeque<Deck>,
+    current: Option<Deck>,
+
+    /**
+     * Tracks still fading out, mixed under the current one until they are silent
+     * */
+    outgoing: Vec<Deck>,
+    last_info: Option<TrackInfo>,
+    until_poll: usize,
+    stopped: bool,
+}
+
+/**
+ * Handle used to control a TransitionEngine after it has been handed to the audio output
+ * */
+#[derive(Clone)]
+pub struct TransitionHandle {
+    commands: Sender<Command>,
+    shared: Arc<Shared>,
+    channels: u16,
+    sample_rate: u32,
+    lookahead: usize,
+}
+
+impl TransitionEngine {
+    /**
+     * Creates a new engine and the handle used to control it
+     * */
+    pub fn new(config: TransitionConfig) -> (Self, TransitionHandle) {
+        let (tx, rx) = channel();
+        let (channels, sample_rate) = (config.channels, config.sample_rate);
+        let lookahead = samples(config.crossfade, &config).max(MIN_LOOKAHEAD);
+        let shared = Arc::new(Shared {
+            playing: AtomicBool::new(false),
+            queued: AtomicUsize::new(0),
+            current: Mutex::new(None),
+            played: AtomicU64::new(0),
+        });
+        (
+            TransitionEngine {
+                config,
+                commands: rx,
+                shared: shared.clone(),
+                queue: VecDeque::new(),
+                current: None,
+                outgoing: vec![],
+                last_info: None,
+                until_poll: 0,
+                stopped: false,
+            },
+            TransitionHandle {
+                commands: tx,
+                shared,
+                channels,
+                sample_rate,
+                lookahead,
+            },
+        )
+    }
+
+    fn samples(&self, duration: Duration) -> usize {
+        samples(duration, &self.config)
+    }
+
+    fn poll_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                Command::Queue(deck) => {
+                    self.queue.push_back(deck);
+                    if self.current.as_ref().is_some_and(|deck| deck.info.live) {
+                        self.fade_out_current(self.config.skip_fade);
+                    }
+                }
+                Command::Replace(deck) => {
+                    let fade = self.samples(REPLACE_FADE);
+                    if let Some(mut current) = self.current.take() {
+                        current.fade_out(fade);
+                        self.outgoing.push(current);
+                    }
+                    self.queue.push_front(deck);
+                    self.start_next(Some(fade));
+                }
+                Command::Skip => self.fade_out_current(self.config.skip_fade),
+                Command::ClearQueue => self.queue.clear(),
+                Command::Stop => self.stopped = true,
+            }
+        }
+        self.shared.queued.store(self.queue.len(), Ordering::Relaxed);
+        self.shared.played.store(
+            self.current.as_ref().map_or(0, |deck| deck.played),
+            Ordering::Relaxed,
+        );
+    }
+
+    /**
+     * Fades the current track out over {fade}, after which the next track starts. Tracks already
+     * fading out carry on fading underneath it.
+     * */
+    fn fade_out_current(&mut self, fade: Duration) {
+        if let Some(mut deck) = self.current.take() {
+            deck.fade_out(self.samples(fade));
+            self.outgoing.push(deck);
+        }
+    }
+
+    /**
+     * Starts the next queued track, fading it in over {fade_in} samples if set. Live streams with
+     * tracks queued behind them are skipped without being opened, since they would never end to
+     * let those tracks play.
+     * */
+    fn start_next(&mut self, fade_in: Option<usize>) -> bool {
+        while self.queue.len() > 1 && self.queue.front().is_some_and(|deck| deck.info.live) {
+            if let Some(deck) = self.queue.pop_front() {
+                log!(
+                    Level::Debug,
+                    "skipping stream {} for the songs queued after it",
+                    deck.info.song_id
+                );
+            }
+        }
+        match self.queue.pop_front() {
+            Some(mut deck) => {
+                deck.start();
+                deck.fade = fade_in.map(Fade::fade_in);
+                self.last_info = Some(deck.info.clone());
+                if let Ok(mut current) = self.shared.current.lock() {
+                    *current = Some(deck.info.clone());
+                }
+                self.current = Some(deck);
+                self.shared.queued.store(self.queue.len(), Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /**
+     * Begins crossfading into the next track once the current one is within the crossfade
+     * window of its end
+     * */
+    fn maybe_crossfade(&mut self) {
+        if !self.outgoing.is_empty() || self.queue.is_empty() || self.config.crossfade.is_zero() {
+            return;
+        }
+        let remaining = match self.current.as_ref().and_then(|deck| deck.remaining()) {
+            Some(remaining) => remaining,
+            None => return,
+        };
+        let gapless = self.config.gapless_albums
+            && self.current.as_ref().is_some_and(|deck| {
+                deck.info.is_followed_by(&self.queue.front().unwrap().info)
+            });
+        if gapless || remaining == 0 {
+            return;
+        }
+
+        let mut deck = self.current.take().unwrap();
+        deck.fade_out(remaining);
+        self.outgoing.push(deck);
+        self.start_next(Some(remaining));
+    }
+}
+
+impl Iterator for TransitionEngine {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.until_poll == 0 {
+            self.poll_commands();
+            self.until_poll = COMMAND_POLL_INTERVAL;
+        }
+        self.until_poll -= 1;
+
+        if self.stopped {
+            return None;
+        }
+
+        // A song fading out from a skip has to finish before the next one starts
+        if self.current.is_none() && self.outgoing.is_empty() {
+            self.start_next(None);
+        }
+        self.maybe_crossfade();
+
+        let mut out = 0.0;
+        self.outgoing.retain_mut(|deck| match deck.next_sample() {
+            Some(sample) if !deck.faded_out() => {
+                out += sample;
+                true
+            }
+            _ => false,
+        });
+        loop {
+            match self.current.as_mut().map(|deck| deck.next_sample()) {
+                Some(Some(sample)) => {
+                    out += sample;
+                    break;
+                }
+                // Current track ended, move straight on to the next one without a gap
+                Some(None) => {
+                    self.current = None;
+                    if let Ok(mut current) = self.shared.current.lock() {
+                        *current = None;
+                    }
+                    if !self.outgoing.is_empty() || !self.start_next(None) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        self.shared
+            .playing
+            .store(self.current.is_some() || !self.outgoing.is_empty(), Ordering::Relaxed);
+        Some(out)
+    }
+}
+
+/**
+ * Number of interleaved samples {duration} lasts at the engine's output format
+ * */
+fn samples(duration: Duration, config: &TransitionConfig) -> usize {
+    let frames = (duration.as_secs_f64() * config.sample_rate as f64) as usize;
+    frames * config.channels as usize
+}
+
+impl Source for TransitionEngine {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.config.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.config.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl TransitionHandle {
+    /**
+     * Queues a source to play after everything already queued
+     * */
+    pub fn queue(&self, source: Box<dyn Source<Item = f32> + Send>, info: TrackInfo) {
+        self.queue_opener(Box::new(move || Ok(source)), info)
+    }
+
+    /**
+     * Queues a track to play after everything already queued, opening it with {open}. Live
+     * tracks are opened when they start playing, everything else straight away.
+     * */
+    pub fn queue_opener(&self, open: Opener, info: TrackInfo) {
+        let _ = self.commands.send(Command::Queue(self.deck(open, info)));
+        self.shared.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /**
+     * Replaces the current track with {source} immediately, keeping the rest of the queue. Used
+     * to seek by replacing the track with a source starting at a different position.
+     * */
+    pub fn replace(&self, source: Box<dyn Source<Item = f32> + Send>, info: TrackInfo) {
+        let _ = self.commands.send(Command::Replace(self.deck(Box::new(move || Ok(source)), info)));
+    }
+
+    /**
+     * Starts decoding a track here rather than on the audio output's thread
+     * */
+    fn deck(&self, open: Opener, info: TrackInfo) -> Deck {
+        Deck::new(open, info, self.lookahead, self.channels, self.sample_rate)
+    }
+
+    /**
+     * Fades out the current track and moves on to the next one
+     * */
+    pub fn skip(&self) {
+        let _ = self.commands.send(Command::Skip);
+    }
+
+    /**
+     * Removes all tracks that haven't started playing yet
+     * */
+    pub fn clear_queue(&self) {
+        let _ = self.commands.send(Command::ClearQueue);
+    }
+
+    /**
+     * Ends the engine's output entirely
+     * */
+    pub fn stop(&self) {
+        let _ = self.commands.send(Command::Stop);
+    }
+
+    /**
+     * Whether a track is currently playing or fading out
+     * */
+    pub fn is_playing(&self) -> bool {
+        self.shared.playing.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Information about the current track, and how far into the song it is. Accurate to within
+     * a few milliseconds.
+     * */
+    pub fn position(&self) -> Option<(TrackInfo, Duration)> {
+        let info = self.shared.current.lock().ok()?.clone()?;
+        let played = self.shared.played.load(Ordering::Relaxed);
+        let rate = self.sample_rate as f64 * self.channels as f64;
+        let elapsed = info.offset + Duration::from_secs_f64(played as f64 / rate);
+        Some((info, elapsed))
+    }
+
+    /**
+     * Number of tracks waiting to play, as of the last time the engine checked for commands
+     * */
+    pub fn queued(&self) -> usize {
+        self.shared.queued.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::{anyhow, Error};
+use reqwest::Url;
+
+use super::http;
+use super::spotify::{LinkKind, SpotifyLink};
+use super::youtube::{is_youtube_host, YoutubeUrl};
+use crate::types::SongOrigin;
+
+/**
+ * Hosts whose links are Spotify tracks
+ * */
+const SPOTIFY_HOSTS: [&str; 1] = ["open.spotify.com"];
+
+/**
+ * Hosts whose links are SoundCloud tracks
+ * */
+const SOUNDCLOUD_HOSTS: [&str; 4] = [
+    "soundcloud.com",
+    "www.soundcloud.com",
+    "m.soundcloud.com",
+    "on.soundcloud.com",
+];
+
+/**
+ * Works out which origin pasted input refers to. Returns None if the input isn't a link, meaning
+ * it should be treated as a search, and an error if it is a link that can't be queued. Links to
+ * sites without their own origin are assumed to be for yt-dlp.
+ * */
+pub fn classify(input: &str) -> Result<Option<SongOrigin>, Error> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(anyhow!("Nothing to queue"));
+    }
+
+    // Spotify's app copies URIs rather than links
+    if input.starts_with("spotify:") {
+        return spotify_track(input).map(Some);
+    }
+
+    let url = match as_url(input) {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+
+    if is_youtube_host(&host) {
+        let video = YoutubeUrl::parse(input)?;
+        // The start time is kept until the song is enqueued, when it becomes the song's start point
+        Ok(Some(SongOrigin::Youtube(video.link())))
+    } else if SPOTIFY_HOSTS.contains(&host.as_str()) {
+        spotify_track(input).map(Some)
+    } else if SOUNDCLOUD_HOSTS.contains(&host.as_str()) {
+        Ok(Some(SongOrigin::Soundcloud(url.to_string())))
+    } else if has_scheme(input) && http::is_audio_url(url.as_str()) {
+        Ok(Some(SongOrigin::Http(url.to_string())))
+    } else if has_scheme(input) {
+        // Whether yt-dlp can handle it, and is allowed to, is checked when it is fetched
+        Ok(Some(SongOrigin::Ytdlp(url.to_string())))
+    } else {
+        // Without a scheme it could just as well be text with a dot in it
+        Ok(None)
+    }
+}
+
+/**
+ * Parses a Spotify link, which must be to a single track
+ * */
+fn spotify_track(input: &str) -> Result<SongOrigin, Error> {
+    match SpotifyLink::parse(input) {
+        Some(link) if link.kind == LinkKind::Track => Ok(SongOrigin::Spotify(link.url())),
+        Some(_) => Err(anyhow!("Spotify albums and playlists have to be imported")),
+        None => Err(anyhow!("{} is not a valid Spotify link", input)),
+    }
+}
+
+/**
+ * Parses input as a web link if it looks like one: either it has an http(s) scheme, or it is a
+ * single word with a dotted host, like "youtu.be/dQw4w9WgXcQ"
+ * */
+fn as_url(input: &str) -> Option<Url> {
+    if has_scheme(input) {
+        return Url::parse(input).ok();
+    }
+    if input.contains(char::is_whitespace) || input.contains("://") {
+        return None;
+    }
+    let url = Url::parse(&format!("https://{input}")).ok()?;
+    let host = url.host_str()?;
+    // Require something that looks like a domain, so "track.mp3" style text isn't mistaken
+    // for a link
+    let tld = host.rsplit('.').next()?;
+    (host.contains('.') && tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic()))
+        .then_some(url)
+}
+
+fn has_scheme(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
@@ -0,0 +1,267 @@
+use log::{log, Level};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/**
+ * How long a read waits for the file to grow before giving up
+ * */
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/**
+ * How much has been downloaded, and whether the download has ended
+ * */
+#[derive(Debug, Default, Clone, Copy)]
+struct Progress {
+    written: u64,
+    done: bool,
+    failed: bool,
+}
+
+/**
+ * Progress of a download into a file, shared between the downloader and readers of the file.
+ * Readers waiting for more data are woken as soon as it is written.
+ * */
+#[derive(Debug, Default)]
+pub struct DownloadState {
+    progress: Mutex<Progress>,
+    changed: Condvar,
+}
+
+impl DownloadState {
+    pub fn new() -> Arc<Self> {
+        Arc::new(DownloadState::default())
+    }
+
+    /**
+     * Records that {bytes} more bytes have been written to the file
+     * */
+    pub fn grew(&self, bytes: u64) {
+        self.update(|progress| progress.written += bytes);
+    }
+
+    /**
+     * Marks the download as complete
+     * */
+    pub fn finish(&self) {
+        self.update(|progress| progress.done = true);
+    }
+
+    /**
+     * Marks the download as failed, causing reads past the downloaded data to error
+     * */
+    pub fn fail(&self) {
+        self.update(|progress| {
+            progress.failed = true;
+            progress.done = true;
+        });
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.progress().done
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.progress().failed
+    }
+
+    /**
+     * Blocks until the download finishes, failing if it failed or stalled
+     * */
+    pub fn wait(&self) -> io::Result<()> {
+        if self.wait_for(u64::MAX)?.failed {
+            return Err(io::Error::other("Download failed"));
+        }
+        Ok(())
+    }
+
+    fn progress(&self) -> MutexGuard<'_, Progress> {
+        // Progress is always left consistent, so a panic while it was locked doesn't matter
+        self.progress.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Progress)) {
+        change(&mut self.progress());
+        self.changed.notify_all();
+    }
+
+    /**
+     * Blocks until at least {len} bytes have been written or the download has ended, returning
+     * the progress at that point. Fails if nothing is written for STALL_TIMEOUT.
+     * */
+    fn wait_for(&self, len: u64) -> io::Result<Progress> {
+        let mut progress = self.progress();
+        while progress.written < len && !progress.done {
+            let written = progress.written;
+            let (guard, result) = self
+                .changed
+                .wait_timeout_while(progress, STALL_TIMEOUT, |p| {
+                    p.written == written && !p.done
+                })
+                .unwrap_or_else(PoisonError::into_inner);
+            if result.timed_out() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Download stalled"));
+            }
+            progress = guard;
+        }
+        Ok(*progress)
+    }
+}
+
+/**
+ * Copies everything from {reader} into {out}, recording each write in {state} so readers of the
+ * file can carry on straight away
+ * */
+pub fn copy<R: Read>(reader: &mut R, out: &mut File, state: &DownloadState) -> io::Result<u64> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut total = 0;
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        out.write_all(&buf[..read])?;
+        total += read as u64;
+        state.grew(read as u64);
+    }
+}
+
+/**
+ * A download written to a ".part" file next to its final path, and moved into place once
+ * complete so unfinished downloads are never mistaken for cached ones
+ * */
+#[derive(Debug, Clone)]
+pub struct Download {
+    /**
+     * Path the file ends up at
+     * */
+    pub path: String,
+
+    /**
+     * Path the file is written to while downloading
+     * */
+    pub part: String,
+
+    pub state: Arc<DownloadState>,
+}
+
+impl Download {
+    pub fn new(path: &str) -> Self {
+        Download {
+            path: path.to_string(),
+            part: format!("{path}.part"),
+            state: DownloadState::new(),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state.is_done()
+    }
+
+    /**
+     * Opens the file wherever it currently is. Reads past what has been downloaded block until
+     * more arrives.
+     * */
+    pub fn open(&self) -> io::Result<GrowingFile> {
+        match GrowingFile::open(&self.part, self.state.clone()) {
+            // Already moved into place
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                GrowingFile::open(&self.path, self.state.clone())
+            }
+            opened => opened,
+        }
+    }
+
+    /**
+     * Blocks until the download has finished and been moved into place
+     * */
+    pub fn wait(&self) -> io::Result<()> {
+        self.state.wait()
+    }
+
+    /**
+     * Moves the finished download into place and wakes anything waiting for it
+     * */
+    pub fn finish(&self) {
+        if let Err(e) = fs::rename(&self.part, &self.path) {
+            log!(Level::Warn, "Could not move {} into place: {}", self.part, e);
+        }
+        self.state.finish();
+    }
+
+    /**
+     * Deletes the partial download and marks it as failed
+     * */
+    pub fn fail(&self) {
+        let _ = fs::remove_file(&self.part);
+        self.state.fail();
+    }
+}
+
+/**
+ * Reader over a file that is still being written. Reads past the end of the written data block
+ * until more is written or the download finishes, so a decoder can start on the file while it
+ * downloads.
+ * */
+pub struct GrowingFile {
+    file: File,
+    state: Arc<DownloadState>,
+}
+
+impl GrowingFile {
+    /**
+     * Opens a file being downloaded, tracking the download through {state}
+     * */
+    pub fn open(path: &str, state: Arc<DownloadState>) -> io::Result<Self> {
+        Ok(GrowingFile {
+            file: File::open(path)?,
+            state,
+        })
+    }
+
+    /**
+     * Blocks until the file is at least {len} bytes long or the download is finished
+     * */
+    fn wait_for(&self, len: u64) -> io::Result<()> {
+        let progress = self.state.wait_for(len)?;
+        if progress.failed && progress.written < len {
+            return Err(io::Error::other("Download failed"));
+        }
+        Ok(())
+    }
+}
+
+impl Read for GrowingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pos = self.file.stream_position()?;
+        self.wait_for(pos + 1)?;
+        self.file.read(buf)
+    }
+}
+
+impl Seek for GrowingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(offset) => {
+                let current = self.file.stream_position()?;
+                current.checked_add_signed(offset).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Seek before start of file")
+                })?
+            }
+            // The end isn't known until the download finishes
+            SeekFrom::End(_) => {
+                self.wait_for(u64::MAX)?;
+                return self.file.seek(pos);
+            }
+        };
+        self.wait_for(target)?;
+        self.file.seek(SeekFrom::Start(target))
+    }
+}
@@ -0,0 +1,423 @@
+use anyhow::{anyhow, Error};
+use hyper::client::connect::dns::Name;
+use lazy_static::lazy_static;
+use log::{log, Level};
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::{Client, Response, Url};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+
+use super::growing::{self, Download, DownloadState};
+use super::{ensure_cache_dir, open_cached, Fetched, SourceConfig};
+use crate::decode;
+
+/**
+ * How long to wait for the server to start responding
+ * */
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/**
+ * Most redirects followed before a link is given up on
+ * */
+const MAX_REDIRECTS: usize = 10;
+
+lazy_static! {
+    /**
+     * Client used for every direct download. Addresses are checked as they are resolved, so
+     * neither a link nor anything it redirects to can reach the server's own network.
+     * */
+    static ref CLIENT: Client = Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(Policy::custom(check_redirect))
+        // Each download runs the client on its own runtime, and connections can't outlive it
+        .pool_max_idle_per_host(0)
+        .build()
+        .expect("Could not build HTTP client");
+}
+
+/**
+ * File extensions of audio that can be played from a direct link
+ * */
+pub const AUDIO_EXTENSIONS: [&str; 8] = ["mp3", "flac", "ogg", "oga", "wav", "m4a", "aac", "webm"];
+
+/**
+ * Downloads audio from a direct link, decoding it as it arrives. The response must be audio, by
+ * its content type or, for servers that don't set one, by the link's extension, and no larger
+ * than the configured maximum download size. Downloads are cached, so a link is only downloaded
+ * once.
+ * */
+pub fn fetch(url: &str, config: &SourceConfig) -> Result<Fetched, Error> {
+    ensure_cache_dir(config)?;
+    let name = cache_name(url);
+    if let Some(fetched) = open_cached(&name, config) {
+        return Ok(fetched);
+    }
+
+    // The file is written as the response is read, so the whole download, including the initial
+    // request, happens on its own thread with its own runtime
+    let (tx, rx) = mpsc::channel();
+    {
+        let url = url.to_string();
+        let (cache_dir, max_size) = (config.cache_dir.clone(), config.max_download_size);
+        thread::spawn(move || {
+            let runtime = match Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    let _ = tx.send(Err(e.into()));
+                    return;
+                }
+            };
+            let (response, ext) = match runtime.block_on(start_download(&url, max_size)) {
+                Ok(started) => started,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+            let download = Download::new(&format!("{cache_dir}/{name}.{ext}"));
+            let out = match File::create(&download.part) {
+                Ok(out) => out,
+                Err(e) => {
+                    let _ = tx.send(Err(e.into()));
+                    return;
+                }
+            };
+            // The reader can only open the file once it exists
+            let _ = tx.send(Ok((download.clone(), ext)));
+
+            let body = Body {
+                response,
+                runtime: &runtime,
+                chunk: vec![],
+                pos: 0,
+            };
+            match copy_limited(body, out, max_size, &download.state) {
+                Ok(bytes) => {
+                    log!(Level::Debug, "Finished downloading {} ({} bytes)", url, bytes);
+                    download.finish();
+                }
+                Err(e) => {
+                    log!(Level::Warn, "Download of {} failed: {}", url, e);
+                    download.fail();
+                }
+            }
+        });
+    }
+
+    let (download, ext) = rx
+        .recv_timeout(CONNECT_TIMEOUT * 2)
+        .map_err(|_| anyhow!("Timed out waiting for {}", url))??;
+    let reader = download.open()?;
+    Ok(Fetched {
+        source: decode::open_reader(reader, Some(&ext))?,
+        path: download.path.clone(),
+        info: None,
+        download: Some(download),
+    })
+}
+
+/**
+ * Whether a link points straight at an audio file, judging by its extension
+ * */
+pub fn is_audio_url(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    decode::extension(path).is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/**
+ * Requests the link and checks the response is audio that isn't too large, returning the
+ * response and the extension to save it with
+ * */
+async fn start_download(url: &str, max_size: u64) -> Result<(Response, String), Error> {
+    log!(Level::Debug, "Downloading audio from {url}");
+    let parsed = Url::parse(url)?;
+    check_host(&parsed)?;
+    // Downloads are streamed, so there is no overall timeout
+    let response = CLIENT.get(parsed).send().await?.error_for_status()?;
+
+    if response.headers().keys().any(|k| k.as_str().starts_with("icy-")) {
+        return Err(anyhow!("{} is a radio stream, not an audio file", url));
+    }
+
+    if let Some(len) = response.content_length() {
+        if len > max_size {
+            return Err(anyhow!(
+                "{} is too large ({} bytes, the limit is {})",
+                url,
+                len,
+                max_size
+            ));
+        }
+    }
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .map(|t| t.split(';').next().unwrap_or_default().trim().to_lowercase());
+    let url_ext = decode::extension(url.split(['?', '#']).next().unwrap_or(url))
+        .filter(|ext| AUDIO_EXTENSIONS.contains(&ext.as_str()));
+
+    let ext = match content_type.as_deref() {
+        Some(t) if t.starts_with("audio/") || t == "application/ogg" => {
+            url_ext.or_else(|| extension_for(t).map(str::to_string))
+        }
+        // Generic types say nothing either way, so trust the link
+        None | Some("application/octet-stream") | Some("binary/octet-stream") => url_ext,
+        Some(t) => return Err(anyhow!("{} is not audio (content type {})", url, t)),
+    };
+    let ext = ext.ok_or_else(|| anyhow!("Could not tell what kind of audio {} is", url))?;
+    Ok((response, ext))
+}
+
+/**
+ * Extension for an audio content type
+ * */
+fn extension_for(content_type: &str) -> Option<&'static str> {
+    match content_type {
+        "audio/mpeg" | "audio/mp3" => Some("mp3"),
+        "audio/flac" | "audio/x-flac" => Some("flac"),
+        "audio/ogg" | "audio/vorbis" | "application/ogg" => Some("ogg"),
+        "audio/wav" | "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => Some("wav"),
+        "audio/mp4" | "audio/x-m4a" | "audio/m4a" => Some("m4a"),
+        "audio/aac" | "audio/aacp" => Some("aac"),
+        "audio/webm" => Some("webm"),
+        _ => None,
+    }
+}
+
+/**
+ * Checks a link doesn't point straight at an address on the server's own network. Links to host
+ * names are checked when the names are resolved.
+ * */
+fn check_host(url: &Url) -> Result<(), Error> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(anyhow!("{} is not an HTTP link", url));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("{} has no host", url))?;
+    let ip = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => return Ok(()),
+    };
+    if is_public(ip) {
+        Ok(())
+    } else {
+        Err(anyhow!("{} is not a public address", ip))
+    }
+}
+
+/**
+ * Follows redirects to public addresses only
+ * */
+fn check_redirect(attempt: Attempt) -> reqwest::redirect::Action {
+    if attempt.previous().len() >= MAX_REDIRECTS {
+        return attempt.error("Too many redirects");
+    }
+    match check_host(attempt.url()) {
+        Ok(()) => attempt.follow(),
+        Err(e) => attempt.error(e.to_string()),
+    }
+}
+
+/**
+ * Whether {ip} is on the public internet, rather than loopback, a private network, link-local
+ * or otherwise reserved
+ * */
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    // 0.0.0.0/8 is "this network" and 100.64.0.0/10 is carrier-grade NAT
+    let reserved = a == 0 || (a == 100 && (b & 0xc0) == 64);
+    !(reserved
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation())
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    // fc00::/7 is unique local and fe80::/10 link-local
+    let local = (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80;
+    !(local || ip.is_loopback() || ip.is_unspecified() || ip.is_multicast())
+}
+
+/**
+ * Resolves host names, failing if any of their addresses isn't public
+ * */
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+            if addrs.is_empty() {
+                return Err(format!("{} has no addresses", name.as_str()).into());
+            }
+            if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+                return Err(format!("{} is not a public address", addr.ip()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/**
+ * Reads a response body on the download's runtime
+ * */
+struct Body<'a> {
+    response: Response,
+    runtime: &'a Runtime,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for Body<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.runtime.block_on(self.response.chunk()) {
+                Ok(Some(chunk)) => {
+                    self.chunk = chunk.to_vec();
+                    self.pos = 0;
+                }
+                Ok(None) => return Ok(0),
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/**
+ * Copies the response into the file, failing once more than {max_size} bytes have been received.
+ * Servers don't always send a content length, so the limit is enforced here as well.
+ * */
+fn copy_limited(
+    response: Body,
+    mut out: File,
+    max_size: u64,
+    state: &DownloadState,
+) -> Result<u64, Error> {
+    let mut limited = response.take(max_size + 1);
+    let bytes = growing::copy(&mut limited, &mut out, state)?;
+    if bytes > max_size {
+        return Err(anyhow!("Download is larger than {} bytes", max_size));
+    }
+    out.flush()?;
+    Ok(bytes)
+}
+
+/**
+ * Name a link's audio is cached under. The hash has to stay the same across builds, or the cache
+ * would be lost whenever the toolchain changes.
+ * */
+fn cache_name(url: &str) -> String {
+    let hash = Sha256::digest(url.as_bytes());
+    let hex = hash[..16].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("http-{hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn public_addresses() {
+        let cases = [
+            ("93.184.216.34", true),
+            ("8.8.8.8", true),
+            ("2606:2800:220:1:248:1893:25c8:1946", true),
+            ("127.0.0.1", false),
+            ("127.1.2.3", false),
+            ("10.1.2.3", false),
+            ("172.16.0.1", false),
+            ("172.31.255.255", false),
+            ("192.168.1.1", false),
+            ("169.254.169.254", false),
+            ("100.64.0.1", false),
+            ("0.0.0.0", false),
+            ("255.255.255.255", false),
+            ("224.0.0.1", false),
+            ("::1", false),
+            ("::", false),
+            ("fd00::1", false),
+            ("fe80::1", false),
+            ("::ffff:127.0.0.1", false),
+            ("::ffff:192.168.1.1", false),
+        ];
+        for (ip, public) in cases {
+            assert_eq!(is_public(ip.parse().unwrap()), public, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn checks_hosts_in_links() {
+        let cases = [
+            ("https://example.com/song.mp3", true),
+            ("http://93.184.216.34/song.mp3", true),
+            ("http://127.0.0.1/song.mp3", false),
+            ("http://[::1]:8000/song.mp3", false),
+            ("http://169.254.169.254/latest/meta-data/", false),
+            ("ftp://example.com/song.mp3", false),
+        ];
+        for (url, allowed) in cases {
+            assert_eq!(check_host(&Url::parse(url).unwrap()).is_ok(), allowed, "{}", url);
+        }
+    }
+
+    #[test]
+    fn cache_names_are_stable() {
+        assert_eq!(
+            cache_name("https://example.com/song.mp3"),
+            "http-8e2970309be418c9dbabdb7a9b42bc09"
+        );
+        assert_ne!(
+            cache_name("https://example.com/song.mp3"),
+            cache_name("https://example.com/song.mp3?v=2")
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_to_download_from_private_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        for url in [
+            format!("http://127.0.0.1:{port}/song.mp3"),
+            format!("http://localhost:{port}/song.mp3"),
+            format!("http://[::ffff:127.0.0.1]:{port}/song.mp3"),
+        ] {
+            assert!(start_download(&url, 1024).await.is_err(), "{} was downloaded", url);
+        }
+        assert!(listener.accept().is_err(), "a private address was connected to");
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::Error;
+use lazy_static::lazy_static;
+use log::{log, Level};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::decode::{self, AudioSource};
+use crate::types::SourceInfo;
+use growing::Download;
+
+/**
+ * Internal module for working out which origin pasted input refers to
+ * */
+pub mod detect;
+
+/**
+ * Internal module for downloading audio from direct links
+ * */
+pub mod http;
+
+/**
+ * Internal module for playing Icecast and Shoutcast radio streams
+ * */
+pub mod radio;
+
+/**
+ * Internal module for reading files that are still being downloaded
+ * */
+pub mod growing;
+
+/**
+ * Internal module for resolving Spotify links to tracks and finding them on YouTube
+ * */
+pub mod spotify;
+
+/**
+ * Internal module for downloading and streaming audio from YouTube
+ * */
+pub mod youtube;
+
+/**
+ * Internal module for downloading audio from any site supported by yt-dlp
+ * */
+pub mod ytdlp;
+
+/**
+ * Extensions a cached download can have
+ * */
+const CACHED_EXTENSIONS: [&str; 8] = ["webm", "m4a", "mp3", "ogg", "opus", "flac", "wav", "aac"];
+
+/**
+ * Configuration for how songs are fetched from their origins
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceConfig {
+    /**
+     * Directory downloaded audio is cached in
+     * */
+    pub cache_dir: String,
+
+    /**
+     * Path to, or name of, the yt-dlp executable
+     * */
+    pub ytdlp: String,
+
+    /**
+     * Whether YouTube audio starts playing while it downloads. Only Opus audio can be; other
+     * codecs, or a failed progressive download, download the whole file before playback starts.
+     * */
+    pub progressive: bool,
+
+    /**
+     * Audio format YouTube songs are downloaded in
+     * */
+    pub codec: AudioCodec,
+
+    /**
+     * yt-dlp extractors that songs from sites other than YouTube and
+     * Spotify may be downloaded with (e.g. "soundcloud", "bandcamp"). Links
+     * handled by any other extractor are rejected.
+     * */
+    pub extractors: Vec<String>,
+
+    /**
+     * Largest file, in bytes, that will be downloaded from a direct link
+     * */
+    pub max_download_size: u64,
+}
+
+/**
+ * Audio formats songs can be downloaded in
+ * */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AudioCodec {
+    /**
+     * Opus in a WebM container. YouTube's highest quality audio for most videos.
+     * */
+    Opus,
+
+    /**
+     * AAC in an m4a container
+     * */
+    Aac,
+
+    /**
+     * Transcodes the best available audio to mp3 with ffmpeg. Slower and lossy, only useful if
+     * the cached files are needed by something that can't read the native formats.
+     * */
+    Mp3,
+}
+
+impl AudioCodec {
+    /**
+     * yt-dlp format selector for the codec, falling back to whatever audio is available if the
+     * video doesn't have the preferred format
+     * */
+    pub fn format(&self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "bestaudio[acodec=opus]/bestaudio",
+            AudioCodec::Aac => "bestaudio[ext=m4a]/bestaudio",
+            AudioCodec::Mp3 => "bestaudio",
+        }
+    }
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        SourceConfig {
+            cache_dir: String::from("/tmp/jukebox"),
+            ytdlp: String::from("yt-dlp"),
+            progressive: true,
+            codec: AudioCodec::Opus,
+            extractors: vec![
+                String::from("soundcloud"),
+                String::from("bandcamp"),
+                String::from("vimeo"),
+            ],
+            max_download_size: 200 * 1024 * 1024,
+        }
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: RwLock<SourceConfig> = RwLock::new(SourceConfig::default());
+}
+
+/**
+ * Gets the current source configuration
+ * */
+pub fn config() -> SourceConfig {
+    CONFIG.read().map(|c| c.clone()).unwrap_or_default()
+}
+
+/**
+ * Replaces the source configuration used for all future fetches
+ * */
+pub fn set_config(config: SourceConfig) {
+    if let Ok(mut c) = CONFIG.write() {
+        *c = config;
+    }
+}
+
+/**
+ * A fetched song, ready to be played
+ * */
+pub struct Fetched {
+    /**
+     * Path the audio is cached at. For progressive downloads the file only exists here once the
+     * download completes.
+     * */
+    pub path: String,
+
+    /**
+     * Information reported by yt-dlp, if it was available
+     * */
+    pub info: Option<SourceInfo>,
+
+    pub source: AudioSource,
+
+    /**
+     * The download, for songs that start playing before it finishes
+     * */
+    pub download: Option<Download>,
+}
+
+/**
+ * Opens a previously downloaded copy of a song cached as {name}, in any format
+ * */
+pub(crate) fn open_cached(name: &str, config: &SourceConfig) -> Option<Fetched> {
+    let path = CACHED_EXTENSIONS
+        .iter()
+        .map(|ext| format!("{}/{name}.{ext}", config.cache_dir))
+        .find(|path| Path::new(path).exists())?;
+
+    log!(Level::Debug, "Using cached download {}", path);
+    match decode::open_file(&path) {
+        Ok(source) => Some(Fetched {
+            source,
+            path,
+            info: None,
+            download: None,
+        }),
+        Err(e) => {
+            log!(Level::Warn, "Could not decode cached file {}: {}", path, e);
+            None
+        }
+    }
+}
+
+pub(crate) fn ensure_cache_dir(config: &SourceConfig) -> Result<(), Error> {
+    if !Path::new(&config.cache_dir).exists() {
+        log!(Level::Debug, "output dir does not exist, creating");
+        fs::create_dir_all(&config.cache_dir)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,644 @@
+use anyhow::{anyhow, Error};
+use lazy_static::lazy_static;
+use log::{log, Level};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use super::Fetched;
+use crate::decode;
+use crate::fingerprint::SongMetadata;
+
+lazy_static! {
+    /**
+     * What each playing stream says it is playing, by song ID
+     * */
+    static ref LIVE: RwLock<HashMap<u64, SongMetadata>> = RwLock::new(HashMap::new());
+}
+
+/**
+ * How long to wait for the station to start responding
+ * */
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/**
+ * Size of the chunks audio is passed to the decoder in
+ * */
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/**
+ * Number of chunks buffered between the network and the decoder
+ * */
+const BUFFERED_CHUNKS: usize = 32;
+
+/**
+ * How much already read audio is kept so decoders can seek back while probing the stream
+ * */
+const REWIND_LIMIT: usize = 512 * 1024;
+
+/**
+ * Connects to an Icecast or Shoutcast stream. The returned source never ends on its own; it
+ * plays until the station disconnects or the player moves on. StreamTitle updates sent by the
+ * station are published as the live metadata of {song_id}.
+ *
+ * Shoutcast v1 servers that answer with an "ICY 200 OK" status line instead of HTTP aren't
+ * supported.
+ * */
+pub fn open(url: &str, song_id: u64) -> Result<Fetched, Error> {
+    let (started_tx, started_rx) = mpsc::channel();
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel(BUFFERED_CHUNKS);
+
+    // The blocking client can't be used from inside the async runtime, so the connection lives
+    // on its own thread for as long as the stream plays
+    let url = url.to_string();
+    thread::spawn(move || {
+        let station = match connect(&url) {
+            Ok(station) => station,
+            Err(e) => {
+                let _ = started_tx.send(Err(e));
+                return;
+            }
+        };
+
+        set_live(song_id, station.name.as_deref(), None);
+        let _ = started_tx.send(Ok(station.ext));
+        let name = station.name.clone();
+        let reader = IcyReader::new(station.response, station.metaint, |title| {
+            log!(Level::Debug, "{} is now playing {}", url, title);
+            set_live(song_id, name.as_deref(), Some(title));
+        });
+        pump(reader, chunk_tx);
+
+        log!(Level::Debug, "disconnected from {}", url);
+        if let Ok(mut live) = LIVE.write() {
+            live.remove(&song_id);
+        }
+    });
+
+    let ext = started_rx
+        .recv_timeout(CONNECT_TIMEOUT * 2)
+        .map_err(|_| anyhow!("Timed out connecting to stream"))??;
+    Ok(Fetched {
+        source: decode::open_reader(StreamReader::new(chunk_rx), Some(ext))?,
+        // Streams are never cached
+        path: String::new(),
+        info: None,
+        download: None,
+    })
+}
+
+/**
+ * A station that has been connected to and is about to send audio
+ * */
+struct Station {
+    response: Response,
+    /**
+     * Extension of the stream's audio format
+     * */
+    ext: &'static str,
+    /**
+     * Bytes of audio between metadata blocks, if the station sends metadata
+     * */
+    metaint: Option<usize>,
+    name: Option<String>,
+}
+
+/**
+ * Connects to a station, asking it to interleave metadata with the audio
+ * */
+fn connect(url: &str) -> Result<Station, Error> {
+    let response = Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(None)
+        .build()
+        .and_then(|client| client.get(url).header("Icy-MetaData", "1").send())
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| anyhow!("Could not connect to {}: {}", url, e))?;
+
+    let headers = response.headers();
+    let ext = stream_extension(headers).ok_or_else(|| anyhow!("{} is not an audio stream", url))?;
+    let metaint = header(headers, "icy-metaint").and_then(|m| m.parse().ok());
+    let name = header(headers, "icy-name");
+    log!(
+        Level::Debug,
+        "connected to {} ({}, metadata every {:?} bytes)",
+        url,
+        ext,
+        metaint
+    );
+    Ok(Station {
+        response,
+        ext,
+        metaint,
+        name,
+    })
+}
+
+/**
+ * What the stream playing as {song_id} is currently playing, if it is still connected
+ * */
+pub fn live_metadata(song_id: u64) -> Option<SongMetadata> {
+    LIVE.read().ok()?.get(&song_id).cloned()
+}
+
+fn set_live(song_id: u64, station: Option<&str>, title: Option<&str>) {
+    let mut meta = SongMetadata {
+        album: station.unwrap_or("Live stream").to_string(),
+        ..SongMetadata::default()
+    };
+    match title.map(|t| t.split_once(" - ").unwrap_or(("", t))) {
+        Some((artist, title)) => {
+            meta.title = title.to_string();
+            if !artist.is_empty() {
+                meta.artist = artist.to_string();
+            }
+        }
+        None => meta.title = meta.album.clone(),
+    }
+    if let Ok(mut live) = LIVE.write() {
+        live.insert(song_id, meta);
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/**
+ * Extension of the stream's audio format, judged by its content type
+ * */
+fn stream_extension(headers: &HeaderMap) -> Option<&'static str> {
+    let content_type = header(headers, CONTENT_TYPE.as_str())?.to_lowercase();
+    match content_type.split(';').next()?.trim() {
+        "audio/mpeg" | "audio/mp3" => Some("mp3"),
+        "audio/aac" | "audio/aacp" | "audio/x-aac" => Some("aac"),
+        "audio/ogg" | "application/ogg" => Some("ogg"),
+        "audio/flac" => Some("flac"),
+        _ => None,
+    }
+}
+
+/**
+ * Reads the stream in chunks and passes them to the decoder until either end goes away
+ * */
+fn pump<R: Read>(mut reader: R, chunks: SyncSender<Vec<u8>>) {
+    loop {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        match reader.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(n) => {
+                chunk.truncate(n);
+                // The decoder has been dropped, so nobody is listening any more
+                if chunks.send(chunk).is_err() {
+                    return;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                log!(Level::Warn, "error reading stream: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/**
+ * Reader that removes the ICY metadata blocks interleaved with the audio of a stream, reporting
+ * each StreamTitle it finds
+ * */
+pub struct IcyReader<R: Read, F: FnMut(&str)> {
+    inner: R,
+    /**
+     * Bytes of audio between metadata blocks, or None if the station doesn't send metadata
+     * */
+    metaint: Option<usize>,
+    until_meta: usize,
+    on_title: F,
+    last_title: Option<String>,
+}
+
+impl<R: Read, F: FnMut(&str)> IcyReader<R, F> {
+    pub fn new(inner: R, metaint: Option<usize>, on_title: F) -> Self {
+        IcyReader {
+            inner,
+            metaint: metaint.filter(|m| *m > 0),
+            until_meta: metaint.unwrap_or(0),
+            on_title,
+            last_title: None,
+        }
+    }
+
+    /**
+     * Reads a metadata block: a length byte counting 16 byte units, followed by that much text
+     * */
+    fn read_metadata(&mut self) -> io::Result<()> {
+        let mut len = [0u8; 1];
+        self.inner.read_exact(&mut len)?;
+        let mut block = vec![0; len[0] as usize * 16];
+        self.inner.read_exact(&mut block)?;
+
+        let text = String::from_utf8_lossy(&block);
+        if let Some(title) = stream_title(&text) {
+            if self.last_title.as_deref() != Some(title.as_str()) {
+                (self.on_title)(&title);
+                self.last_title = Some(title);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read, F: FnMut(&str)> Read for IcyReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let metaint = match self.metaint {
+            Some(metaint) => metaint,
+            None => return self.inner.read(buf),
+        };
+        if self.until_meta == 0 {
+            self.read_metadata()?;
+            self.until_meta = metaint;
+        }
+        let len = buf.len().min(self.until_meta);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.until_meta -= read;
+        Ok(read)
+    }
+}
+
+/**
+ * Gets the StreamTitle from an ICY metadata block like "StreamTitle='Artist - Title';"
+ * */
+pub fn stream_title(metadata: &str) -> Option<String> {
+    let start = metadata.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &metadata[start..];
+    // Titles can contain quotes, so the value ends at the last "';" rather than the first quote
+    let end = rest.find("';").or_else(|| rest.rfind('\''))?;
+    Some(rest[..end].trim().to_string()).filter(|t| !t.is_empty())
+}
+
+/**
+ * Reader over chunks received from the network. Streams can't be seeked, but decoders probing
+ * the format seek back to the start after reading a header, so recently read data is kept to
+ * allow seeking backwards a limited distance.
+ * */
+struct StreamReader {
+    // Only ever used through &mut, the mutex just makes the reader Sync as decoders require
+    chunks: Mutex<Receiver<Vec<u8>>>,
+    buffer: Vec<u8>,
+    /**
+     * Position in the stream of the first byte of buffer
+     * */
+    base: u64,
+    pos: u64,
+}
+
+impl StreamReader {
+    fn new(chunks: Receiver<Vec<u8>>) -> Self {
+        StreamReader {
+            chunks: Mutex::new(chunks),
+            buffer: vec![],
+            base: 0,
+            pos: 0,
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.base + self.buffer.len() as u64
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.end() {
+            let chunks = self
+                .chunks
+                .get_mut()
+                .map_err(|_| io::Error::other("Stream reader poisoned"))?;
+            match chunks.recv() {
+                Ok(chunk) => self.buffer.extend(chunk),
+                // The stream ended
+                Err(_) => return Ok(0),
+            }
+            // Forget data too far behind to be seeked back to
+            let behind = (self.pos - self.base) as usize;
+            if behind > REWIND_LIMIT {
+                let drop = behind - REWIND_LIMIT;
+                self.buffer.drain(..drop);
+                self.base += drop as u64;
+            }
+        }
+        let offset = (self.pos - self.base) as usize;
+        let len = buf.len().min(self.buffer.len() - offset);
+        buf[..len].copy_from_slice(&self.buffer[offset..offset + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for StreamReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+        match target {
+            Some(target) if target >= self.base && target <= self.end() => {
+                self.pos = target;
+                Ok(target)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Live streams can't be seeked",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::transition::{
+        Opener, TrackInfo, TransitionConfig, TransitionEngine, TransitionHandle,
+    };
+    use rodio::buffer::SamplesBuffer;
+    use rodio::Source;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    /**
+     * Starts a stand-in station on a loopback port that answers one request with {headers} and
+     * {body}, then hangs up. Returns the station's URL and the request headers it received.
+     * */
+    fn serve(headers: &str, body: Vec<u8>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/stream", listener.local_addr().unwrap());
+        let headers = headers.to_string();
+        let (request_tx, request_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            let mut reader = BufReader::new(socket.try_clone().unwrap());
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+                request.push_str(&line);
+            }
+            let _ = request_tx.send(request.to_lowercase());
+            let head = format!("HTTP/1.1 200 OK\r\n{headers}Connection: close\r\n\r\n");
+            socket.write_all(head.as_bytes()).unwrap();
+            socket.write_all(&body).unwrap();
+        });
+        (url, request_rx)
+    }
+
+    fn audio(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /**
+     * Interleaves {audio} with a metadata block after every {metaint} bytes, taking the text of
+     * each from {blocks} in turn. Blocks past the end of {blocks} are empty.
+     * */
+    fn interleave(audio: &[u8], metaint: usize, blocks: &[&str]) -> Vec<u8> {
+        let mut body = vec![];
+        for (i, chunk) in audio.chunks(metaint).enumerate() {
+            body.extend(chunk);
+            if chunk.len() == metaint {
+                let text = blocks.get(i).copied().unwrap_or_default();
+                let units = text.len().div_ceil(16);
+                body.push(units as u8);
+                body.extend(text.as_bytes());
+                body.resize(body.len() + units * 16 - text.len(), 0);
+            }
+        }
+        body
+    }
+
+    /**
+     * Reads a station's stream the way a playing stream is read, returning the audio that
+     * reaches the decoder and every title reported
+     * */
+    fn play(station: Station) -> (Vec<u8>, Vec<String>) {
+        let (chunk_tx, chunk_rx) = mpsc::sync_channel(BUFFERED_CHUNKS);
+        let mut titles = vec![];
+        let reader = IcyReader::new(station.response, station.metaint, |title| {
+            titles.push(title.to_string())
+        });
+        let audio = thread::scope(|scope| {
+            let pumping = scope.spawn(|| pump(reader, chunk_tx));
+            let mut audio = vec![];
+            StreamReader::new(chunk_rx).read_to_end(&mut audio).unwrap();
+            pumping.join().unwrap();
+            audio
+        });
+        (audio, titles)
+    }
+
+    #[test]
+    fn relays_audio_and_titles() {
+        let sent = audio(1000);
+        let body = interleave(
+            &sent,
+            64,
+            &[
+                "StreamTitle='Artist - One';",
+                // Repeats of the current title aren't reported again
+                "StreamTitle='Artist - One';",
+                "",
+                "StreamTitle='Artist - Don't Stop';StreamUrl='http://example.com';",
+                // Blank titles are ignored
+                "StreamTitle='';",
+                "StreamTitle='Artist - One';",
+            ],
+        );
+        let (url, request) = serve(
+            "Content-Type: audio/mpeg\r\nicy-metaint: 64\r\nicy-name: Test FM\r\n",
+            body,
+        );
+
+        let station = connect(&url).unwrap();
+        assert!(request.recv().unwrap().contains("icy-metadata: 1"));
+        assert_eq!(station.ext, "mp3");
+        assert_eq!(station.metaint, Some(64));
+        assert_eq!(station.name.as_deref(), Some("Test FM"));
+
+        let (received, titles) = play(station);
+        assert_eq!(received, sent);
+        assert_eq!(
+            titles,
+            ["Artist - One", "Artist - Don't Stop", "Artist - One"]
+        );
+    }
+
+    #[test]
+    fn relays_audio_without_metadata() {
+        let sent = audio(100_000);
+        let (url, _) = serve("Content-Type: audio/ogg\r\n", sent.clone());
+
+        let station = connect(&url).unwrap();
+        assert_eq!(station.ext, "ogg");
+        assert_eq!(station.metaint, None);
+        assert_eq!(station.name, None);
+
+        let (received, titles) = play(station);
+        assert_eq!(received, sent);
+        assert!(titles.is_empty());
+    }
+
+    #[test]
+    fn rejects_streams_that_are_not_audio() {
+        let (url, _) = serve("Content-Type: text/html\r\n", b"<html></html>".to_vec());
+        assert!(connect(&url).is_err());
+    }
+
+    #[test]
+    fn stream_titles() {
+        let cases = [
+            ("StreamTitle='Artist - Title';", Some("Artist - Title")),
+            (
+                "StreamTitle='Artist - Title';StreamUrl='http://example.com';",
+                Some("Artist - Title"),
+            ),
+            ("StreamTitle='Guns N' Roses - Paradise City';", Some("Guns N' Roses - Paradise City")),
+            ("StreamTitle=' Padded ';\0\0\0", Some("Padded")),
+            // Some stations leave off the semicolon
+            ("StreamTitle='No Semicolon'", Some("No Semicolon")),
+            ("StreamTitle='';", None),
+            ("StreamUrl='http://example.com';", None),
+            ("", None),
+        ];
+        for (metadata, expected) in cases {
+            assert_eq!(stream_title(metadata).as_deref(), expected, "{metadata:?}");
+        }
+    }
+
+    #[test]
+    fn stream_reader_seeks_back_a_limited_distance() {
+        let (tx, rx) = mpsc::channel();
+        let sent = audio(CHUNK_SIZE * 40);
+        for chunk in sent.chunks(CHUNK_SIZE) {
+            tx.send(chunk.to_vec()).unwrap();
+        }
+        drop(tx);
+        let mut reader = StreamReader::new(rx);
+
+        // Probing a header and seeking back to the start
+        let mut header = [0; 100];
+        reader.read_exact(&mut header).unwrap();
+        assert_eq!(reader.seek(SeekFrom::Start(0)).unwrap(), 0);
+        let mut again = [0; 100];
+        reader.read_exact(&mut again).unwrap();
+        assert_eq!(header, again);
+        assert_eq!(reader.seek(SeekFrom::Current(-50)).unwrap(), 50);
+
+        // Only what has been received can be seeked to, and the end is never known
+        assert!(reader.seek(SeekFrom::Start(CHUNK_SIZE as u64 + 1)).is_err());
+        assert!(reader.seek(SeekFrom::End(0)).is_err());
+
+        // Reading on forgets what is too far behind
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, sent[50..]);
+        assert!(reader.seek(SeekFrom::Start(0)).is_err());
+        let back = sent.len() as u64 - REWIND_LIMIT as u64;
+        assert_eq!(reader.seek(SeekFrom::Start(back)).unwrap(), back);
+    }
+
+    /**
+     * Pulls samples from {engine} until one is {value}, giving up after a few seconds. Returns
+     * whether it was found.
+     * */
+    fn play_until(engine: &mut TransitionEngine, value: f32) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if engine.next() == Some(value) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /**
+     * A stand-in stream that plays {value} forever, and records when it is opened
+     * */
+    fn live_stream(value: f32, opened: &Arc<AtomicBool>) -> Opener {
+        let opened = opened.clone();
+        Box::new(move || {
+            opened.store(true, Ordering::SeqCst);
+            let source = SamplesBuffer::new(2, 44100, vec![value; 64]).repeat_infinite();
+            Ok(Box::new(source) as Box<dyn Source<Item = f32> + Send>)
+        })
+    }
+
+    fn song(value: f32, len: usize) -> Box<dyn Source<Item = f32> + Send> {
+        Box::new(SamplesBuffer::new(2, 44100, vec![value; len]))
+    }
+
+    fn info(song_id: u64, live: bool) -> TrackInfo {
+        TrackInfo {
+            song_id,
+            live,
+            ..Default::default()
+        }
+    }
+
+    fn engine() -> (TransitionEngine, TransitionHandle) {
+        TransitionEngine::new(TransitionConfig {
+            crossfade: Duration::ZERO,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn streams_are_opened_when_they_start() {
+        let (mut engine, handle) = engine();
+        let opened = Arc::new(AtomicBool::new(false));
+        handle.queue(song(0.5, 44100 * 2), info(1, false));
+        handle.queue_opener(live_stream(0.25, &opened), info(2, true));
+
+        assert!(play_until(&mut engine, 0.5));
+        assert!(!opened.load(Ordering::SeqCst), "stream opened while waiting in the queue");
+        assert!(play_until(&mut engine, 0.25));
+        assert!(opened.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn songs_queued_behind_a_stream_still_play() {
+        let (mut engine, handle) = engine();
+        let opened = Arc::new(AtomicBool::new(false));
+        handle.queue(song(0.5, 4096), info(1, false));
+        handle.queue_opener(live_stream(0.25, &opened), info(2, true));
+        handle.queue(song(1.0, 4096), info(3, false));
+
+        assert!(play_until(&mut engine, 0.5));
+        assert!(play_until(&mut engine, 1.0));
+        assert!(!opened.load(Ordering::SeqCst), "skipped stream was opened");
+    }
+
+    #[test]
+    fn queueing_a_song_ends_the_current_stream() {
+        let (mut engine, handle) = engine();
+        let opened = Arc::new(AtomicBool::new(false));
+        handle.queue_opener(live_stream(0.25, &opened), info(1, true));
+        assert!(play_until(&mut engine, 0.25));
+
+        handle.queue(song(1.0, 4096), info(2, false));
+        assert!(play_until(&mut engine, 1.0));
+    }
+}
+
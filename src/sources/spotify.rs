@@ -0,0 +1,948 @@
+use anyhow::{anyhow, Error};
+use lazy_static::lazy_static;
+use log::{log, Level};
+use reqwest::{Client, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::env;
+use std::future::Future;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+
+use crate::fingerprint::{format_artist_credits, ArtistCredit, SongMetadata};
+use crate::types::{Song, SourceInfo};
+
+lazy_static! {
+    static ref CLIENT_ID: Option<String> = env::var("SPOTIFY_CLIENT_ID").ok();
+    static ref CLIENT_SECRET: Option<String> = env::var("SPOTIFY_CLIENT_SECRET").ok();
+    static ref CLIENT: Client = reqwest::Client::new();
+    static ref TOKEN: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+    static ref RUNTIME: Runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("spotify")
+        .enable_all()
+        .build()
+        .expect("could not start the Spotify runtime");
+}
+
+const API_URL: &str = "https://api.spotify.com/v1";
+const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+const WEB_URL: &str = "https://open.spotify.com";
+
+/**
+ * Most tracks the Web API returns details for in one request
+ * */
+const TRACK_BATCH: usize = 50;
+
+/**
+ * Number of YouTube search results considered when matching a track
+ * */
+const SEARCH_RESULTS: usize = 8;
+
+/**
+ * How far a YouTube video's duration can be from the track's before it is considered a different
+ * recording
+ * */
+const DURATION_TOLERANCE: f64 = 7.0;
+
+/**
+ * Words that mark a video as a different version of a song, unless the track's own title has them
+ * */
+const ALTERNATE_VERSIONS: [&str; 10] = [
+    "live",
+    "cover",
+    "remix",
+    "karaoke",
+    "instrumental",
+    "acoustic",
+    "sped up",
+    "slowed",
+    "nightcore",
+    "8d",
+];
+
+/**
+ * Kinds of Spotify links that can be resolved to tracks
+ * */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    Track,
+    Album,
+    Playlist,
+}
+
+/**
+ * A link to a Spotify track, album or playlist
+ * */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpotifyLink {
+    pub kind: LinkKind,
+    pub id: String,
+}
+
+impl SpotifyLink {
+    /**
+     * Parses an open.spotify.com link (including localized "/intl-xx/" links) or a "spotify:"
+     * URI copied from the app
+     * */
+    pub fn parse(input: &str) -> Option<Self> {
+        let input = input.trim();
+        let parts: Vec<String> = match input.strip_prefix("spotify:") {
+            Some(uri) => uri.split(':').map(str::to_string).collect(),
+            None => {
+                let url = if input.contains("://") {
+                    Url::parse(input).ok()?
+                } else {
+                    Url::parse(&format!("https://{input}")).ok()?
+                };
+                if url.host_str()? != "open.spotify.com" {
+                    return None;
+                }
+                url.path_segments()?
+                    .filter(|s| !s.is_empty() && !s.starts_with("intl-"))
+                    .map(str::to_string)
+                    .collect()
+            }
+        };
+
+        let (kind, id) = match parts.as_slice() {
+            [kind, id, ..] => (kind.as_str(), id),
+            _ => return None,
+        };
+        let kind = match kind {
+            "track" => LinkKind::Track,
+            "album" => LinkKind::Album,
+            "playlist" => LinkKind::Playlist,
+            _ => return None,
+        };
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        Some(SpotifyLink {
+            kind,
+            id: id.to_string(),
+        })
+    }
+
+    /**
+     * Canonical open.spotify.com link
+     * */
+    pub fn url(&self) -> String {
+        self.url_on(WEB_URL)
+    }
+
+    /**
+     * Link to the same page on another host, such as a stand-in for Spotify
+     * */
+    fn url_on(&self, web: &str) -> String {
+        let kind = match self.kind {
+            LinkKind::Track => "track",
+            LinkKind::Album => "album",
+            LinkKind::Playlist => "playlist",
+        };
+        format!("{web}/{kind}/{}", self.id)
+    }
+}
+
+/**
+ * What Spotify knows about a track, used to find it elsewhere
+ * */
+#[derive(Debug, Clone, Default)]
+pub struct SpotifyTrack {
+    pub id: String,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub duration: Option<Duration>,
+    /**
+     * Only available through the Web API
+     * */
+    pub isrc: Option<String>,
+}
+
+impl SpotifyTrack {
+    /**
+     * Link to the track on Spotify
+     * */
+    pub fn url(&self) -> String {
+        SpotifyLink {
+            kind: LinkKind::Track,
+            id: self.id.clone(),
+        }
+        .url()
+    }
+
+    /**
+     * Song metadata built from the track's details
+     * */
+    pub fn metadata(&self) -> SongMetadata {
+        let artists = self
+            .artists
+            .iter()
+            .enumerate()
+            .map(|(i, name)| ArtistCredit {
+                name: name.clone(),
+                mbid: None,
+                join_phrase: if i + 1 < self.artists.len() {
+                    String::from(", ")
+                } else {
+                    String::new()
+                },
+            })
+            .collect::<Vec<_>>();
+        SongMetadata {
+            title: self.title.clone(),
+            artist: format_artist_credits(&artists),
+            artists,
+            album: self.album.clone().unwrap_or_default(),
+            duration: self.duration.map_or(0.0, |d| d.as_secs_f64()),
+            isrcs: self.isrc.iter().cloned().collect(),
+            ..SongMetadata::default()
+        }
+    }
+}
+
+/**
+ * Gets the tracks a Spotify link refers to. Uses the Web API if SPOTIFY_CLIENT_ID and
+ * SPOTIFY_CLIENT_SECRET are set, and the metadata in Spotify's public web pages otherwise.
+ * */
+pub async fn resolve(url: &str) -> Result<Vec<SpotifyTrack>, Error> {
+    let link = SpotifyLink::parse(url).ok_or_else(|| anyhow!("{} is not a Spotify link", url))?;
+    match token().await? {
+        Some(token) => resolve_api(&link, &token, API_URL).await,
+        None => resolve_page(&link, WEB_URL).await,
+    }
+}
+
+/**
+ * Gets the single track a Spotify track link refers to
+ * */
+pub async fn resolve_track(url: &str) -> Result<SpotifyTrack, Error> {
+    match SpotifyLink::parse(url) {
+        Some(link) if link.kind == LinkKind::Track => resolve(url)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Spotify track {} not found", link.id)),
+        _ => Err(anyhow!("{} is not a Spotify track", url)),
+    }
+}
+
+/**
+ * Gets the Spotify track a link refers to and the YouTube video it was matched to
+ * */
+pub async fn find_song(url: String) -> Result<(SpotifyTrack, Song), Error> {
+    let track = resolve_track(&url).await?;
+    let found = find_youtube(&track).await?;
+    Ok((track, found))
+}
+
+/**
+ * Runs a Spotify lookup to completion from synchronous code. Lookups run on their own runtime,
+ * so this works whether or not the caller is itself inside an async runtime.
+ * */
+pub fn block_on<T, F>(future: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: Future<Output = T> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    RUNTIME.spawn(async move {
+        let _ = tx.send(future.await);
+    });
+    rx.recv()
+        .map_err(|_| anyhow!("Spotify lookup stopped before finishing"))
+}
+
+/**
+ * Finds a YouTube video of a Spotify track. If the track's ISRC is known videos found by
+ * searching for it are tried first, then the artist and title are searched for. Candidates must
+ * be within a few seconds of the track's duration, and are ranked by how closely their titles
+ * and channels match the track.
+ * */
+pub async fn find_youtube(track: &SpotifyTrack) -> Result<Song, Error> {
+    if let Some(isrc) = &track.isrc {
+        let found = search(format!("\"{isrc}\"")).await?;
+        if let Some(song) = found
+            .into_iter()
+            // Unrelated videos can mention the code, so they still have to look like the track
+            .filter(|song| matches_duration(track, song) && score(track, song) > 0.0)
+            .max_by(|a, b| score(track, a).total_cmp(&score(track, b)))
+        {
+            log!(Level::Debug, "matched {} by ISRC {}", track.title, isrc);
+            return Ok(song);
+        }
+    }
+
+    let query = format!("{} - {}", track.artists.join(" "), track.title);
+    search(query)
+        .await?
+        .into_iter()
+        .filter(|song| matches_duration(track, song))
+        .map(|song| (score(track, &song), song))
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(score, song)| {
+            log!(Level::Debug, "matched {} with score {}", track.title, score);
+            song
+        })
+        .ok_or_else(|| anyhow!("No YouTube video found for {}", track.title))
+}
+
+async fn search(query: String) -> Result<Vec<Song>, Error> {
+    crate::search_async(&query, SEARCH_RESULTS).await
+}
+
+fn video_info(song: &Song) -> Option<&SourceInfo> {
+    song.source_info.as_ref()
+}
+
+/**
+ * Whether a video is close enough in length to be the track. Videos or tracks with unknown
+ * durations always match.
+ * */
+fn matches_duration(track: &SpotifyTrack, song: &Song) -> bool {
+    match (track.duration, video_info(song).and_then(|i| i.duration)) {
+        (Some(expected), Some(actual)) => {
+            (expected.as_secs_f64() - actual).abs() <= DURATION_TOLERANCE
+        }
+        _ => true,
+    }
+}
+
+/**
+ * Scores how likely a video is to be the track. Higher is better.
+ * */
+fn score(track: &SpotifyTrack, song: &Song) -> f64 {
+    let info = match video_info(song) {
+        Some(info) => info,
+        None => return 0.0,
+    };
+    let title = info.title.as_deref().unwrap_or_default().to_lowercase();
+    let channel = info.channel.as_deref().unwrap_or_default().to_lowercase();
+    let expected_title = track.title.to_lowercase();
+
+    let mut score = 0.0;
+    if title.contains(&expected_title) {
+        score += 3.0;
+    }
+    for artist in &track.artists {
+        let artist = artist.to_lowercase();
+        if channel.contains(&artist) {
+            score += 2.0;
+        } else if title.contains(&artist) {
+            score += 1.0;
+        }
+    }
+    // Auto-generated "Artist - Topic" channels host the studio recordings
+    if channel.ends_with(" - topic") {
+        score += 2.0;
+    }
+    for word in ALTERNATE_VERSIONS {
+        if title.contains(word) && !expected_title.contains(word) {
+            score -= 4.0;
+        }
+    }
+    if let (Some(expected), Some(actual)) = (track.duration, info.duration) {
+        score -= (expected.as_secs_f64() - actual).abs() / DURATION_TOLERANCE;
+    }
+    score
+}
+
+/**
+ * Gets a Web API access token using the client credentials flow, reusing the previous token until
+ * it expires. Returns None if no credentials are configured.
+ * */
+async fn token() -> Result<Option<String>, Error> {
+    let (id, secret) = match (CLIENT_ID.as_ref(), CLIENT_SECRET.as_ref()) {
+        (Some(id), Some(secret)) => (id, secret),
+        _ => return Ok(None),
+    };
+
+    let mut token = TOKEN.lock().await;
+    if let Some((token, expires)) = token.as_ref() {
+        if Instant::now() < *expires {
+            return Ok(Some(token.clone()));
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct TokenResponse {
+        access_token: String,
+        expires_in: u64,
+    }
+
+    log!(Level::Debug, "requesting Spotify access token");
+    let res: TokenResponse = CLIENT
+        .post(TOKEN_URL)
+        .basic_auth(id, Some(secret))
+        .form(&[("grant_type", "client_credentials")])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // Refresh a little early so a token doesn't expire mid-request
+    let expires = Instant::now() + Duration::from_secs(res.expires_in.saturating_sub(60));
+    *token = Some((res.access_token.clone(), expires));
+    Ok(Some(res.access_token))
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiTrack {
+    id: Option<String>,
+    name: String,
+    artists: Vec<ApiArtist>,
+    album: Option<ApiAlbum>,
+    duration_ms: u64,
+    external_ids: Option<ApiExternalIds>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiArtist {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiAlbum {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiExternalIds {
+    isrc: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiPage<T> {
+    items: Vec<T>,
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiAlbumTracks {
+    tracks: ApiPage<ApiTrackId>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiTrackId {
+    id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiPlaylistItem {
+    track: Option<ApiTrack>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiTracks {
+    tracks: Vec<Option<ApiTrack>>,
+}
+
+impl From<ApiTrack> for SpotifyTrack {
+    fn from(track: ApiTrack) -> Self {
+        SpotifyTrack {
+            id: track.id.unwrap_or_default(),
+            title: track.name,
+            artists: track.artists.into_iter().map(|a| a.name).collect(),
+            album: track.album.map(|a| a.name),
+            duration: Some(Duration::from_millis(track.duration_ms)),
+            isrc: track.external_ids.and_then(|ids| ids.isrc),
+        }
+    }
+}
+
+async fn api_get<T: DeserializeOwned>(url: &str, token: &str) -> Result<T, Error> {
+    log!(Level::Trace, "Spotify API request: {}", url);
+    Ok(CLIENT
+        .get(url)
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+/**
+ * Gets every item of a paginated Web API list, following it on from its {first} page
+ * */
+async fn api_pages<T: DeserializeOwned>(first: ApiPage<T>, token: &str) -> Result<Vec<T>, Error> {
+    let mut page = first;
+    let mut items = vec![];
+    loop {
+        items.append(&mut page.items);
+        match page.next.take() {
+            Some(next) => page = api_get(&next, token).await?,
+            None => return Ok(items),
+        }
+    }
+}
+
+/**
+ * Resolves a link through the Web API at {api}
+ * */
+async fn resolve_api(
+    link: &SpotifyLink,
+    token: &str,
+    api: &str,
+) -> Result<Vec<SpotifyTrack>, Error> {
+    match link.kind {
+        LinkKind::Track => {
+            let track: ApiTrack = api_get(&format!("{api}/tracks/{}", link.id), token).await?;
+            Ok(vec![track.into()])
+        }
+        LinkKind::Album => {
+            // Album track listings leave out ISRCs, so the full tracks are fetched separately
+            let album: ApiAlbumTracks =
+                api_get(&format!("{api}/albums/{}", link.id), token).await?;
+            let ids = api_pages(album.tracks, token)
+                .await?
+                .into_iter()
+                .filter_map(|t| t.id)
+                .collect::<Vec<_>>();
+            let mut tracks = vec![];
+            for batch in ids.chunks(TRACK_BATCH) {
+                let url = format!("{api}/tracks?ids={}", batch.join(","));
+                let res: ApiTracks = api_get(&url, token).await?;
+                tracks.extend(res.tracks.into_iter().flatten().map(SpotifyTrack::from));
+            }
+            Ok(tracks)
+        }
+        LinkKind::Playlist => {
+            let url = format!("{api}/playlists/{}/tracks?limit=100", link.id);
+            let first: ApiPage<ApiPlaylistItem> = api_get(&url, token).await?;
+            Ok(api_pages(first, token)
+                .await?
+                .into_iter()
+                .filter_map(|item| item.track)
+                // Local files in playlists have no ID and can't be found anywhere else
+                .filter(|track| track.id.is_some())
+                .map(SpotifyTrack::from)
+                .collect())
+        }
+    }
+}
+
+/**
+ * Resolves a link from the Open Graph and music metadata Spotify includes in its web pages,
+ * which are fetched from {web}
+ * */
+async fn resolve_page(link: &SpotifyLink, web: &str) -> Result<Vec<SpotifyTrack>, Error> {
+    match link.kind {
+        LinkKind::Track => Ok(vec![track_page(&link.id, web).await?]),
+        LinkKind::Album | LinkKind::Playlist => {
+            let meta = fetch_meta(&link.url_on(web)).await?;
+            let ids = meta
+                .iter()
+                .filter(|(name, _)| name == "music:song")
+                .filter_map(|(_, url)| SpotifyLink::parse(url))
+                .filter(|l| l.kind == LinkKind::Track)
+                .map(|l| l.id)
+                .collect::<Vec<_>>();
+            log!(Level::Debug, "{} lists {} tracks", link.url(), ids.len());
+
+            let mut tracks = vec![];
+            for id in ids {
+                match track_page(&id, web).await {
+                    Ok(track) => tracks.push(track),
+                    Err(e) => log!(Level::Warn, "could not resolve Spotify track {}: {}", id, e),
+                }
+            }
+            Ok(tracks)
+        }
+    }
+}
+
+async fn track_page(id: &str, web: &str) -> Result<SpotifyTrack, Error> {
+    let url = format!("{web}/track/{id}");
+    let meta = fetch_meta(&url).await?;
+    let get = |name: &str| {
+        meta.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, content)| content.clone())
+    };
+
+    let title = match get("og:title") {
+        Some(title) => title,
+        None => oembed_title(&url, web).await?,
+    };
+    let artists = get("music:musician_description")
+        .map(|a| a.split(", ").map(str::to_string).collect())
+        .unwrap_or_default();
+    let duration = get("music:duration")
+        .and_then(|d| d.parse::<u64>().ok())
+        .map(Duration::from_secs);
+
+    Ok(SpotifyTrack {
+        id: id.to_string(),
+        title,
+        artists,
+        album: None,
+        duration,
+        isrc: None,
+    })
+}
+
+/**
+ * Gets a track's title from Spotify's oEmbed endpoint, for when the page doesn't have it
+ * */
+async fn oembed_title(url: &str, web: &str) -> Result<String, Error> {
+    #[derive(Deserialize)]
+    struct OEmbed {
+        title: String,
+    }
+
+    let res: OEmbed = CLIENT
+        .get(format!("{web}/oembed"))
+        .query(&[("url", url)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(res.title)
+}
+
+async fn fetch_meta(url: &str) -> Result<Vec<(String, String)>, Error> {
+    log!(Level::Trace, "fetching Spotify page {}", url);
+    let html = CLIENT
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    Ok(meta_tags(&html))
+}
+
+/**
+ * Gets the name (or property) and content of every <meta> tag in a page
+ * */
+pub(crate) fn meta_tags(html: &str) -> Vec<(String, String)> {
+    html.split("<meta ")
+        .skip(1)
+        .filter_map(|tag| {
+            let tag = &tag[..tag.find('>')?];
+            let name = attribute(tag, "property").or_else(|| attribute(tag, "name"))?;
+            let content = attribute(tag, "content")?;
+            Some((name, content))
+        })
+        .collect()
+}
+
+/**
+ * Gets the unescaped value of a double quoted attribute within a tag
+ * */
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let pattern = format!("{name}=\"");
+    let start = tag
+        .match_indices(&pattern)
+        // Don't match the end of a longer attribute name, like "og:content="
+        .find(|(i, _)| *i == 0 || tag[..*i].ends_with(char::is_whitespace))?
+        .0
+        + pattern.len();
+    let len = tag[start..].find('"')?;
+    Some(unescape(&tag[start..start + len]))
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#x27;", "'")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::SongOrigin;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn fixture(name: &str) -> String {
+        let path = format!(
+            "{}/tests/fixtures/spotify/{name}",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{path}: {e}"))
+    }
+
+    /**
+     * Serves saved responses on a loopback port, standing in for Spotify. Requests are matched on
+     * their path and query, then on their path alone, and anything else is a 404. "{base}" in a
+     * response is replaced with the server's URL, which is returned.
+     * */
+    async fn serve(routes: &[(&str, &str)]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let routes = routes
+            .iter()
+            .map(|(target, file)| (target.to_string(), fixture(file).replace("{base}", &base)))
+            .collect::<HashMap<_, _>>();
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let target = request.split_whitespace().nth(1).unwrap_or_default();
+                let path = target.split('?').next().unwrap_or_default();
+                let response = match routes.get(target).or_else(|| routes.get(path)) {
+                    Some(body) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    ),
+                    None => String::from(
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    ),
+                };
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        base
+    }
+
+    fn link(kind: LinkKind, id: &str) -> SpotifyLink {
+        SpotifyLink {
+            kind,
+            id: id.to_string(),
+        }
+    }
+
+    fn ids(tracks: &[SpotifyTrack]) -> Vec<&str> {
+        tracks.iter().map(|t| t.id.as_str()).collect()
+    }
+
+    #[test]
+    fn meta_tags_from_saved_page() {
+        let tags = meta_tags(&fixture("track.html"));
+        let get = |name: &str| {
+            tags.iter()
+                .filter(|(n, _)| n == name)
+                .map(|(_, content)| content.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(get("og:title"), ["Under Pressure - Remastered 2011"]);
+        assert_eq!(
+            get("og:description"),
+            ["Queen & David Bowie · Hot Space (2011 Remaster) · Song · 1982"]
+        );
+        assert_eq!(get("music:duration"), ["248"]);
+        assert_eq!(get("music:musician_description"), ["Queen, David Bowie"]);
+        assert_eq!(get("music:musician").len(), 2);
+        // The charset tag has no name or property, so isn't listed
+        assert!(tags.iter().all(|(name, _)| !name.is_empty()));
+        assert_eq!(tags.len(), 15);
+    }
+
+    #[test]
+    fn attributes_are_matched_whole() {
+        let tags = meta_tags(r#"<meta data-property="x" property="og:title" content="It&#39;s"/>"#);
+        assert_eq!(tags, [(String::from("og:title"), String::from("It's"))]);
+    }
+
+    #[tokio::test]
+    async fn resolve_page_track() {
+        let base = serve(&[("/track/2fuCquhmrzHpu5xcA1ci9x", "track.html")]).await;
+        let tracks = resolve_page(&link(LinkKind::Track, "2fuCquhmrzHpu5xcA1ci9x"), &base)
+            .await
+            .unwrap();
+
+        assert_eq!(tracks.len(), 1);
+        let track = &tracks[0];
+        assert_eq!(track.id, "2fuCquhmrzHpu5xcA1ci9x");
+        assert_eq!(track.title, "Under Pressure - Remastered 2011");
+        assert_eq!(track.artists, ["Queen", "David Bowie"]);
+        assert_eq!(track.duration, Some(Duration::from_secs(248)));
+        assert_eq!(track.album, None);
+        assert_eq!(track.isrc, None);
+    }
+
+    #[tokio::test]
+    async fn resolve_page_album() {
+        let base = serve(&[
+            ("/album/3mnsR3Sxk7Ykq5PjbOZqYV", "album.html"),
+            ("/track/2fuCquhmrzHpu5xcA1ci9x", "track.html"),
+            ("/track/0ofHAoxe9vBkTCp2UQIavz", "track_untitled.html"),
+            ("/oembed", "oembed.json"),
+        ])
+        .await;
+        let tracks = resolve_page(&link(LinkKind::Album, "3mnsR3Sxk7Ykq5PjbOZqYV"), &base)
+            .await
+            .unwrap();
+
+        // The third track's page is missing, so it is left out
+        assert_eq!(
+            ids(&tracks),
+            ["2fuCquhmrzHpu5xcA1ci9x", "0ofHAoxe9vBkTCp2UQIavz"]
+        );
+        // The second page has no title, which comes from oEmbed instead
+        assert_eq!(tracks[1].title, "Body Language - Remastered 2011");
+        assert_eq!(tracks[1].artists, ["Queen"]);
+        assert_eq!(tracks[1].duration, Some(Duration::from_secs(242)));
+    }
+
+    #[tokio::test]
+    async fn resolve_page_missing_track() {
+        let base = serve(&[]).await;
+        assert!(resolve_page(&link(LinkKind::Track, "6hV0nK3kS1y4r8Wt2ZbQqR"), &base)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn resolve_api_track() {
+        let base = serve(&[("/tracks/2fuCquhmrzHpu5xcA1ci9x", "api_track.json")]).await;
+        let tracks = resolve_api(
+            &link(LinkKind::Track, "2fuCquhmrzHpu5xcA1ci9x"),
+            "token",
+            &base,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tracks.len(), 1);
+        let track = &tracks[0];
+        assert_eq!(track.title, "Under Pressure - Remastered 2011");
+        assert_eq!(track.artists, ["Queen", "David Bowie"]);
+        assert_eq!(track.album.as_deref(), Some("Hot Space (2011 Remaster)"));
+        assert_eq!(track.duration, Some(Duration::from_millis(248440)));
+        assert_eq!(track.isrc.as_deref(), Some("GBUM71029620"));
+
+        let metadata = track.metadata();
+        assert_eq!(metadata.artist, "Queen, David Bowie");
+        assert_eq!(metadata.isrcs, ["GBUM71029620"]);
+    }
+
+    #[tokio::test]
+    async fn resolve_api_album() {
+        let base = serve(&[
+            ("/albums/3mnsR3Sxk7Ykq5PjbOZqYV", "api_album.json"),
+            ("/albums/3mnsR3Sxk7Ykq5PjbOZqYV/tracks", "api_album_tracks.json"),
+            ("/tracks", "api_tracks.json"),
+        ])
+        .await;
+        let tracks = resolve_api(
+            &link(LinkKind::Album, "3mnsR3Sxk7Ykq5PjbOZqYV"),
+            "token",
+            &base,
+        )
+        .await
+        .unwrap();
+
+        // Every page of the listing is followed, and unavailable tracks are skipped
+        assert_eq!(
+            ids(&tracks),
+            ["4c5p9bBuXa8Q2mQdyjV9Jv", "2fuCquhmrzHpu5xcA1ci9x"]
+        );
+        // The full tracks have the ISRCs the listing leaves out
+        assert_eq!(tracks[0].isrc.as_deref(), Some("GBUM71029611"));
+    }
+
+    #[tokio::test]
+    async fn resolve_api_playlist() {
+        let base = serve(&[
+            (
+                "/playlists/37i9dQZF1DWXRqgorJj26U/tracks?limit=100",
+                "api_playlist.json",
+            ),
+            (
+                "/playlists/37i9dQZF1DWXRqgorJj26U/tracks?offset=100&limit=100",
+                "api_playlist_next.json",
+            ),
+        ])
+        .await;
+        let tracks = resolve_api(
+            &link(LinkKind::Playlist, "37i9dQZF1DWXRqgorJj26U"),
+            "token",
+            &base,
+        )
+        .await
+        .unwrap();
+
+        // Removed tracks and local files are left out
+        assert_eq!(
+            ids(&tracks),
+            ["2fuCquhmrzHpu5xcA1ci9x", "4u7EnebtmKWzUH433cf5Qv"]
+        );
+    }
+
+    fn video(title: &str, channel: &str, duration: f64) -> Song {
+        let mut song = Song::new(
+            SongOrigin::Youtube(format!("https://www.youtube.com/watch?v={title}")),
+            String::new(),
+        );
+        song.source_info = Some(SourceInfo {
+            title: Some(title.to_string()),
+            channel: Some(channel.to_string()),
+            duration: Some(duration),
+            ..SourceInfo::default()
+        });
+        song
+    }
+
+    #[test]
+    fn score_ranks_videos() {
+        let track = SpotifyTrack {
+            id: String::from("2fuCquhmrzHpu5xcA1ci9x"),
+            title: String::from("Under Pressure"),
+            artists: vec![String::from("Queen"), String::from("David Bowie")],
+            duration: Some(Duration::from_secs(248)),
+            ..SpotifyTrack::default()
+        };
+        let topic = video("Under Pressure", "Queen - Topic", 248.0);
+        let official = video(
+            "Queen & David Bowie - Under Pressure (Official Video)",
+            "Queen Official",
+            245.0,
+        );
+        let live = video("Under Pressure (Live at Wembley)", "Queen Official", 250.0);
+        let cover = video("Under Pressure - Acoustic Cover", "Some Busker", 248.0);
+        let unrelated = video("Another One Bites the Dust", "Queen Official", 248.0);
+
+        assert_eq!(score(&track, &topic), 7.0);
+        assert!(score(&track, &topic) > score(&track, &official));
+        assert!(score(&track, &official) > score(&track, &live));
+        assert!(score(&track, &official) > score(&track, &unrelated));
+        assert!(score(&track, &cover) < 0.0);
+
+        let mut unknown = topic.clone();
+        unknown.source_info = None;
+        assert_eq!(score(&track, &unknown), 0.0);
+    }
+
+    #[test]
+    fn score_keeps_versions_the_track_is() {
+        let track = SpotifyTrack {
+            title: String::from("Under Pressure - Live"),
+            artists: vec![String::from("Queen")],
+            ..SpotifyTrack::default()
+        };
+        let live = video("Under Pressure - Live", "Queen - Topic", 250.0);
+        assert_eq!(score(&track, &live), 7.0);
+    }
+
+    #[test]
+    fn matches_duration_within_tolerance() {
+        let track = SpotifyTrack {
+            duration: Some(Duration::from_secs(248)),
+            ..SpotifyTrack::default()
+        };
+        assert!(matches_duration(&track, &video("a", "b", 254.0)));
+        assert!(!matches_duration(&track, &video("a", "b", 256.0)));
+        assert!(matches_duration(&SpotifyTrack::default(), &video("a", "b", 256.0)));
+    }
+}
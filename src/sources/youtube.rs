@@ -0,0 +1,627 @@
+use anyhow::{anyhow, Error};
+use log::{log, Level};
+use reqwest::Url;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use youtube_dl::YoutubeDl;
+
+use super::growing::{self, Download};
+use super::{ensure_cache_dir, open_cached, AudioCodec, Fetched, SourceConfig};
+use crate::decode;
+use crate::types::SourceInfo;
+
+/**
+ * Hosts that serve YouTube videos under the usual paths
+ * */
+const YOUTUBE_HOSTS: [&str; 5] = [
+    "youtube.com",
+    "www.youtube.com",
+    "m.youtube.com",
+    "music.youtube.com",
+    "www.youtube-nocookie.com",
+];
+
+/**
+ * Host of YouTube's short links, where the path is the video ID
+ * */
+const SHORT_HOST: &str = "youtu.be";
+
+/**
+ * Path prefixes that are followed by the video ID
+ * */
+const ID_PATHS: [&str; 5] = ["shorts", "embed", "v", "live", "e"];
+
+/**
+ * Prefix of the IDs of mixes, the endless playlists YouTube generates from a song
+ * */
+const MIX_PREFIX: &str = "RD";
+
+/**
+ * A YouTube video, as identified by a link to it
+ * */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YoutubeUrl {
+    /**
+     * The 11 character video ID
+     * */
+    pub id: String,
+
+    /**
+     * Position the link starts playback at, from a `t` or `start` parameter
+     * */
+    pub start: Option<Duration>,
+}
+
+/**
+ * Reasons a link can't be turned into a YouTube video
+ * */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UrlError {
+    Malformed(String),
+    NotYoutube(String),
+    MissingVideoId,
+    InvalidVideoId(String),
+    InvalidTimestamp(String),
+}
+
+impl fmt::Display for UrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UrlError::Malformed(url) => write!(f, "\"{}\" is not a valid link", url),
+            UrlError::NotYoutube(host) => write!(f, "{} is not a YouTube link", host),
+            UrlError::MissingVideoId => write!(f, "The link does not point to a video"),
+            UrlError::InvalidVideoId(id) => write!(f, "\"{}\" is not a valid video ID", id),
+            UrlError::InvalidTimestamp(t) => write!(f, "\"{}\" is not a valid timestamp", t),
+        }
+    }
+}
+
+impl std::error::Error for UrlError {}
+
+impl YoutubeUrl {
+    /**
+     * Parses any supported YouTube link: watch pages on youtube.com, m.youtube.com and
+     * music.youtube.com, youtu.be short links, shorts, embeds and live streams. Playlist and
+     * tracking parameters are ignored. Links without a scheme are accepted.
+     * */
+    pub fn parse(input: &str) -> Result<Self, UrlError> {
+        let input = input.trim();
+        let with_scheme = if input.contains("://") {
+            input.to_string()
+        } else {
+            format!("https://{input}")
+        };
+        let url = Url::parse(&with_scheme).map_err(|_| UrlError::Malformed(input.to_string()))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(UrlError::Malformed(input.to_string()));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| UrlError::Malformed(input.to_string()))?
+            .to_lowercase();
+
+        let segments = url
+            .path_segments()
+            .map(|s| s.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let id = if is_short_host(&host) {
+            segments.first().map(|id| id.to_string())
+        } else if YOUTUBE_HOSTS.contains(&host.as_str()) {
+            match segments.as_slice() {
+                ["watch"] => url
+                    .query_pairs()
+                    .find(|(k, _)| k == "v")
+                    .map(|(_, v)| v.into_owned()),
+                [prefix, id, ..] if ID_PATHS.contains(prefix) => Some(id.to_string()),
+                _ => None,
+            }
+        } else {
+            return Err(UrlError::NotYoutube(host));
+        };
+        let id = id.ok_or(UrlError::MissingVideoId)?;
+        if !is_video_id(&id) {
+            return Err(UrlError::InvalidVideoId(id));
+        }
+
+        // Timestamps can be in the query (t=, start=) or, on older links, the fragment (#t=)
+        let timestamp = url
+            .query_pairs()
+            .find(|(k, _)| k == "t" || k == "start")
+            .map(|(_, v)| v.into_owned())
+            .or_else(|| {
+                url.fragment()
+                    .and_then(|f| f.strip_prefix("t="))
+                    .map(str::to_string)
+            });
+        let start = match timestamp {
+            Some(t) => parse_timestamp(&t)?,
+            None => None,
+        };
+
+        Ok(YoutubeUrl { id, start })
+    }
+
+    /**
+     * The canonical watch page link for the video. The start time is left out, so every link to
+     * the same video has the same canonical link.
+     * */
+    pub fn canonical(&self) -> String {
+        format!("https://www.youtube.com/watch?v={}", self.id)
+    }
+
+    /**
+     * A watch page link for the video that keeps its start time, if it has one
+     * */
+    pub fn link(&self) -> String {
+        match self.start {
+            Some(start) => format!(
+                "https://www.youtube.com/watch?v={}&t={}s",
+                self.id,
+                start.as_secs()
+            ),
+            None => self.canonical(),
+        }
+    }
+}
+
+/**
+ * Gets the canonical playlist link for a YouTube playlist or YouTube Music album link. Watch
+ * pages opened from a playlist count as links to the playlist, unless the playlist is a mix:
+ * YouTube adds those to links to a single song, and they go on endlessly.
+ * */
+pub fn playlist_url(input: &str) -> Option<String> {
+    let input = input.trim();
+    let url = if input.contains("://") {
+        Url::parse(input).ok()?
+    } else {
+        Url::parse(&format!("https://{input}")).ok()?
+    };
+    let host = url.host_str()?.to_lowercase();
+    if !YOUTUBE_HOSTS.contains(&host.as_str()) {
+        return None;
+    }
+
+    if let Some((_, list)) = url.query_pairs().find(|(k, _)| k == "list") {
+        if url.path() != "/playlist" && list.starts_with(MIX_PREFIX) {
+            return None;
+        }
+        return Some(format!("https://www.youtube.com/playlist?list={list}"));
+    }
+
+    // YouTube Music album pages aren't playlists, but yt-dlp expands them into their tracks
+    url.path()
+        .starts_with("/browse/")
+        .then(|| url.to_string())
+}
+
+/**
+ * Lists the videos in a YouTube playlist or album without downloading them. Only the information
+ * in the playlist itself is fetched, so titles and durations are available but not much else.
+ * */
+pub fn expand_playlist(url: &str, config: &SourceConfig) -> Result<Vec<SourceInfo>, Error> {
+    log!(Level::Debug, "Expanding playlist {}", url);
+    let playlist = YoutubeDl::new(url)
+        .youtube_dl_path(&config.ytdlp)
+        .socket_timeout("15")
+        .flat_playlist(true)
+        .run()?
+        .into_playlist()
+        .ok_or_else(|| anyhow!("{} is not a playlist", url))?;
+
+    let entries = playlist
+        .entries
+        .unwrap_or_default()
+        .iter()
+        // Deleted and private videos are listed without a usable ID
+        .filter(|video| is_video_id(&video.id))
+        .map(SourceInfo::from_video)
+        .collect::<Vec<_>>();
+    log!(Level::Debug, "Playlist {} has {} videos", url, entries.len());
+    Ok(entries)
+}
+
+/**
+ * Whether links on {host} are YouTube videos, including short links
+ * */
+pub fn is_youtube_host(host: &str) -> bool {
+    let host = host.to_lowercase();
+    is_short_host(&host) || YOUTUBE_HOSTS.contains(&host.as_str())
+}
+
+fn is_short_host(host: &str) -> bool {
+    host == SHORT_HOST || host.strip_prefix("www.") == Some(SHORT_HOST)
+}
+
+/**
+ * Gets the video ID from any supported YouTube link
+ * */
+pub fn video_id(url: &str) -> Option<String> {
+    YoutubeUrl::parse(url).ok().map(|u| u.id)
+}
+
+/**
+ * Checks that {id} looks like a YouTube video ID: 11 characters of URL-safe base64
+ * */
+fn is_video_id(id: &str) -> bool {
+    id.len() == 11
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/**
+ * Parses a YouTube timestamp, either plain seconds ("90", "90s") or units ("1h2m3s", "1m30s").
+ * A timestamp of zero is treated as no timestamp.
+ * */
+fn parse_timestamp(t: &str) -> Result<Option<Duration>, UrlError> {
+    let invalid = || UrlError::InvalidTimestamp(t.to_string());
+    if t.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(secs) = t.parse::<u64>() {
+        return Ok(Some(Duration::from_secs(secs)).filter(|d| !d.is_zero()));
+    }
+
+    let mut total = 0;
+    let mut number = String::new();
+    for c in t.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'h' | 'm' | 's' => {
+                let value: u64 = number.parse().map_err(|_| invalid())?;
+                number.clear();
+                total += value
+                    * match c {
+                        'h' => 3600,
+                        'm' => 60,
+                        _ => 1,
+                    };
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Some(Duration::from_secs(total)).filter(|d| !d.is_zero()))
+}
+
+/**
+ * Fetches a YouTube song, streaming it while it downloads if progressive playback is enabled and
+ * falling back to downloading the whole file otherwise
+ * */
+pub fn fetch(url: &str, config: &SourceConfig) -> Result<Fetched, Error> {
+    ensure_cache_dir(config)?;
+
+    if let Some(fetched) = video_id(url).and_then(|id| open_cached(&id, config)) {
+        return Ok(fetched);
+    }
+
+    // Only WebM can be decoded while it downloads. Transcoding to mp3 needs the whole file, and the
+    // MP4 reader has to know how long an m4a file is to find its index.
+    if config.progressive && config.codec == AudioCodec::Opus {
+        match stream_progressive(url, config) {
+            Ok(fetched) => return Ok(fetched),
+            Err(e) => log!(
+                Level::Warn,
+                "progressive download of {} failed, falling back to full download: {}",
+                url,
+                e
+            ),
+        }
+    }
+
+    download(url, config)
+}
+
+/**
+ * Downloads the whole song before decoding it. The audio is kept in the format YouTube serves it
+ * in unless the configured codec is mp3.
+ * */
+pub fn download(url: &str, config: &SourceConfig) -> Result<Fetched, Error> {
+    log!(Level::Debug, "Downloading Youtube video from {url}");
+    let mut dl = YoutubeDl::new(url);
+    dl.youtube_dl_path(&config.ytdlp)
+        .socket_timeout("15")
+        .format(config.codec.format())
+        .output_directory(&config.cache_dir)
+        .output_template("%(id)s.%(ext)s")
+        .download(true);
+    if config.codec == AudioCodec::Mp3 {
+        dl.extra_arg("-x").extra_arg("--audio-format").extra_arg("mp3");
+    }
+    let song_dl = dl.run()?;
+
+    log!(Level::Trace, "Getting id from data download");
+    let video = song_dl.into_single_video();
+    let info = video.as_ref().map(SourceInfo::from_video);
+    let id = match &info {
+        Some(info) => info.id.clone(),
+        None => video_id(url).ok_or_else(|| anyhow!("Could not find video id in {}", url))?,
+    };
+
+    let ext = match config.codec {
+        AudioCodec::Mp3 => Some(String::from("mp3")),
+        _ => video.and_then(|v| v.ext),
+    };
+    let path = match ext {
+        Some(ext) => format!("{}/{id}.{ext}", config.cache_dir),
+        None => open_cached(&id, config)
+            .map(|fetched| fetched.path)
+            .ok_or_else(|| anyhow!("Could not find downloaded file for {}", id))?,
+    };
+
+    log!(Level::Debug, "Converting output video to stream");
+    Ok(Fetched {
+        source: decode::open_file(&path)?,
+        path,
+        info,
+        download: None,
+    })
+}
+
+/**
+ * Starts downloading the song's Opus audio and decodes it as it arrives. The download is written
+ * next to its final path and moved into place once complete.
+ * */
+pub fn stream_progressive(url: &str, config: &SourceConfig) -> Result<Fetched, Error> {
+    let id = video_id(url).ok_or_else(|| anyhow!("Could not find video id in {}", url))?;
+
+    // The extension has to be known before the download starts, so unlike full downloads there is
+    // no fallback to other formats
+    let (format, ext) = ("bestaudio[acodec=opus][ext=webm]", "webm");
+    let download = Download::new(&format!("{}/{id}.{ext}", config.cache_dir));
+    log!(Level::Debug, "Streaming Youtube video from {url} into {}", download.part);
+    let mut child = Command::new(&config.ytdlp)
+        .args(["--quiet", "--no-playlist", "--socket-timeout", "15"])
+        .args(["-f", format, "-o", "-", url])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let (mut stdout, mut stderr) = match (child.stdout.take(), child.stderr.take()) {
+        (Some(stdout), Some(stderr)) => (stdout, stderr),
+        _ => return Err(anyhow!("Could not read yt-dlp output")),
+    };
+    let mut out = File::create(&download.part)?;
+
+    {
+        let download = download.clone();
+        thread::spawn(move || {
+            // Read alongside stdout so yt-dlp can't block on a full stderr pipe
+            let errors = thread::spawn(move || {
+                let mut errors = String::new();
+                let _ = stderr.read_to_string(&mut errors);
+                errors
+            });
+            let copied = growing::copy(&mut stdout, &mut out, &download.state);
+            let status = child.wait();
+            let errors = errors.join().unwrap_or_default();
+            let errors = errors.trim();
+            match (copied, status) {
+                (Ok(bytes), Ok(status)) if status.success() && bytes > 0 => {
+                    log!(
+                        Level::Debug,
+                        "Finished downloading {} ({} bytes)",
+                        download.path,
+                        bytes
+                    );
+                    if !errors.is_empty() {
+                        log!(Level::Debug, "yt-dlp output for {}: {}", download.path, errors);
+                    }
+                    download.finish();
+                }
+                (copied, status) => {
+                    log!(
+                        Level::Warn,
+                        "Download of {} failed: {:?} / {:?}: {}",
+                        download.path,
+                        copied.map_err(|e| e.to_string()),
+                        status.map_err(|e| e.to_string()),
+                        errors
+                    );
+                    download.fail();
+                }
+            }
+        });
+    }
+
+    let reader = download.open()?;
+    Ok(Fetched {
+        source: decode::open_reader(reader, Some(ext))?,
+        path: download.path.clone(),
+        info: None,
+        download: Some(download),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "dQw4w9WgXcQ";
+
+    #[test]
+    fn parses_video_links() {
+        let cases: &[(&str, Option<u64>)] = &[
+            // Watch pages
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ", None),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=RDdQw4w9WgXcQ", None),
+            ("http://youtube.com/watch?v=dQw4w9WgXcQ", None),
+            ("youtube.com/watch?v=dQw4w9WgXcQ", None),
+            ("https://m.youtube.com/watch?v=dQw4w9WgXcQ&feature=share", None),
+            ("https://music.youtube.com/watch?v=dQw4w9WgXcQ&si=AbCdEf", None),
+            ("https://www.youtube.com/watch?feature=youtu.be&v=dQw4w9WgXcQ", None),
+            // Short links
+            ("https://youtu.be/dQw4w9WgXcQ", None),
+            ("youtu.be/dQw4w9WgXcQ?si=AbCdEf", None),
+            ("https://www.youtu.be/dQw4w9WgXcQ", None),
+            // Shorts, embeds and live streams
+            ("https://www.youtube.com/shorts/dQw4w9WgXcQ", None),
+            ("https://www.youtube.com/embed/dQw4w9WgXcQ", None),
+            ("https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ?start=30", Some(30)),
+            ("https://www.youtube.com/live/dQw4w9WgXcQ?feature=share", None),
+            ("https://www.youtube.com/v/dQw4w9WgXcQ", None),
+            // Timestamps
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=90", Some(90)),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=90s", Some(90)),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=1h2m3s", Some(3723)),
+            ("https://youtu.be/dQw4w9WgXcQ?t=1m30s", Some(90)),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ#t=75", Some(75)),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ#t=1m15s", Some(75)),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=0", None),
+            // Playlist parameters are ignored
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PLabc123&index=2", None),
+            ("https://music.youtube.com/watch?v=dQw4w9WgXcQ&list=OLAK5uy_abc", None),
+            // Mixed case hosts
+            ("HTTPS://WWW.YouTube.COM/watch?v=dQw4w9WgXcQ", None),
+            ("YOUTU.BE/dQw4w9WgXcQ", None),
+            // Surrounding whitespace and trailing junk
+            ("  https://youtu.be/dQw4w9WgXcQ/  \n", None),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ#comments", None),
+            ("https://www.youtube.com/shorts/dQw4w9WgXcQ/extra?feature=share", None),
+        ];
+        for &(input, start) in cases {
+            let parsed = YoutubeUrl::parse(input).unwrap_or_else(|e| panic!("{input:?}: {e}"));
+            assert_eq!(parsed.id, ID, "{input:?}");
+            assert_eq!(parsed.start, start.map(Duration::from_secs), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_links() {
+        let cases = [
+            ("", UrlError::Malformed(String::new())),
+            (
+                "ftp://youtube.com/watch?v=dQw4w9WgXcQ",
+                UrlError::Malformed(String::from("ftp://youtube.com/watch?v=dQw4w9WgXcQ")),
+            ),
+            (
+                "https://vimeo.com/76979871",
+                UrlError::NotYoutube(String::from("vimeo.com")),
+            ),
+            (
+                "https://notyoutube.com/watch?v=dQw4w9WgXcQ",
+                UrlError::NotYoutube(String::from("notyoutube.com")),
+            ),
+            (
+                "https://youtube.com.example.com/watch?v=dQw4w9WgXcQ",
+                UrlError::NotYoutube(String::from("youtube.com.example.com")),
+            ),
+            ("https://www.youtube.com/watch", UrlError::MissingVideoId),
+            ("https://www.youtube.com/playlist?list=PLabc123", UrlError::MissingVideoId),
+            ("https://www.youtube.com/@RickAstleyYT", UrlError::MissingVideoId),
+            ("https://youtu.be/", UrlError::MissingVideoId),
+            (
+                "https://www.youtube.com/watch?v=dQw4w9WgXc",
+                UrlError::InvalidVideoId(String::from("dQw4w9WgXc")),
+            ),
+            (
+                "https://youtu.be/dQw4w9WgXcQQ",
+                UrlError::InvalidVideoId(String::from("dQw4w9WgXcQQ")),
+            ),
+            (
+                "https://www.youtube.com/shorts/dQw4w9WgX!Q",
+                UrlError::InvalidVideoId(String::from("dQw4w9WgX!Q")),
+            ),
+            (
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=abc",
+                UrlError::InvalidTimestamp(String::from("abc")),
+            ),
+            (
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=5m3",
+                UrlError::InvalidTimestamp(String::from("5m3")),
+            ),
+            (
+                "https://youtu.be/dQw4w9WgXcQ?t=",
+                UrlError::InvalidTimestamp(String::new()),
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(YoutubeUrl::parse(input), Err(expected), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn canonical_links_round_trip() {
+        for start in [None, Some(Duration::from_secs(90))] {
+            let url = YoutubeUrl {
+                id: ID.to_string(),
+                start,
+            };
+            assert_eq!(YoutubeUrl::parse(&url.link()), Ok(url));
+        }
+        let url = YoutubeUrl::parse("https://youtu.be/dQw4w9WgXcQ?t=1m30s").unwrap();
+        assert_eq!(url.link(), "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=90s");
+        assert_eq!(url.canonical(), "https://www.youtube.com/watch?v=dQw4w9WgXcQ");
+    }
+
+    #[test]
+    fn playlist_links() {
+        let cases = [
+            (
+                "https://www.youtube.com/playlist?list=PLabc123",
+                Some("https://www.youtube.com/playlist?list=PLabc123"),
+            ),
+            (
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PLabc123&index=2",
+                Some("https://www.youtube.com/playlist?list=PLabc123"),
+            ),
+            (
+                "https://music.youtube.com/playlist?list=OLAK5uy_abc",
+                Some("https://www.youtube.com/playlist?list=OLAK5uy_abc"),
+            ),
+            (
+                "WWW.YouTube.com/playlist?list=PLabc123",
+                Some("https://www.youtube.com/playlist?list=PLabc123"),
+            ),
+            (
+                "https://music.youtube.com/browse/MPREb_abc123",
+                Some("https://music.youtube.com/browse/MPREb_abc123"),
+            ),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ", None),
+            // Mixes added to a song's link are just the song
+            (
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=RDdQw4w9WgXcQ&start_radio=1",
+                None,
+            ),
+            ("https://music.youtube.com/watch?v=dQw4w9WgXcQ&list=RDAMVMdQw4w9WgXcQ", None),
+            // ... unless the mix itself was linked
+            (
+                "https://www.youtube.com/playlist?list=RDdQw4w9WgXcQ",
+                Some("https://www.youtube.com/playlist?list=RDdQw4w9WgXcQ"),
+            ),
+            // Mixes added to a song's link are just the song
+            (
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=RDdQw4w9WgXcQ&start_radio=1",
+                None,
+            ),
+            ("https://music.youtube.com/watch?v=dQw4w9WgXcQ&list=RDAMVMdQw4w9WgXcQ", None),
+            // ... unless the mix itself was linked
+            (
+                "https://www.youtube.com/playlist?list=RDdQw4w9WgXcQ",
+                Some("https://www.youtube.com/playlist?list=RDdQw4w9WgXcQ"),
+            ),
+            ("https://www.youtube.com/shorts/dQw4w9WgXcQ", None),
+            ("https://example.com/playlist?list=PLabc123", None),
+            ("not a link", None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(playlist_url(input).as_deref(), expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn youtube_hosts() {
+        for host in ["youtube.com", "WWW.YOUTUBE.COM", "music.youtube.com", "youtu.be"] {
+            assert!(is_youtube_host(host), "{host}");
+        }
+        for host in ["youtube.co", "evilyoutube.com", "youtu.be.example.com", ""] {
+            assert!(!is_youtube_host(host), "{host}");
+        }
+    }
+}
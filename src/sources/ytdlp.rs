@@ -0,0 +1,135 @@
+use anyhow::{anyhow, Error};
+use log::{log, Level};
+use std::fmt;
+use youtube_dl::YoutubeDl;
+
+use super::{ensure_cache_dir, open_cached, AudioCodec, Fetched, SourceConfig};
+use crate::types::SourceInfo;
+
+/**
+ * Format requested from yt-dlp. Opus is avoided where possible, but when it is all a site has it
+ * ends up extracted to an Ogg .opus file.
+ * */
+const FORMAT: &str = "bestaudio[acodec!=opus]/bestaudio/best";
+
+/**
+ * A link yt-dlp has looked up without downloading
+ * */
+pub struct Probed {
+    pub info: SourceInfo,
+
+    /**
+     * Name of the yt-dlp extractor that handles the link, in lowercase (e.g. "soundcloud")
+     * */
+    pub extractor: String,
+}
+
+/**
+ * Error for links handled by a yt-dlp extractor that isn't in the configured allowlist; contains
+ * the extractor's name
+ * */
+#[derive(Debug)]
+pub struct NotAllowed(pub String);
+
+impl fmt::Display for NotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Songs from {} are not allowed", self.0)
+    }
+}
+
+impl std::error::Error for NotAllowed {}
+
+/**
+ * Looks up a link with yt-dlp, failing if it isn't a single song or, with NotAllowed, if it is
+ * handled by an extractor that isn't allowed
+ * */
+pub fn probe(url: &str, config: &SourceConfig) -> Result<Probed, Error> {
+    log!(Level::Debug, "Probing {} with yt-dlp", url);
+    let video = YoutubeDl::new(url)
+        .youtube_dl_path(&config.ytdlp)
+        .socket_timeout("15")
+        .extra_arg("--no-playlist")
+        .run()?
+        .into_single_video()
+        .ok_or_else(|| anyhow!("{} is not a single song", url))?;
+
+    let extractor = video
+        .extractor
+        .as_deref()
+        .or(video.extractor_key.as_deref())
+        .map(extractor_name)
+        .ok_or_else(|| anyhow!("yt-dlp did not report which site {} is from", url))?;
+    if !is_allowed(&extractor, config) {
+        return Err(NotAllowed(extractor).into());
+    }
+
+    Ok(Probed {
+        info: SourceInfo::from_video(&video),
+        extractor,
+    })
+}
+
+/**
+ * Whether songs handled by {extractor} may be downloaded
+ * */
+pub fn is_allowed(extractor: &str, config: &SourceConfig) -> bool {
+    let name = extractor_name(extractor);
+    config.extractors.iter().any(|e| e.eq_ignore_ascii_case(&name))
+}
+
+/**
+ * Downloads a song from any site yt-dlp supports, or opens it from the cache if it has been
+ * downloaded before
+ * */
+pub fn fetch(url: &str, config: &SourceConfig) -> Result<Fetched, Error> {
+    ensure_cache_dir(config)?;
+    let probed = probe(url, config)?;
+    let name = cache_name(&probed);
+
+    if let Some(mut fetched) = open_cached(&name, config) {
+        fetched.info = Some(probed.info);
+        return Ok(fetched);
+    }
+
+    log!(Level::Debug, "Downloading {} from {}", url, probed.extractor);
+    let mut dl = YoutubeDl::new(url);
+    dl.youtube_dl_path(&config.ytdlp)
+        .socket_timeout("15")
+        .format(FORMAT)
+        .output_directory(&config.cache_dir)
+        .output_template(format!("{name}.%(ext)s"))
+        .download(true)
+        .extra_arg("--no-playlist")
+        // Some sites only serve video, so the audio has to be pulled out of it
+        .extra_arg("-x");
+    if config.codec == AudioCodec::Mp3 {
+        dl.extra_arg("--audio-format").extra_arg("mp3");
+    }
+    dl.run()?;
+
+    let mut fetched = open_cached(&name, config)
+        .ok_or_else(|| anyhow!("Could not find or decode downloaded file for {}", url))?;
+    fetched.info = Some(probed.info);
+    Ok(fetched)
+}
+
+/**
+ * Strips the sub-extractor from an extractor name, so "soundcloud:set" is treated as "soundcloud"
+ * */
+fn extractor_name(extractor: &str) -> String {
+    extractor
+        .split(':')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/**
+ * Name a song is cached under. IDs are only unique within a site, so the extractor is included.
+ * */
+fn cache_name(probed: &Probed) -> String {
+    format!("{}-{}", probed.extractor, probed.info.id)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
@@ -1,7 +1,24 @@
 #![allow(dead_code)]
 
-use crate::fingerprint::SongMetadata;
-use std::collections::VecDeque;
+use crate::admin::{AuditEntry, BanList, Role};
+use crate::autoplay::Autoplay;
+use crate::dedup::{Duplicate, DuplicateConfig};
+use crate::federated::SearchConfig;
+use crate::fingerprint::chromaprint::FingerprintData;
+use crate::fingerprint::{ArtistCredit, SongMetadata};
+use crate::library::Library;
+use crate::limits::QueueLimits;
+use crate::moderation::ModerationConfig;
+use crate::player::loudness::LoudnessInfo;
+use crate::player::silence::{SilenceInfo, TrimPoints};
+use crate::sources::growing::Download;
+use crate::votes::VoteConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 /**
  * The global song queue, which will pull songs from each user into the global
@@ -18,8 +35,267 @@ pub struct GlobalQueue {
      * from next.
      * */
     pub users: VecDeque<UserQueue>,
+
+    /**
+     * Songs that have been played, oldest first. Only the last MAX_HISTORY
+     * are kept.
+     * */
+    pub history: VecDeque<HistoryEntry>,
+
+    /**
+     * How duplicate submissions are detected and handled
+     * */
+    pub duplicates: DuplicateConfig,
+
+    /**
+     * Users currently listening, used to determine how many votes are
+     * needed to skip a song
+     * */
+    pub listeners: HashSet<String>,
+
+    /**
+     * How skip votes are counted and how skips affect scheduling
+     * */
+    pub votes: VoteConfig,
+
+    /**
+     * Scheduling weight of each user who has had a song skipped, from
+     * VoteConfig::min_weight (at least votes::MIN_WEIGHT) - 1.0. Users not
+     * present have a weight of 1.0.
+     * */
+    pub user_weights: HashMap<String, f64>,
+
+    /**
+     * Limits on how much each user can queue
+     * */
+    pub limits: QueueLimits,
+
+    /**
+     * Times of each user's recent submissions, oldest first, used for rate
+     * limiting. Kept here rather than on UserQueue since empty user queues
+     * are dropped.
+     * */
+    pub submissions: HashMap<String, VecDeque<SystemTime>>,
+
+    /**
+     * Roles of users who aren't default_role
+     * */
+    pub roles: HashMap<String, Role>,
+
+    /**
+     * Role of users not present in roles
+     * */
+    pub default_role: Role,
+
+    /**
+     * Users, sources and recordings that may not be queued
+     * */
+    pub bans: BanList,
+
+    /**
+     * Whether the queue is locked. Only admins can submit songs to a locked
+     * queue.
+     * */
+    pub locked: bool,
+
+    /**
+     * Log of all admin actions, oldest first
+     * */
+    pub audit_log: Vec<AuditEntry>,
+
+    /**
+     * Blocklists and content filters checked against submitted songs
+     * */
+    pub moderation: ModerationConfig,
+
+    /**
+     * Messages waiting to be delivered to users, oldest first
+     * */
+    pub notifications: VecDeque<Notification>,
+
+    /**
+     * What to play when every queue is empty
+     * */
+    pub autoplay: Autoplay,
+
+    /**
+     * Local music library, if one is configured
+     * */
+    pub library: Option<Arc<Library>>,
+
+    /**
+     * How searches across YouTube, the library and history are run
+     * */
+    pub search: SearchConfig,
+}
+
+/**
+ * A message to be delivered to a user
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    /**
+     * The user the message is for
+     * */
+    pub user_id: String,
+
+    /**
+     * The message
+     * */
+    pub message: String,
+}
+
+/**
+ * A song that was taken from the global queue to be played
+ * */
+#[derive(Clone)]
+pub struct HistoryEntry {
+    /**
+     * The song that was played
+     * */
+    pub song: Song,
+
+    /**
+     * When the song was taken from the queue
+     * */
+    pub played_at: SystemTime,
+
+    /**
+     * Whether the song has stopped playing. Only the most recent entry can
+     * be unfinished, and is the song currently playing.
+     * */
+    pub finished: bool,
+
+    /**
+     * Whether the song was skipped before it finished
+     * */
+    pub skipped: bool,
+}
+
+/**
+ * Result of successfully submitting a song to the queue
+ * */
+#[derive(Debug)]
+pub enum EnqueueOutcome {
+    /**
+     * The song was added to the user's queue
+     * */
+    Queued,
+
+    /**
+     * The song was added to the user's queue, but duplicates another song
+     * */
+    QueuedDuplicate(Duplicate),
+
+    /**
+     * The song was not queued, and was instead counted as an upvote of the song it duplicates
+     * */
+    Merged(Duplicate),
+}
+
+/**
+ * Reason a song submission was rejected
+ * */
+#[derive(Debug)]
+pub enum EnqueueError {
+    /**
+     * The song duplicates one already queued or recently played
+     * */
+    Duplicate(Duplicate),
+
+    /**
+     * The user's queue already holds the maximum number of songs
+     * */
+    QueueFull { max: usize },
+
+    /**
+     * Adding the song would put the user's queue over the maximum total
+     * duration
+     * */
+    QueueTooLong { total: Duration, max: Duration },
+
+    /**
+     * The song is longer than the maximum song duration
+     * */
+    SongTooLong { duration: Duration, max: Duration },
+
+    /**
+     * The user has made too many submissions recently; contains how long
+     * until they can submit again
+     * */
+    RateLimited { retry_after: Duration },
+
+    /**
+     * The user doesn't have a role that allows submitting songs
+     * */
+    NotPermitted,
+
+    /**
+     * The queue is locked by an admin
+     * */
+    QueueLocked,
+
+    /**
+     * The user is banned from submitting songs
+     * */
+    UserBanned,
+
+    /**
+     * The song's source or recording is banned
+     * */
+    SongBanned,
+
+    /**
+     * The song was rejected by moderation; contains the reason
+     * */
+    Blocked(String),
+
+    /**
+     * The song's origin couldn't be parsed; contains the reason
+     * */
+    InvalidOrigin(String),
 }
 
+impl fmt::Display for EnqueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnqueueError::Duplicate(dup) => write!(
+                f,
+                "Song duplicates one submitted by {} ({:?})",
+                dup.submitter, dup.location
+            ),
+            EnqueueError::QueueFull { max } => {
+                write!(f, "Queue is full (maximum {} songs)", max)
+            }
+            EnqueueError::QueueTooLong { total, max } => write!(
+                f,
+                "Queue would be {}s long (maximum {}s)",
+                total.as_secs(),
+                max.as_secs()
+            ),
+            EnqueueError::SongTooLong { duration, max } => write!(
+                f,
+                "Song is {}s long (maximum {}s)",
+                duration.as_secs(),
+                max.as_secs()
+            ),
+            EnqueueError::RateLimited { retry_after } => write!(
+                f,
+                "Too many submissions, try again in {}s",
+                retry_after.as_secs()
+            ),
+            EnqueueError::NotPermitted => write!(f, "You are not allowed to submit songs"),
+            EnqueueError::QueueLocked => write!(f, "The queue is locked"),
+            EnqueueError::UserBanned => write!(f, "You are banned from submitting songs"),
+            EnqueueError::SongBanned => write!(f, "This song is banned"),
+            EnqueueError::Blocked(reason) => write!(f, "Song was blocked: {}", reason),
+            EnqueueError::InvalidOrigin(reason) => write!(f, "Invalid song link: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for EnqueueError {}
+
 /**
  * A user's song queue, to be filtered into the global queue in a manner tbd
  * */
@@ -38,6 +314,12 @@ pub struct UserQueue {
      * Whether the user's songs should be selected in a random order
      * */
     pub shuffle: bool,
+
+    /**
+     * Accumulated scheduling weight. A song is taken from the user whenever
+     * this reaches 1.0.
+     * */
+    pub credit: f64,
 }
 
 impl Default for UserQueue {
@@ -46,6 +328,7 @@ impl Default for UserQueue {
             user_id: "".to_string(),
             q: vec![].into(),
             shuffle: false,
+            credit: 0.0,
         }
     }
 }
@@ -54,7 +337,14 @@ impl Default for UserQueue {
  * A Song, with information about how to retrieve it, as well as
  * assocated metadata such as artist, title, album cover, etc.
  * */
+#[derive(Clone)]
 pub struct Song {
+    /**
+     * Unique identifier of this song within the jukebox, used to refer to
+     * it once queued
+     * */
+    pub id: u64,
+
     /**
      * The origin of the song, necessary for getting the audio data
      * */
@@ -65,53 +355,223 @@ pub struct Song {
      * */
     pub submitter: String,
 
+    /**
+     * Whether the song was picked by autoplay rather than submitted by a
+     * user
+     * */
+    pub autoplay: bool,
+
     pub metadata: Option<SongMetadata>,
 
     pub path: Option<String>,
+
+    /**
+     * Download of the song's audio, if it started playing before the
+     * download finished. The file is only at {path} once it completes.
+     * */
+    pub download: Option<Download>,
+
+    /**
+     * Audio fingerprint of the song, calculated when metadata is fetched
+     * */
+    pub fingerprint: Option<FingerprintData>,
+
+    /**
+     * Users who upvoted this song, including users whose duplicate
+     * submissions were merged into it
+     * */
+    pub upvotes: HashSet<String>,
+
+    /**
+     * Users who downvoted this song
+     * */
+    pub downvotes: HashSet<String>,
+
+    /**
+     * Users who voted to skip this song while it was playing
+     * */
+    pub skip_votes: HashSet<String>,
+
+    /**
+     * Information about the song reported by its source, such as the
+     * uploading channel. Filled in when the song is downloaded.
+     * */
+    pub source_info: Option<SourceInfo>,
+
+    /**
+     * Measured loudness of the song, used to normalize its volume
+     * */
+    pub loudness: Option<LoudnessInfo>,
+
+    /**
+     * Manually chosen start and end points, set when the song is submitted.
+     * Take priority over automatically detected silence.
+     * */
+    pub trim: TrimPoints,
+
+    /**
+     * Detected leading and trailing silence of the song
+     * */
+    pub silence: Option<SilenceInfo>,
+
+    /**
+     * Origin the song is actually played from, for origins that can't be
+     * played directly (e.g. a YouTube video matching a Spotify track)
+     * */
+    pub resolved: Option<SongOrigin>,
+}
+
+/**
+ * Information about a song reported by the site it was downloaded from
+ * */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SourceInfo {
+    /**
+     * The site's ID for the song (e.g. the YouTube video ID)
+     * */
+    pub id: String,
+
+    /**
+     * Title of the upload
+     * */
+    pub title: Option<String>,
+
+    /**
+     * Name of the channel the song was uploaded to
+     * */
+    pub channel: Option<String>,
+
+    /**
+     * ID of the channel the song was uploaded to
+     * */
+    pub channel_id: Option<String>,
+
+    /**
+     * Duration of the song in seconds
+     * */
+    pub duration: Option<f64>,
+
+    /**
+     * Minimum age required to view the upload, if age restricted
+     * */
+    pub age_limit: Option<i64>,
+}
+
+static NEXT_SONG_ID: AtomicU64 = AtomicU64::new(1);
+
+/**
+ * Allocates a new unique song id
+ * */
+pub(crate) fn next_song_id() -> u64 {
+    NEXT_SONG_ID.fetch_add(1, Ordering::Relaxed)
 }
 
 impl Default for Song {
     fn default() -> Self {
         Song {
+            id: next_song_id(),
             origin: SongOrigin::Youtube("https://www.youtube.com/watch?v=dQw4w9WgXcQ".to_string()),
             submitter: "joeneil".to_string(),
+            autoplay: false,
             metadata: None,
             path: None,
+            download: None,
+            fingerprint: None,
+            upvotes: HashSet::new(),
+            downvotes: HashSet::new(),
+            skip_votes: HashSet::new(),
+            source_info: None,
+            loudness: None,
+            trim: TrimPoints::default(),
+            silence: None,
+            resolved: None,
         }
     }
 }
 
+/**
+ * Display information for a single queued song, as shown in the queue UI
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueEntry {
+    /**
+     * Song title, or None if metadata has not been fetched yet
+     * */
+    pub title: Option<String>,
+
+    /**
+     * Formatted display string for all credited artists
+     * */
+    pub artist: Option<String>,
+
+    /**
+     * Structured artist credits, in credited order
+     * */
+    pub artists: Vec<ArtistCredit>,
+
+    /**
+     * The user who submitted the song to the queue
+     * */
+    pub submitter: String,
+}
+
 /**
  * Enum for all supported and planned audio sources
  * */
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum SongOrigin {
     /**
-     * Song originates from youtube; contained value is the full url
+     * Song originates from youtube; contained value is the full url. Songs
+     * are canonicalized to a watch page url when they are enqueued, and any
+     * timestamp in the url becomes the song's start point.
      * */
     Youtube(String),
 
     /**
      * Song originates from Spotify; contained value is the full url
      *
-     * Spotify support is not currently implemented, and will be implemented
-     * at a later date.
+     * Spotify tracks aren't streamed from Spotify. Songs are resolved with
+     * Song::resolve, which finds the track on YouTube, when they are queued
+     * or played.
      * */
     Spotify(String),
 
     /**
      * Song originates from Soundcloud; contained value is the full url
      *
-     * Soundcloud support is not currently implemented, and is postponed
-     * indefinitely as Soundcloud has stopped allowing new 3rd party
-     * applications API access since 2019.
+     * Soundcloud stopped giving new 3rd party applications API access in
+     * 2019, so songs are downloaded through yt-dlp the same as Ytdlp songs.
      * */
     Soundcloud(String),
 
     /**
-     * Song is a user submitted file; contained value if the full local path.
+     * Song originates from any other site yt-dlp can download from;
+     * contained value is the full url. Only sites whose extractor is in
+     * the source configuration's allowlist can be played.
+     * */
+    Ytdlp(String),
+
+    /**
+     * Song is an audio file on a web server; contained value is the direct
+     * link to it
+     * */
+    Http(String),
+
+    /**
+     * Song is an endless internet radio stream (e.g. Icecast or
+     * Shoutcast); contained value is the stream url. Radio plays until
+     * another song is queued.
+     * */
+    Radio(String),
+
+    /**
+     * Song is a local file, either uploaded by a user or a track from the
+     * music library; contained value is the full local path.
      *
-     * Supports several common filetypes transparently. Current supported list
-     * is:
+     * The decoder is chosen from the file's extension (see
+     * `decode::open_file`). Current supported list is:
+     *  - Opus, in WebM (.webm, .weba, .mka) or Ogg (.opus)
+     *  - AAC, in MP4 (.m4a, .mp4)
      *  - FLAC
      *  - MP3
      *  - Vorbis
@@ -0,0 +1,386 @@
+use log::{log, Level};
+use serde::{Deserialize, Serialize};
+
+use crate::types::{GlobalQueue, Song};
+
+/**
+ * Lowest scheduling weight allowed, whatever VoteConfig::min_weight is set to, so a user can't be
+ * reduced to never being scheduled
+ * */
+pub const MIN_WEIGHT: f64 = 0.05;
+
+/**
+ * Configuration for skip votes and how skipped songs affect scheduling
+ * */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoteConfig {
+    /**
+     * Fraction of active listeners that must vote to skip a song, from 0.0 - 1.0
+     * */
+    pub skip_ratio: f64,
+
+    /**
+     * Minimum number of votes needed to skip a song, regardless of listener count
+     * */
+    pub min_skip_votes: usize,
+
+    /**
+     * Multiplier applied to a submitter's scheduling weight each time one of their songs is
+     * skipped by vote
+     * */
+    pub skip_penalty: f64,
+
+    /**
+     * Amount a submitter's scheduling weight recovers each time one of their songs plays without
+     * being skipped
+     * */
+    pub recovery: f64,
+
+    /**
+     * Lowest scheduling weight a submitter can be reduced to. Values below MIN_WEIGHT are treated
+     * as MIN_WEIGHT so every user is eventually scheduled.
+     * */
+    pub min_weight: f64,
+}
+
+impl Default for VoteConfig {
+    fn default() -> Self {
+        VoteConfig {
+            skip_ratio: 0.5,
+            min_skip_votes: 1,
+            skip_penalty: 0.75,
+            recovery: 0.1,
+            min_weight: 0.25,
+        }
+    }
+}
+
+/**
+ * A reaction a user can leave on a song
+ * */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reaction {
+    Upvote,
+    Downvote,
+}
+
+/**
+ * Result of voting to skip the current song
+ * */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SkipVote {
+    /**
+     * No song is currently playing
+     * */
+    NothingPlaying,
+
+    /**
+     * The vote was counted but the threshold hasn't been reached yet
+     * */
+    Counted { votes: usize, needed: usize },
+
+    /**
+     * The user isn't listening, so can't vote
+     * */
+    NotListening,
+
+    /**
+     * The user had already voted to skip this song
+     * */
+    AlreadyVoted { votes: usize, needed: usize },
+
+    /**
+     * The song was skipped. The player should stop it and move on to the next song.
+     * */
+    Skipped,
+}
+
+impl Song {
+    /**
+     * Records a user's reaction to this song, replacing any previous reaction from that user
+     * */
+    pub fn react(&mut self, user_id: &str, reaction: Reaction) {
+        match reaction {
+            Reaction::Upvote => {
+                self.downvotes.remove(user_id);
+                self.upvotes.insert(user_id.to_string());
+            }
+            Reaction::Downvote => {
+                self.upvotes.remove(user_id);
+                self.downvotes.insert(user_id.to_string());
+            }
+        }
+    }
+
+    /**
+     * Removes a user's reaction from this song
+     * */
+    pub fn clear_reaction(&mut self, user_id: &str) {
+        self.upvotes.remove(user_id);
+        self.downvotes.remove(user_id);
+    }
+
+    /**
+     * Net reaction score of this song
+     * */
+    pub fn score(&self) -> i64 {
+        self.upvotes.len() as i64 - self.downvotes.len() as i64
+    }
+}
+
+impl GlobalQueue {
+    /**
+     * Marks a user as actively listening
+     * */
+    pub fn join_listener(&mut self, user_id: &str) {
+        self.listeners.insert(user_id.to_string());
+    }
+
+    /**
+     * Marks a user as no longer listening
+     * */
+    pub fn leave_listener(&mut self, user_id: &str) {
+        self.listeners.remove(user_id);
+    }
+
+    /**
+     * Gets the song currently playing, if any
+     * */
+    pub fn now_playing(&self) -> Option<&Song> {
+        self.history
+            .back()
+            .filter(|entry| !entry.finished)
+            .map(|entry| &entry.song)
+    }
+
+    /**
+     * Gets the song currently playing mutably, if any
+     * */
+    pub fn now_playing_mut(&mut self) -> Option<&mut Song> {
+        self.history
+            .back_mut()
+            .filter(|entry| !entry.finished)
+            .map(|entry| &mut entry.song)
+    }
+
+    /**
+     * Number of skip votes needed to skip the current song with the current listeners
+     * */
+    pub fn skip_votes_needed(&self) -> usize {
+        let by_ratio = (self.listeners.len() as f64 * self.votes.skip_ratio).ceil() as usize;
+        by_ratio.max(self.votes.min_skip_votes)
+    }
+
+    /**
+     * Votes to skip the current song. Each listener gets one vote, and the song is skipped once
+     * enough listeners have voted, or immediately if the submitter votes. Only users who are
+     * listening can vote, apart from the submitter, and votes from users who have since stopped
+     * listening no longer count.
+     * */
+    pub fn vote_skip(&mut self, user_id: &str) -> SkipVote {
+        let needed = self.skip_votes_needed();
+        let listeners = &self.listeners;
+        let song = match self.history.back_mut().filter(|entry| !entry.finished) {
+            Some(entry) => &mut entry.song,
+            None => return SkipVote::NothingPlaying,
+        };
+
+        let submitter = song.submitter == user_id;
+        if !submitter && !listeners.contains(user_id) {
+            return SkipVote::NotListening;
+        }
+        let inserted = song.skip_votes.insert(user_id.to_string());
+        let votes = song
+            .skip_votes
+            .iter()
+            .filter(|voter| listeners.contains(*voter))
+            .count();
+        if !inserted {
+            return SkipVote::AlreadyVoted { votes, needed };
+        }
+
+        if submitter {
+            log!(Level::Debug, "{} skipped their own song", user_id);
+            self.skip_current(false);
+            SkipVote::Skipped
+        } else if votes >= needed {
+            log!(Level::Debug, "song skipped with {}/{} votes", votes, needed);
+            self.skip_current(true);
+            SkipVote::Skipped
+        } else {
+            SkipVote::Counted { votes, needed }
+        }
+    }
+
+    /**
+     * Records a reaction to the current song. Returns false if nothing is playing.
+     * */
+    pub fn react_current(&mut self, user_id: &str, reaction: Reaction) -> bool {
+        match self.now_playing_mut() {
+            Some(song) => {
+                song.react(user_id, reaction);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /**
+     * Marks the current song as skipped. If {penalize} is set the submitter's scheduling weight
+     * is reduced.
+     * */
+    pub(crate) fn skip_current(&mut self, penalize: bool) {
+        let entry = match self.history.back_mut().filter(|entry| !entry.finished) {
+            Some(entry) => entry,
+            None => return,
+        };
+        entry.finished = true;
+        entry.skipped = true;
+
+        if penalize {
+            let submitter = entry.song.submitter.clone();
+            let weight = (self.weight_of(&submitter) * self.votes.skip_penalty)
+                .max(self.min_weight());
+            self.user_weights.insert(submitter, weight);
+        }
+    }
+
+    /**
+     * Marks the current song as finished without being skipped, letting the submitter's
+     * scheduling weight recover
+     * */
+    pub(crate) fn finish_current(&mut self) {
+        let entry = match self.history.back_mut().filter(|entry| !entry.finished) {
+            Some(entry) => entry,
+            None => return,
+        };
+        entry.finished = true;
+
+        let submitter = entry.song.submitter.clone();
+        if let Some(weight) = self.user_weights.get_mut(&submitter) {
+            *weight += self.votes.recovery;
+            if *weight >= 1.0 {
+                self.user_weights.remove(&submitter);
+            }
+        }
+    }
+
+    /**
+     * Gets a user's scheduling weight
+     * */
+    pub fn weight_of(&self, user_id: &str) -> f64 {
+        self.user_weights
+            .get(user_id)
+            .copied()
+            .unwrap_or(1.0)
+            .max(self.min_weight())
+    }
+
+    /**
+     * Lowest scheduling weight a user can have, clamped to MIN_WEIGHT
+     * */
+    fn min_weight(&self) -> f64 {
+        self.votes.min_weight.max(MIN_WEIGHT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{HistoryEntry, SongOrigin};
+    use std::time::SystemTime;
+
+    /**
+     * A queue playing a song submitted by "dj", with {listeners} listening
+     * */
+    fn playing(listeners: &[&str]) -> GlobalQueue {
+        let mut queue = GlobalQueue::new();
+        let origin = SongOrigin::FileUpload("/music/song.flac".to_string());
+        queue.history.push_back(HistoryEntry {
+            song: Song::new(origin, "dj".to_string()),
+            played_at: SystemTime::now(),
+            finished: false,
+            skipped: false,
+        });
+        listeners.iter().for_each(|user| queue.join_listener(user));
+        queue
+    }
+
+    #[test]
+    fn listeners_vote_to_skip() {
+        let mut queue = playing(&["a", "b", "c", "d"]);
+        assert_eq!(queue.skip_votes_needed(), 2);
+
+        assert_eq!(queue.vote_skip("a"), SkipVote::Counted { votes: 1, needed: 2 });
+        assert_eq!(queue.vote_skip("a"), SkipVote::AlreadyVoted { votes: 1, needed: 2 });
+        assert_eq!(queue.vote_skip("someone"), SkipVote::NotListening);
+        assert_eq!(queue.vote_skip("b"), SkipVote::Skipped);
+
+        let entry = queue.history.back().unwrap();
+        assert!(entry.finished && entry.skipped);
+        assert!(queue.now_playing().is_none());
+        assert_eq!(queue.vote_skip("c"), SkipVote::NothingPlaying);
+        // Skipping by vote counts against the submitter
+        assert_eq!(queue.weight_of("dj"), queue.votes.skip_penalty);
+    }
+
+    #[test]
+    fn votes_from_listeners_who_left_dont_count() {
+        let mut queue = playing(&["a", "b", "c", "d"]);
+        assert_eq!(queue.vote_skip("a"), SkipVote::Counted { votes: 1, needed: 2 });
+        queue.leave_listener("a");
+        assert_eq!(queue.vote_skip("b"), SkipVote::Counted { votes: 1, needed: 2 });
+        assert_eq!(queue.vote_skip("c"), SkipVote::Skipped);
+    }
+
+    #[test]
+    fn submitters_skip_their_own_songs() {
+        // Even without listening
+        let mut queue = playing(&["a", "b", "c"]);
+        assert_eq!(queue.vote_skip("dj"), SkipVote::Skipped);
+        assert!(queue.history.back().unwrap().skipped);
+        assert_eq!(queue.weight_of("dj"), 1.0);
+    }
+
+    #[test]
+    fn skip_votes_needed_has_a_minimum() {
+        let mut queue = playing(&[]);
+        queue.votes.min_skip_votes = 2;
+        assert_eq!(queue.skip_votes_needed(), 2);
+        queue.join_listener("a");
+        assert_eq!(queue.vote_skip("a"), SkipVote::Counted { votes: 1, needed: 2 });
+    }
+
+    #[test]
+    fn skipped_submitters_recover() {
+        let mut queue = playing(&["a"]);
+        queue.vote_skip("a");
+        assert_eq!(queue.weight_of("dj"), 0.75);
+
+        // Weights only fall as far as the minimum
+        for _ in 0..10 {
+            queue.history.back_mut().unwrap().finished = false;
+            queue.skip_current(true);
+        }
+        assert_eq!(queue.weight_of("dj"), queue.votes.min_weight);
+
+        // ... and come back as their songs play through
+        for _ in 0..8 {
+            queue.history.back_mut().unwrap().finished = false;
+            queue.finish_current();
+        }
+        assert!(!queue.user_weights.contains_key("dj"), "weight didn't recover");
+        assert_eq!(queue.weight_of("dj"), 1.0);
+    }
+
+    #[test]
+    fn reactions_replace_each_other() {
+        let mut queue = playing(&[]);
+        assert!(queue.react_current("a", Reaction::Upvote));
+        assert!(queue.react_current("b", Reaction::Upvote));
+        assert!(queue.react_current("a", Reaction::Downvote));
+        assert_eq!(queue.now_playing().unwrap().score(), 0);
+        queue.now_playing_mut().unwrap().clear_reaction("a");
+        assert_eq!(queue.now_playing().unwrap().score(), 1);
+    }
+}
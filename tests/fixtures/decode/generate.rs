@@ -0,0 +1,290 @@
+//! Writes the decode test fixtures: one second of stereo audio, silent for its first quarter
+//! second and then a 440 Hz tone on the left and a 660 Hz tone on the right, encoded as Opus in
+//! WebM and as AAC-LC in MP4.
+//!
+//! Built as a scratch binary depending on `opus = "0.3"` and `fdk-aac = "0.8"`:
+//!
+//!     cargo run --release -- <output directory>
+
+use fdk_aac::enc::{AudioObjectType, BitRate, ChannelMode, Encoder, EncoderParams, Transport};
+use opus::{Application, Channels};
+use std::f32::consts::PI;
+use std::fs;
+use std::path::Path;
+
+const ONSET: f32 = 0.25;
+
+fn sample(rate: u32, i: usize, channel: usize) -> f32 {
+    let t = i as f32 / rate as f32;
+    if t < ONSET {
+        return 0.0;
+    }
+    let freq = [440.0, 660.0][channel];
+    0.5 * (2.0 * PI * freq * t).sin()
+}
+
+fn signal(rate: u32, frames: usize) -> Vec<f32> {
+    (0..frames)
+        .flat_map(|i| [sample(rate, i, 0), sample(rate, i, 1)])
+        .collect()
+}
+
+fn main() {
+    let out = std::env::args().nth(1).expect("usage: generate <output directory>");
+    let out = Path::new(&out);
+    fs::write(out.join("tone.webm"), webm()).unwrap();
+    fs::write(out.join("tone.m4a"), m4a()).unwrap();
+}
+
+/* ---------------------------------- WebM / Opus ---------------------------------- */
+
+const OPUS_RATE: u32 = 48000;
+const OPUS_FRAME: usize = 960;
+const OPUS_PACKETS: usize = 50;
+
+fn webm() -> Vec<u8> {
+    let mut encoder = opus::Encoder::new(OPUS_RATE, Channels::Stereo, Application::Audio).unwrap();
+    let pre_skip = encoder.get_lookahead().unwrap() as u16;
+    eprintln!("opus pre-skip {}", pre_skip);
+
+    let pcm = signal(OPUS_RATE, OPUS_FRAME * OPUS_PACKETS);
+    let packets = pcm
+        .chunks(OPUS_FRAME * 2)
+        .map(|frame| encoder.encode_vec_float(frame, 4000).unwrap())
+        .collect::<Vec<_>>();
+
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(2);
+    head.extend(pre_skip.to_le_bytes());
+    head.extend(OPUS_RATE.to_le_bytes());
+    head.extend(0i16.to_le_bytes());
+    head.push(0);
+
+    let duration_ms = (OPUS_FRAME * OPUS_PACKETS) as f64 * 1000.0 / OPUS_RATE as f64;
+    let header = ebml(
+        0x1A45DFA3,
+        &[
+            ebml_uint(0x4286, 1),
+            ebml_uint(0x42F7, 1),
+            ebml_uint(0x42F2, 4),
+            ebml_uint(0x42F3, 8),
+            ebml(0x4282, b"webm"),
+            ebml_uint(0x4287, 4),
+            ebml_uint(0x4285, 2),
+        ]
+        .concat(),
+    );
+    let info = ebml(
+        0x1549A966,
+        &[
+            ebml_uint(0x2AD7B1, 1_000_000),
+            ebml(0x4489, &duration_ms.to_be_bytes()),
+            ebml(0x4D80, b"generate.rs"),
+            ebml(0x5741, b"generate.rs"),
+        ]
+        .concat(),
+    );
+    let audio = ebml(
+        0xE1,
+        &[ebml(0xB5, &(OPUS_RATE as f64).to_be_bytes()), ebml_uint(0x9F, 2)].concat(),
+    );
+    let tracks = ebml(
+        0x1654AE6B,
+        &ebml(
+            0xAE,
+            &[
+                ebml_uint(0xD7, 1),
+                ebml_uint(0x73C5, 1),
+                ebml_uint(0x83, 2),
+                ebml(0x86, b"A_OPUS"),
+                ebml(0x63A2, &head),
+                ebml_uint(0x56AA, pre_skip as u64 * 1_000_000_000 / OPUS_RATE as u64),
+                ebml_uint(0x56BB, 80_000_000),
+                audio,
+            ]
+            .concat(),
+        ),
+    );
+    let mut cluster = ebml_uint(0xE7, 0);
+    for (i, packet) in packets.iter().enumerate() {
+        let timestamp = (i * OPUS_FRAME * 1000 / OPUS_RATE as usize) as i16;
+        let mut block = vec![0x81];
+        block.extend(timestamp.to_be_bytes());
+        block.push(0x80);
+        block.extend(packet);
+        cluster.extend(ebml(0xA3, &block));
+    }
+    let cluster = ebml(0x1F43B675, &cluster);
+
+    [header, ebml(0x18538067, &[info, tracks, cluster].concat())].concat()
+}
+
+fn ebml(id: u32, data: &[u8]) -> Vec<u8> {
+    let id_bytes = id.to_be_bytes();
+    let skip = id_bytes.iter().position(|b| *b != 0).unwrap();
+    let mut out = id_bytes[skip..].to_vec();
+    // Sizes are always written as 8 byte vints
+    out.push(0x01);
+    out.extend(&(data.len() as u64).to_be_bytes()[1..]);
+    out.extend(data);
+    out
+}
+
+fn ebml_uint(id: u32, value: u64) -> Vec<u8> {
+    ebml(id, &value.to_be_bytes())
+}
+
+/* ---------------------------------- MP4 / AAC ---------------------------------- */
+
+const AAC_RATE: u32 = 44100;
+const AAC_FRAME: usize = 1024;
+
+fn m4a() -> Vec<u8> {
+    let encoder = Encoder::new(EncoderParams {
+        bit_rate: BitRate::Cbr(128_000),
+        sample_rate: AAC_RATE,
+        transport: Transport::Raw,
+        channels: ChannelMode::Stereo,
+        audio_object_type: AudioObjectType::Mpeg4LowComplexity,
+    })
+    .unwrap();
+    let info = encoder.info().unwrap();
+    let config = info.confBuf[..info.confSize as usize].to_vec();
+    eprintln!("aac delay {}, config {:02x?}", info.nDelay, config);
+
+    // One second of audio, padded with silence until the encoder has flushed all of it
+    let frames = (AAC_RATE as usize).div_ceil(AAC_FRAME);
+    let mut pcm = signal(AAC_RATE, AAC_RATE as usize)
+        .iter()
+        .map(|s| (s * 32767.0) as i16)
+        .collect::<Vec<_>>();
+    pcm.resize(pcm.len() + 8 * AAC_FRAME * 2, 0);
+
+    let mut packets = vec![];
+    let mut buf = [0u8; 4096];
+    for frame in pcm.chunks(AAC_FRAME * 2) {
+        let res = encoder.encode(frame, &mut buf).unwrap();
+        assert_eq!(res.input_consumed, frame.len());
+        if res.output_size > 0 {
+            packets.push(buf[..res.output_size].to_vec());
+        }
+        if packets.len() == frames {
+            break;
+        }
+    }
+    assert_eq!(packets.len(), frames);
+
+    let ftyp = mp4(b"ftyp", &[b"M4A ".as_slice(), &0u32.to_be_bytes(), b"M4A mp42isom"].concat());
+    // moov is written twice, the first time only to learn its size for the chunk offset
+    let moov_len = moov(&config, &packets, 0).len();
+    let data_offset = ftyp.len() + moov_len + 8;
+    let moov = moov(&config, &packets, data_offset as u32);
+    let mdat = mp4(b"mdat", &packets.concat());
+    [ftyp, moov, mdat].concat()
+}
+
+fn moov(config: &[u8], packets: &[Vec<u8>], data_offset: u32) -> Vec<u8> {
+    let duration = (packets.len() * AAC_FRAME) as u32;
+    let matrix = [0x10000u32, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000]
+        .iter()
+        .flat_map(|v| v.to_be_bytes())
+        .collect::<Vec<_>>();
+
+    let mut mvhd = vec![0; 4 + 8];
+    mvhd.extend(AAC_RATE.to_be_bytes());
+    mvhd.extend(duration.to_be_bytes());
+    mvhd.extend(0x10000u32.to_be_bytes());
+    mvhd.extend(0x100u16.to_be_bytes());
+    mvhd.extend([0; 10]);
+    mvhd.extend(&matrix);
+    mvhd.extend([0; 24]);
+    mvhd.extend(2u32.to_be_bytes());
+
+    let mut tkhd = vec![0, 0, 0, 3];
+    tkhd.extend([0; 8]);
+    tkhd.extend(1u32.to_be_bytes());
+    tkhd.extend([0; 4]);
+    tkhd.extend(duration.to_be_bytes());
+    tkhd.extend([0; 8]);
+    tkhd.extend([0; 4]);
+    tkhd.extend(0x100u16.to_be_bytes());
+    tkhd.extend([0; 2]);
+    tkhd.extend(&matrix);
+    tkhd.extend([0; 8]);
+
+    let mut mdhd = vec![0; 4 + 8];
+    mdhd.extend(AAC_RATE.to_be_bytes());
+    mdhd.extend(duration.to_be_bytes());
+    mdhd.extend(0x55C4u16.to_be_bytes());
+    mdhd.extend([0; 2]);
+
+    let mut hdlr = vec![0; 8];
+    hdlr.extend(b"soun");
+    hdlr.extend([0; 12]);
+    hdlr.extend(b"SoundHandler\0");
+
+    let dinf = mp4(
+        b"dinf",
+        &mp4(
+            b"dref",
+            &[&[0, 0, 0, 0, 0, 0, 0, 1][..], &mp4(b"url ", &[0, 0, 0, 1])].concat(),
+        ),
+    );
+
+    let max_size = packets.iter().map(Vec::len).max().unwrap() as u32;
+    let total = packets.iter().map(Vec::len).sum::<usize>() as u32;
+    let bitrate = (total as u64 * 8 * AAC_RATE as u64 / duration as u64) as u32;
+    let mut decoder_config = vec![0x40, 0x15];
+    decoder_config.extend(&max_size.to_be_bytes()[1..]);
+    decoder_config.extend(bitrate.to_be_bytes());
+    decoder_config.extend(bitrate.to_be_bytes());
+    decoder_config.extend(descriptor(0x05, config));
+    let mut es = vec![0, 1, 0];
+    es.extend(descriptor(0x04, &decoder_config));
+    es.extend(descriptor(0x06, &[0x02]));
+    let esds = mp4(b"esds", &[&[0, 0, 0, 0][..], &descriptor(0x03, &es)].concat());
+
+    let mut mp4a = vec![0; 6];
+    mp4a.extend(1u16.to_be_bytes());
+    mp4a.extend([0; 8]);
+    mp4a.extend(2u16.to_be_bytes());
+    mp4a.extend(16u16.to_be_bytes());
+    mp4a.extend([0; 4]);
+    mp4a.extend((AAC_RATE << 16).to_be_bytes());
+    mp4a.extend(esds);
+    let stsd = mp4(b"stsd", &[&[0, 0, 0, 0, 0, 0, 0, 1][..], &mp4(b"mp4a", &mp4a)].concat());
+
+    let table = |entries: &[u32]| {
+        let mut out = vec![0; 4];
+        out.extend(entries.iter().flat_map(|v| v.to_be_bytes()));
+        out
+    };
+    let stts = mp4(b"stts", &table(&[1, packets.len() as u32, AAC_FRAME as u32]));
+    let stsc = mp4(b"stsc", &table(&[1, 1, packets.len() as u32, 1]));
+    let mut sizes = vec![0, packets.len() as u32];
+    sizes.extend(packets.iter().map(|p| p.len() as u32));
+    let stsz = mp4(b"stsz", &table(&sizes));
+    let stco = mp4(b"stco", &table(&[1, data_offset]));
+    let stbl = mp4(b"stbl", &[stsd, stts, stsc, stsz, stco].concat());
+
+    let minf = mp4(b"minf", &[mp4(b"smhd", &[0; 8]), dinf, stbl].concat());
+    let mdia = mp4(
+        b"mdia",
+        &[mp4(b"mdhd", &mdhd), mp4(b"hdlr", &hdlr), minf].concat(),
+    );
+    let trak = mp4(b"trak", &[mp4(b"tkhd", &tkhd), mdia].concat());
+    mp4(b"moov", &[mp4(b"mvhd", &mvhd), trak].concat())
+}
+
+fn mp4(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut out = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+    out.extend(kind);
+    out.extend(data);
+    out
+}
+
+fn descriptor(tag: u8, data: &[u8]) -> Vec<u8> {
+    assert!(data.len() < 128);
+    [&[tag, data.len() as u8][..], data].concat()
+}
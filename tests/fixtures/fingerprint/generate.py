@@ -0,0 +1,34 @@
+# Synthesizes the fingerprint test clip: 15 seconds of mono 11025 Hz 16 bit audio playing a
+# chord progression with a little noise, so its chroma features change over time
+import math, struct, wave
+
+RATE = 11025
+CHORDS = [(261.63, 329.63, 392.00), (220.00, 261.63, 329.63), (174.61, 220.00, 261.63),
+          (196.00, 246.94, 293.66), (293.66, 369.99, 440.00), (246.94, 311.13, 369.99)]
+BEAT = 1.25
+seed = 12345
+def noise():
+    global seed
+    seed = (seed * 1103515245 + 12345) % (1 << 31)
+    return seed / (1 << 31) * 2 - 1
+
+samples = []
+for i in range(RATE * 15):
+    t = i / RATE
+    chord = CHORDS[int(t / BEAT) % len(CHORDS)]
+    pos = (t % BEAT) / BEAT
+    env = min(1.0, pos * 20) * (1 - 0.6 * pos)
+    v = 0.0
+    for j, f in enumerate(chord):
+        for h, a in ((1, 1.0), (2, 0.5), (3, 0.25)):
+            v += a * math.sin(2 * math.pi * f * h * t + j)
+    v = 0.18 * env * v + 0.02 * noise()
+    samples.append(max(-32768, min(32767, int(round(v * 32767)))))
+
+with wave.open("clip.wav", "wb") as w:
+    w.setnchannels(1)
+    w.setsampwidth(2)
+    w.setframerate(RATE)
+    w.writeframes(struct.pack("<%dh" % len(samples), *samples))
+with open("clip.raw", "wb") as f:
+    f.write(struct.pack("<%dh" % len(samples), *samples))
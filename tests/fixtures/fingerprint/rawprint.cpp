@@ -0,0 +1,32 @@
+// Prints the raw and compressed fingerprints of s16le audio, like "fpcalc -raw" and plain
+// "fpcalc" do for decoded audio
+#include <chromaprint.h>
+#include <cstdio>
+#include <cstdlib>
+#include <vector>
+
+int main(int argc, char **argv) {
+    if (argc != 4) { fprintf(stderr, "usage: rawprint FILE RATE CHANNELS\n"); return 1; }
+    FILE *f = fopen(argv[1], "rb");
+    std::vector<int16_t> samples;
+    int16_t buf[4096];
+    size_t n;
+    while ((n = fread(buf, 2, 4096, f)) > 0) samples.insert(samples.end(), buf, buf + n);
+    fclose(f);
+    int rate = atoi(argv[2]), channels = atoi(argv[3]);
+    ChromaprintContext *ctx = chromaprint_new(CHROMAPRINT_ALGORITHM_TEST2);
+    chromaprint_start(ctx, rate, channels);
+    chromaprint_feed(ctx, samples.data(), samples.size());
+    chromaprint_finish(ctx);
+    uint32_t *raw; int size;
+    chromaprint_get_raw_fingerprint(ctx, &raw, &size);
+    printf("DURATION=%.3f\nFINGERPRINT=", samples.size() / (double)(rate * channels));
+    for (int i = 0; i < size; i++) printf(i ? ",%u" : "%u", raw[i]);
+    printf("\n");
+    chromaprint_dealloc(raw);
+    char *fp;
+    chromaprint_get_fingerprint(ctx, &fp);
+    printf("COMPRESSED=%s\n", fp);
+    chromaprint_dealloc(fp);
+    chromaprint_free(ctx);
+}